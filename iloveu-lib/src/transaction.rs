@@ -0,0 +1,731 @@
+//! The binary format of the transactions log. Every integer is big endian, every string is a u64 byte length
+//! followed by that many bytes of UTF-8 and every optional value is a u64 of 0 if it's missing, or 1 followed
+//! by the value.
+
+use std::fmt;
+
+use serde::{Serialize, Deserialize};
+
+pub const RECORD_HEADER_LENGTH: u64 = 24;
+pub const RECORD_CHECKSUM_LENGTH: u64 = 4;
+/// Records only hold metadata, so anything longer than this is a corrupt length.
+pub const MAX_RECORD_LENGTH: u64 = 64*1024*1024;
+/// The payload is sealed with the store's key and has to be opened before it can be decoded.
+pub const RECORD_FLAG_ENCRYPTED: u64 = 1 << 0;
+/// The payload is zstd compressed. Compression happens before sealing, so it is undone after opening.
+pub const RECORD_FLAG_COMPRESSED: u64 = 1 << 1;
+
+pub type BlobHash = [u8; 32];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MediaType {
+    #[serde(alias = "picture")]
+    Picture,
+    #[serde(alias = "video")]
+    Video,
+}
+
+/// A smaller copy of a picture, generated from the original for showing it in less space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum VariantSize {
+    #[serde(alias = "thumb")]
+    Thumb,
+    #[serde(alias = "medium")]
+    Medium,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaMetadata {
+    pub title: String,
+    pub description: String,
+    pub tags_vec: Vec<u64>,
+    pub taken_datetime: f64,
+    pub media_type: MediaType,
+    pub filename: String,
+}
+
+/// What the EXIF metadata embedded in a photo says about it, leaving out whatever the photo doesn't say.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExifMetadata {
+    /// When the photo was taken in milliseconds since the unix epoch. Without `utc_offset_minutes` this is the
+    /// camera's clock read as if it were UTC.
+    pub taken_datetime: Option<f64>,
+    pub utc_offset_minutes: Option<i64>,
+    /// How the photo has to be turned to be upright, from 1 to 8 as EXIF numbers them.
+    pub orientation: Option<u64>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    /// In degrees, north and east are positive.
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// In meters above sea level.
+    pub altitude: Option<f64>,
+}
+
+/// What the container of a video says about it, leaving out whatever it doesn't say.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VideoInfo {
+    /// In seconds.
+    pub duration: Option<f64>,
+    /// In pixels, as the video is shown rather than as it is stored.
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    /// When the video was recorded in milliseconds since the unix epoch.
+    pub created_datetime: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transaction {
+    /// Type 0
+    AddTag {
+        name: String,
+    },
+    /// Type 1, only written by version 1 stores. The `file_length` bytes of the file follow the transaction directly.
+    AddInlineMedia {
+        metadata: MediaMetadata,
+        file_length: u64,
+    },
+    /// Type 2
+    UpdateMedia {
+        media_id: u64,
+        title: String,
+        description: String,
+        tags_vec: Vec<u64>,
+        taken_datetime: f64,
+    },
+    /// Type 3
+    DeleteMedia {
+        media_id: u64,
+        deleted_datetime: f64,
+    },
+    /// Type 4
+    RestoreMedia {
+        media_id: u64,
+    },
+    /// Type 5
+    RenameTag {
+        tag_id: u64,
+        name: String,
+    },
+    /// Type 6, replaces every reference to `tag_id` with `into_tag_id`
+    MergeTag {
+        tag_id: u64,
+        into_tag_id: u64,
+    },
+    /// Type 7
+    DeleteTag {
+        tag_id: u64,
+    },
+    /// Type 8, advances the next tag and media ids without adding anything, so compaction can keep ids stable
+    SkipIds {
+        tag_ids: u64,
+        media_ids: u64,
+    },
+    /// Type 9, media whose bytes are in the blob named by `hash`
+    AddBlobMedia {
+        metadata: MediaMetadata,
+        hash: BlobHash,
+        size: u64,
+    },
+    /// Type 10, a variant of media `media_id` in the blob named by `hash`, replacing any earlier one of that size
+    AddMediaVariant {
+        media_id: u64,
+        variant_size: VariantSize,
+        hash: BlobHash,
+        size: u64,
+    },
+    /// Type 11, replaces the EXIF metadata of media `media_id`
+    SetMediaExif {
+        media_id: u64,
+        exif: ExifMetadata,
+    },
+    /// Type 12, replaces the container metadata of video `media_id`
+    SetVideoInfo {
+        media_id: u64,
+        video_info: VideoInfo,
+    },
+}
+
+impl Transaction {
+    pub fn transaction_type(&self) -> u64 {
+        match self {
+            Transaction::AddTag { .. } => 0,
+            Transaction::AddInlineMedia { .. } => 1,
+            Transaction::UpdateMedia { .. } => 2,
+            Transaction::DeleteMedia { .. } => 3,
+            Transaction::RestoreMedia { .. } => 4,
+            Transaction::RenameTag { .. } => 5,
+            Transaction::MergeTag { .. } => 6,
+            Transaction::DeleteTag { .. } => 7,
+            Transaction::SkipIds { .. } => 8,
+            Transaction::AddBlobMedia { .. } => 9,
+            Transaction::AddMediaVariant { .. } => 10,
+            Transaction::SetMediaExif { .. } => 11,
+            Transaction::SetVideoInfo { .. } => 12,
+        }
+    }
+
+    /// The payload of the transaction, without its type.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            Transaction::AddTag { name } => {
+                write_string(&mut payload, name);
+            },
+            Transaction::AddInlineMedia { metadata, file_length } => {
+                write_media_metadata(&mut payload, metadata);
+                write_u64(&mut payload, *file_length);
+            },
+            Transaction::UpdateMedia { media_id, title, description, tags_vec, taken_datetime } => {
+                write_u64(&mut payload, *media_id);
+                write_string(&mut payload, title);
+                write_string(&mut payload, description);
+                write_ids(&mut payload, tags_vec);
+                write_f64(&mut payload, *taken_datetime);
+            },
+            Transaction::DeleteMedia { media_id, deleted_datetime } => {
+                write_u64(&mut payload, *media_id);
+                write_f64(&mut payload, *deleted_datetime);
+            },
+            Transaction::RestoreMedia { media_id } => {
+                write_u64(&mut payload, *media_id);
+            },
+            Transaction::RenameTag { tag_id, name } => {
+                write_u64(&mut payload, *tag_id);
+                write_string(&mut payload, name);
+            },
+            Transaction::MergeTag { tag_id, into_tag_id } => {
+                write_u64(&mut payload, *tag_id);
+                write_u64(&mut payload, *into_tag_id);
+            },
+            Transaction::DeleteTag { tag_id } => {
+                write_u64(&mut payload, *tag_id);
+            },
+            Transaction::SkipIds { tag_ids, media_ids } => {
+                write_u64(&mut payload, *tag_ids);
+                write_u64(&mut payload, *media_ids);
+            },
+            Transaction::AddBlobMedia { metadata, hash, size } => {
+                write_media_metadata(&mut payload, metadata);
+                payload.extend_from_slice(hash);
+                write_u64(&mut payload, *size);
+            },
+            Transaction::AddMediaVariant { media_id, variant_size, hash, size } => {
+                write_u64(&mut payload, *media_id);
+                write_u64(&mut payload, match variant_size {
+                    VariantSize::Thumb => 0,
+                    VariantSize::Medium => 1,
+                });
+                payload.extend_from_slice(hash);
+                write_u64(&mut payload, *size);
+            },
+            Transaction::SetMediaExif { media_id, exif } => {
+                write_u64(&mut payload, *media_id);
+                write_exif_metadata(&mut payload, exif);
+            },
+            Transaction::SetVideoInfo { media_id, video_info } => {
+                write_u64(&mut payload, *media_id);
+                write_video_info(&mut payload, video_info);
+            },
+        }
+        payload
+    }
+
+    /// Decodes a whole payload, returning `None` if the transaction type is unknown.
+    pub fn decode(transaction_type: u64, payload: &[u8]) -> Result<Option<Transaction>, DecodeError> {
+        let mut remaining = payload;
+        let transaction = Transaction::read(transaction_type, &mut remaining)?;
+        if transaction.is_some() && !remaining.is_empty() {
+            return Err(DecodeError {
+                field: "payload",
+                kind: DecodeErrorKind::TrailingBytes(remaining.len()),
+            });
+        }
+        Ok(transaction)
+    }
+
+    /// Decodes a transaction from the start of `bytes` and advances past it, for unframed logs where transactions
+    /// follow each other directly. Returns `None` without advancing if the transaction type is unknown.
+    pub fn read(transaction_type: u64, bytes: &mut &[u8]) -> Result<Option<Transaction>, DecodeError> {
+        let transaction = match transaction_type {
+            0 => Transaction::AddTag {
+                name: read_string(bytes, "name")?,
+            },
+            1 => Transaction::AddInlineMedia {
+                metadata: read_media_metadata(bytes)?,
+                file_length: read_u64(bytes, "file_length")?,
+            },
+            2 => Transaction::UpdateMedia {
+                media_id: read_u64(bytes, "media_id")?,
+                title: read_string(bytes, "title")?,
+                description: read_string(bytes, "description")?,
+                tags_vec: read_ids(bytes, "tags_vec")?,
+                taken_datetime: read_f64(bytes, "taken_datetime")?,
+            },
+            3 => Transaction::DeleteMedia {
+                media_id: read_u64(bytes, "media_id")?,
+                deleted_datetime: read_f64(bytes, "deleted_datetime")?,
+            },
+            4 => Transaction::RestoreMedia {
+                media_id: read_u64(bytes, "media_id")?,
+            },
+            5 => Transaction::RenameTag {
+                tag_id: read_u64(bytes, "tag_id")?,
+                name: read_string(bytes, "name")?,
+            },
+            6 => Transaction::MergeTag {
+                tag_id: read_u64(bytes, "tag_id")?,
+                into_tag_id: read_u64(bytes, "into_tag_id")?,
+            },
+            7 => Transaction::DeleteTag {
+                tag_id: read_u64(bytes, "tag_id")?,
+            },
+            8 => Transaction::SkipIds {
+                tag_ids: read_u64(bytes, "tag_ids")?,
+                media_ids: read_u64(bytes, "media_ids")?,
+            },
+            9 => Transaction::AddBlobMedia {
+                metadata: read_media_metadata(bytes)?,
+                hash: read_bytes(bytes, 32, "hash")?.try_into().unwrap(),
+                size: read_u64(bytes, "size")?,
+            },
+            10 => Transaction::AddMediaVariant {
+                media_id: read_u64(bytes, "media_id")?,
+                variant_size: match read_u64(bytes, "variant_size")? {
+                    0 => VariantSize::Thumb,
+                    1 => VariantSize::Medium,
+                    variant_size => return Err(DecodeError {
+                        field: "variant_size",
+                        kind: DecodeErrorKind::UnknownVariantSize(variant_size),
+                    }),
+                },
+                hash: read_bytes(bytes, 32, "hash")?.try_into().unwrap(),
+                size: read_u64(bytes, "size")?,
+            },
+            11 => Transaction::SetMediaExif {
+                media_id: read_u64(bytes, "media_id")?,
+                exif: read_exif_metadata(bytes)?,
+            },
+            12 => Transaction::SetVideoInfo {
+                media_id: read_u64(bytes, "media_id")?,
+                video_info: read_video_info(bytes)?,
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(transaction))
+    }
+
+    /// The transaction framed as its type, flags, payload length, the payload and finally a CRC-32 of everything
+    /// before it. The flags say how the payload was transformed, [`RECORD_FLAG_COMPRESSED`] and then
+    /// [`RECORD_FLAG_ENCRYPTED`]; the plain payload written here has none of them set.
+    pub fn encode_record(&self) -> Vec<u8> {
+        frame_record(self.transaction_type(), &self.encode())
+    }
+}
+
+/// Frames an already encoded payload, see [`Transaction::encode_record`].
+pub fn frame_record(transaction_type: u64, payload: &[u8]) -> Vec<u8> {
+    frame_record_with_flags(transaction_type, 0, payload)
+}
+
+/// Frames a payload that has been transformed as described by `flags`, such as [`RECORD_FLAG_ENCRYPTED`].
+pub fn frame_record_with_flags(transaction_type: u64, flags: u64, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LENGTH as usize+payload.len()+RECORD_CHECKSUM_LENGTH as usize);
+    RecordHeader {
+        transaction_type,
+        flags,
+        length: payload.len() as u64,
+    }.write(&mut record);
+    record.extend_from_slice(payload);
+    let checksum = crc32fast::hash(&record);
+    write_u32(&mut record, checksum);
+    record
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordHeader {
+    pub transaction_type: u64,
+    pub flags: u64,
+    pub length: u64,
+}
+
+impl RecordHeader {
+    pub fn parse(header_bytes: &[u8; RECORD_HEADER_LENGTH as usize]) -> RecordHeader {
+        RecordHeader {
+            transaction_type: u64::from_be_bytes(header_bytes[0..8].try_into().unwrap()),
+            flags: u64::from_be_bytes(header_bytes[8..16].try_into().unwrap()),
+            length: u64::from_be_bytes(header_bytes[16..24].try_into().unwrap()),
+        }
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        write_u64(bytes, self.transaction_type);
+        write_u64(bytes, self.flags);
+        write_u64(bytes, self.length);
+    }
+}
+
+/// The CRC-32 stored after a record's payload.
+pub fn record_checksum(header_bytes: &[u8; RECORD_HEADER_LENGTH as usize], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header_bytes);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Why a payload couldn't be decoded, and the field of the transaction that was being read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub field: &'static str,
+    pub kind: DecodeErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    /// The payload ended in the middle of the field.
+    UnexpectedEnd,
+    InvalidUtf8,
+    UnknownMediaType(u64),
+    UnknownVariantSize(u64),
+    /// An optional value was neither missing (0) nor present (1).
+    InvalidOption(u64),
+    /// The payload continued after the last field.
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "field `{}`: ", self.field)?;
+        match self.kind {
+            DecodeErrorKind::UnexpectedEnd => write!(f, "transaction ended unexpectedly"),
+            DecodeErrorKind::InvalidUtf8 => write!(f, "invalid utf8 string"),
+            DecodeErrorKind::UnknownMediaType(media_type) => write!(f, "unknown media type {}", media_type),
+            DecodeErrorKind::UnknownVariantSize(variant_size) => write!(f, "unknown variant size {}", variant_size),
+            DecodeErrorKind::InvalidOption(presence) => write!(f, "invalid option marker {}", presence),
+            DecodeErrorKind::TrailingBytes(length) => write!(f, "{} unexpected bytes after the transaction", length),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for std::io::Error {
+    fn from(err: DecodeError) -> Self {
+        let kind = match err.kind {
+            DecodeErrorKind::UnexpectedEnd => std::io::ErrorKind::UnexpectedEof,
+            _ => std::io::ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_u64(bytes: &mut Vec<u8>, value: u64) {
+    bytes.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_i64(bytes: &mut Vec<u8>, value: i64) {
+    bytes.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_f64(bytes: &mut Vec<u8>, value: f64) {
+    bytes.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_option<T>(bytes: &mut Vec<u8>, value: &Option<T>, write: impl FnOnce(&mut Vec<u8>, &T)) {
+    match value {
+        Some(value) => {
+            write_u64(bytes, 1);
+            write(bytes, value);
+        },
+        None => write_u64(bytes, 0),
+    }
+}
+
+fn write_string(bytes: &mut Vec<u8>, string: &str) {
+    write_u64(bytes, string.len() as u64);
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+fn write_ids(bytes: &mut Vec<u8>, ids: &[u64]) {
+    write_u64(bytes, ids.len() as u64);
+    for id in ids {
+        write_u64(bytes, *id);
+    }
+}
+
+fn write_media_metadata(bytes: &mut Vec<u8>, metadata: &MediaMetadata) {
+    write_string(bytes, &metadata.title);
+    write_string(bytes, &metadata.description);
+    write_ids(bytes, &metadata.tags_vec);
+    write_f64(bytes, metadata.taken_datetime);
+    write_u64(bytes, match metadata.media_type {
+        MediaType::Picture => 0,
+        MediaType::Video => 1,
+    });
+    write_string(bytes, &metadata.filename);
+}
+
+fn write_exif_metadata(bytes: &mut Vec<u8>, exif: &ExifMetadata) {
+    write_option(bytes, &exif.taken_datetime, |bytes, value| write_f64(bytes, *value));
+    write_option(bytes, &exif.utc_offset_minutes, |bytes, value| write_i64(bytes, *value));
+    write_option(bytes, &exif.orientation, |bytes, value| write_u64(bytes, *value));
+    write_option(bytes, &exif.camera_make, |bytes, value| write_string(bytes, value));
+    write_option(bytes, &exif.camera_model, |bytes, value| write_string(bytes, value));
+    write_option(bytes, &exif.latitude, |bytes, value| write_f64(bytes, *value));
+    write_option(bytes, &exif.longitude, |bytes, value| write_f64(bytes, *value));
+    write_option(bytes, &exif.altitude, |bytes, value| write_f64(bytes, *value));
+}
+
+fn write_video_info(bytes: &mut Vec<u8>, video_info: &VideoInfo) {
+    write_option(bytes, &video_info.duration, |bytes, value| write_f64(bytes, *value));
+    write_option(bytes, &video_info.width, |bytes, value| write_u64(bytes, *value));
+    write_option(bytes, &video_info.height, |bytes, value| write_u64(bytes, *value));
+    write_option(bytes, &video_info.created_datetime, |bytes, value| write_f64(bytes, *value));
+}
+
+fn read_bytes<'a>(bytes: &mut &'a [u8], length: u64, field: &'static str) -> Result<&'a [u8], DecodeError> {
+    if (bytes.len() as u64) < length {
+        return Err(DecodeError {
+            field,
+            kind: DecodeErrorKind::UnexpectedEnd,
+        });
+    }
+    let (read, remaining) = bytes.split_at(length as usize);
+    *bytes = remaining;
+    Ok(read)
+}
+
+fn read_u64(bytes: &mut &[u8], field: &'static str) -> Result<u64, DecodeError> {
+    Ok(u64::from_be_bytes(read_bytes(bytes, 8, field)?.try_into().unwrap()))
+}
+
+fn read_i64(bytes: &mut &[u8], field: &'static str) -> Result<i64, DecodeError> {
+    Ok(i64::from_be_bytes(read_bytes(bytes, 8, field)?.try_into().unwrap()))
+}
+
+fn read_f64(bytes: &mut &[u8], field: &'static str) -> Result<f64, DecodeError> {
+    Ok(f64::from_be_bytes(read_bytes(bytes, 8, field)?.try_into().unwrap()))
+}
+
+fn read_option<T>(bytes: &mut &[u8], field: &'static str, read: impl FnOnce(&mut &[u8], &'static str) -> Result<T, DecodeError>) -> Result<Option<T>, DecodeError> {
+    match read_u64(bytes, field)? {
+        0 => Ok(None),
+        1 => Ok(Some(read(bytes, field)?)),
+        presence => Err(DecodeError {
+            field,
+            kind: DecodeErrorKind::InvalidOption(presence),
+        }),
+    }
+}
+
+fn read_string(bytes: &mut &[u8], field: &'static str) -> Result<String, DecodeError> {
+    let length = read_u64(bytes, field)?;
+    String::from_utf8(read_bytes(bytes, length, field)?.to_vec()).map_err(|_| DecodeError {
+        field,
+        kind: DecodeErrorKind::InvalidUtf8,
+    })
+}
+
+fn read_ids(bytes: &mut &[u8], field: &'static str) -> Result<Vec<u64>, DecodeError> {
+    let count = read_u64(bytes, field)?;
+    // a corrupt count can't make us allocate more than the payload could hold
+    let mut ids = Vec::with_capacity(count.min(bytes.len() as u64/8) as usize);
+    for _ in 0..count {
+        ids.push(read_u64(bytes, field)?);
+    }
+    Ok(ids)
+}
+
+fn read_media_metadata(bytes: &mut &[u8]) -> Result<MediaMetadata, DecodeError> {
+    Ok(MediaMetadata {
+        title: read_string(bytes, "title")?,
+        description: read_string(bytes, "description")?,
+        tags_vec: read_ids(bytes, "tags_vec")?,
+        taken_datetime: read_f64(bytes, "taken_datetime")?,
+        media_type: match read_u64(bytes, "media_type")? {
+            0 => MediaType::Picture,
+            1 => MediaType::Video,
+            media_type => return Err(DecodeError {
+                field: "media_type",
+                kind: DecodeErrorKind::UnknownMediaType(media_type),
+            }),
+        },
+        filename: read_string(bytes, "filename")?,
+    })
+}
+
+fn read_exif_metadata(bytes: &mut &[u8]) -> Result<ExifMetadata, DecodeError> {
+    Ok(ExifMetadata {
+        taken_datetime: read_option(bytes, "taken_datetime", read_f64)?,
+        utc_offset_minutes: read_option(bytes, "utc_offset_minutes", read_i64)?,
+        orientation: read_option(bytes, "orientation", read_u64)?,
+        camera_make: read_option(bytes, "camera_make", read_string)?,
+        camera_model: read_option(bytes, "camera_model", read_string)?,
+        latitude: read_option(bytes, "latitude", read_f64)?,
+        longitude: read_option(bytes, "longitude", read_f64)?,
+        altitude: read_option(bytes, "altitude", read_f64)?,
+    })
+}
+
+fn read_video_info(bytes: &mut &[u8]) -> Result<VideoInfo, DecodeError> {
+    Ok(VideoInfo {
+        duration: read_option(bytes, "duration", read_f64)?,
+        width: read_option(bytes, "width", read_u64)?,
+        height: read_option(bytes, "height", read_u64)?,
+        created_datetime: read_option(bytes, "created_datetime", read_f64)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> MediaMetadata {
+        MediaMetadata {
+            title: "Lake day".to_string(),
+            description: "the one from the lake ❤".to_string(),
+            tags_vec: vec![0, 3, 7],
+            taken_datetime: 1681234567890.0,
+            media_type: MediaType::Video,
+            filename: "IMG_0001.MOV".to_string(),
+        }
+    }
+
+    fn every_transaction() -> Vec<Transaction> {
+        vec![
+            Transaction::AddTag { name: "beach".to_string() },
+            Transaction::AddInlineMedia { metadata: metadata(), file_length: 12345 },
+            Transaction::UpdateMedia { media_id: 4, title: "".to_string(), description: "new".to_string(), tags_vec: vec![], taken_datetime: -1.5 },
+            Transaction::DeleteMedia { media_id: 4, deleted_datetime: 1700000000000.0 },
+            Transaction::RestoreMedia { media_id: 4 },
+            Transaction::RenameTag { tag_id: 2, name: "Beach".to_string() },
+            Transaction::MergeTag { tag_id: 2, into_tag_id: 0 },
+            Transaction::DeleteTag { tag_id: 0 },
+            Transaction::SkipIds { tag_ids: 3, media_ids: u64::MAX },
+            Transaction::AddBlobMedia { metadata: metadata(), hash: [0xab; 32], size: 5000 },
+            Transaction::AddMediaVariant { media_id: 4, variant_size: VariantSize::Thumb, hash: [0xcd; 32], size: 1200 },
+            Transaction::AddMediaVariant { media_id: 4, variant_size: VariantSize::Medium, hash: [0xef; 32], size: 90000 },
+            Transaction::SetMediaExif { media_id: 4, exif: ExifMetadata::default() },
+            Transaction::SetMediaExif { media_id: 4, exif: ExifMetadata {
+                taken_datetime: Some(1681234567890.0),
+                utc_offset_minutes: Some(-420),
+                orientation: Some(6),
+                camera_make: Some("Apple".to_string()),
+                camera_model: Some("iPhone 12".to_string()),
+                latitude: Some(-33.8568),
+                longitude: Some(151.2153),
+                altitude: None,
+            } },
+            Transaction::SetVideoInfo { media_id: 5, video_info: VideoInfo::default() },
+            Transaction::SetVideoInfo { media_id: 5, video_info: VideoInfo {
+                duration: Some(12.345),
+                width: Some(1080),
+                height: Some(1920),
+                created_datetime: Some(1681234567000.0),
+            } },
+        ]
+    }
+
+    #[test]
+    fn every_transaction_round_trips() {
+        for transaction in every_transaction() {
+            let payload = transaction.encode();
+            assert_eq!(Transaction::decode(transaction.transaction_type(), &payload), Ok(Some(transaction)));
+        }
+    }
+
+    #[test]
+    fn unframed_transactions_read_back_to_back() {
+        let mut log = Vec::new();
+        for transaction in every_transaction() {
+            log.extend_from_slice(&transaction.encode());
+        }
+
+        let mut remaining = log.as_slice();
+        for transaction in every_transaction() {
+            assert_eq!(Transaction::read(transaction.transaction_type(), &mut remaining), Ok(Some(transaction)));
+        }
+        assert!(remaining.is_empty());
+    }
+
+    #[test]
+    fn records_round_trip() {
+        for transaction in every_transaction() {
+            let record = transaction.encode_record();
+            let header_bytes: &[u8; RECORD_HEADER_LENGTH as usize] = record[..RECORD_HEADER_LENGTH as usize].try_into().unwrap();
+            let header = RecordHeader::parse(header_bytes);
+            assert_eq!(header.transaction_type, transaction.transaction_type());
+            assert_eq!(header.flags, 0);
+            assert_eq!(record.len() as u64, RECORD_HEADER_LENGTH+header.length+RECORD_CHECKSUM_LENGTH);
+
+            let payload = &record[RECORD_HEADER_LENGTH as usize..record.len()-RECORD_CHECKSUM_LENGTH as usize];
+            let checksum = u32::from_be_bytes(record[record.len()-RECORD_CHECKSUM_LENGTH as usize..].try_into().unwrap());
+            assert_eq!(record_checksum(header_bytes, payload), checksum);
+            assert_eq!(Transaction::decode(header.transaction_type, payload), Ok(Some(transaction)));
+        }
+    }
+
+    #[test]
+    fn flags_are_framed_and_checksummed() {
+        let record = frame_record_with_flags(9, RECORD_FLAG_ENCRYPTED, &[1, 2, 3]);
+        let header_bytes: &[u8; RECORD_HEADER_LENGTH as usize] = record[..RECORD_HEADER_LENGTH as usize].try_into().unwrap();
+        assert_eq!(RecordHeader::parse(header_bytes), RecordHeader { transaction_type: 9, flags: RECORD_FLAG_ENCRYPTED, length: 3 });
+
+        let checksum = u32::from_be_bytes(record[record.len()-RECORD_CHECKSUM_LENGTH as usize..].try_into().unwrap());
+        assert_eq!(record_checksum(header_bytes, &[1, 2, 3]), checksum);
+        assert_ne!(checksum, u32::from_be_bytes(frame_record(9, &[1, 2, 3])[RECORD_HEADER_LENGTH as usize+3..].try_into().unwrap()));
+    }
+
+    #[test]
+    fn unknown_types_are_not_errors() {
+        assert_eq!(Transaction::decode(99, &[1, 2, 3]), Ok(None));
+    }
+
+    #[test]
+    fn damaged_payloads_are_rejected() {
+        let transaction = Transaction::AddBlobMedia { metadata: metadata(), hash: [1; 32], size: 5 };
+        let payload = transaction.encode();
+        for length in 0..payload.len() {
+            assert_eq!(Transaction::decode(9, &payload[..length]).unwrap_err().kind, DecodeErrorKind::UnexpectedEnd);
+        }
+        assert_eq!(Transaction::decode(9, &payload[..payload.len()-1]).unwrap_err().field, "size");
+
+        let mut long_payload = payload.clone();
+        long_payload.push(0);
+        assert_eq!(Transaction::decode(9, &long_payload), Err(DecodeError { field: "payload", kind: DecodeErrorKind::TrailingBytes(1) }));
+
+        let mut bad_utf8 = Transaction::AddTag { name: "ab".to_string() }.encode();
+        bad_utf8[9] = 0xff;
+        assert_eq!(Transaction::decode(0, &bad_utf8), Err(DecodeError { field: "name", kind: DecodeErrorKind::InvalidUtf8 }));
+    }
+
+    #[test]
+    fn errors_name_the_field() {
+        let mut payload = Transaction::AddBlobMedia { metadata: metadata(), hash: [1; 32], size: 5 }.encode();
+        // the media type comes right before the filename at the end, then the hash and size
+        let media_type_offset = payload.len()-8-32-(8+metadata().filename.len())-8;
+        payload[media_type_offset+7] = 7;
+        let err = Transaction::decode(9, &payload).unwrap_err();
+        assert_eq!(err, DecodeError { field: "media_type", kind: DecodeErrorKind::UnknownMediaType(7) });
+        assert_eq!(err.to_string(), "field `media_type`: unknown media type 7");
+    }
+
+    #[test]
+    fn unknown_variant_sizes_are_rejected() {
+        let mut payload = Transaction::AddMediaVariant { media_id: 1, variant_size: VariantSize::Medium, hash: [1; 32], size: 5 }.encode();
+        payload[15] = 9;
+        assert_eq!(Transaction::decode(10, &payload), Err(DecodeError { field: "variant_size", kind: DecodeErrorKind::UnknownVariantSize(9) }));
+    }
+
+    #[test]
+    fn invalid_option_markers_are_rejected() {
+        let mut payload = Transaction::SetMediaExif { media_id: 1, exif: ExifMetadata::default() }.encode();
+        // the orientation marker comes after the media id and two missing fields
+        payload[8+8+8+7] = 2;
+        assert_eq!(Transaction::decode(11, &payload), Err(DecodeError { field: "orientation", kind: DecodeErrorKind::InvalidOption(2) }));
+    }
+}
@@ -0,0 +1,377 @@
+use std::{fmt, io::SeekFrom, path::Path, pin::Pin, sync::Arc};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{aead::{Aead, Payload}, KeyInit, XChaCha20Poly1305, XNonce};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::{bytes::Bytes, io::StreamReader};
+
+use crate::types::{BlobHash, hash_to_hex};
+
+const ENCRYPTION_FILE: &str = "encryption";
+/// Version 2 binds each sealed record to where it starts in the log, version 3 each chunk of a blob to the file it holds.
+const ENCRYPTION_VERSION: u64 = 3;
+const NONCE_LENGTH: usize = 24;
+const TAG_LENGTH: u64 = 16;
+/// Blobs are sealed in chunks of this many bytes, so they can be streamed without holding a whole file in memory.
+pub const BLOB_CHUNK_LENGTH: u64 = 64*1024;
+/// Each chunk's nonce is this random prefix followed by the chunk index.
+const BLOB_NONCE_PREFIX_LENGTH: usize = 16;
+const KEY_CHECK_PLAINTEXT: &[u8] = b"iloveu";
+
+/// Everything needed to derive the key of an encrypted store again, kept in its `encryption` file.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct EncryptionConfig {
+    version: u64,
+    salt: String,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+    /// A known plaintext sealed with the key, so a wrong passphrase is refused before anything is read.
+    key_check: String,
+}
+
+/// The key record payloads, blobs and the snapshot of an encrypted store are sealed with.
+pub struct StoreKey {
+    cipher: XChaCha20Poly1305,
+    /// Blobs are named by a keyed hash of their contents, so the names don't reveal which files are stored.
+    blob_name_key: [u8; 32],
+}
+
+impl fmt::Debug for StoreKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StoreKey(..)")
+    }
+}
+
+impl StoreKey {
+    fn derive(passphrase: &str, config: &EncryptionConfig) -> Result<StoreKey, tokio::io::Error> {
+        let params = Params::new(config.memory_kib, config.iterations, config.parallelism, Some(64)).map_err(invalid_config)?;
+        let mut key_material = [0u8; 64];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &hex_to_bytes(&config.salt)?, &mut key_material)
+            .map_err(invalid_config)?;
+
+        Ok(StoreKey {
+            cipher: XChaCha20Poly1305::new_from_slice(&key_material[..32]).map_err(invalid_config)?,
+            blob_name_key: key_material[32..].try_into().unwrap(),
+        })
+    }
+
+    /// Encrypts and authenticates `plaintext`, binding it to `associated_data` so it can't be moved elsewhere.
+    fn seal(&self, associated_data: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut sealed = vec![0u8; NONCE_LENGTH];
+        rand::thread_rng().fill(&mut sealed[..]);
+        let ciphertext = self.cipher.encrypt(XNonce::from_slice(&sealed), Payload {
+            msg: plaintext,
+            aad: associated_data,
+        }).expect("sealing never fails for payloads this short");
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    fn open(&self, associated_data: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        self.cipher.decrypt(XNonce::from_slice(nonce), Payload {
+            msg: ciphertext,
+            aad: associated_data,
+        }).ok()
+    }
+
+    /// Seals the payload of a record starting `offset` bytes into the log, so it can't be moved or swapped with another.
+    pub(crate) fn seal_record(&self, transaction_type: u64, offset: u64, payload: &[u8]) -> Vec<u8> {
+        self.seal(&record_associated_data(transaction_type, offset), payload)
+    }
+
+    pub(crate) fn open_record(&self, transaction_type: u64, offset: u64, sealed: &[u8]) -> Option<Vec<u8>> {
+        self.open(&record_associated_data(transaction_type, offset), sealed)
+    }
+
+    pub(crate) fn seal_snapshot(&self, snapshot: &[u8]) -> Vec<u8> {
+        self.seal(b"snapshot", snapshot)
+    }
+
+    pub(crate) fn open_snapshot(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        self.open(b"snapshot", sealed)
+    }
+
+    pub fn blob_name(&self, hash: &BlobHash) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.blob_name_key);
+        hasher.update(hash);
+        let keyed_hash: BlobHash = hasher.finalize().into();
+        hash_to_hex(&keyed_hash)
+    }
+
+    /// Starts sealing the blob of the file hashing to `blob_hash`, or a staged one if its hash isn't known yet.
+    pub(crate) fn blob_sealer(&self, blob_hash: Option<BlobHash>) -> BlobSealer<'_> {
+        let mut nonce_prefix = [0u8; BLOB_NONCE_PREFIX_LENGTH];
+        rand::thread_rng().fill(&mut nonce_prefix);
+        BlobSealer {
+            key: self,
+            nonce_prefix,
+            blob_hash,
+            chunk_index: 0,
+            pending_chunk: None,
+        }
+    }
+
+    fn seal_chunk(&self, nonce_prefix: &[u8; BLOB_NONCE_PREFIX_LENGTH], blob_hash: Option<&BlobHash>, chunk_index: u64, last: bool, chunk: &[u8]) -> Vec<u8> {
+        self.cipher.encrypt(&chunk_nonce(nonce_prefix, chunk_index), Payload {
+            msg: chunk,
+            aad: &chunk_associated_data(blob_hash, chunk_index, last),
+        }).expect("sealing never fails for chunks this short")
+    }
+
+    fn open_chunk(&self, nonce_prefix: &[u8; BLOB_NONCE_PREFIX_LENGTH], blob_hash: Option<&BlobHash>, chunk_index: u64, last: bool, sealed_chunk: &[u8]) -> Option<Vec<u8>> {
+        self.cipher.decrypt(&chunk_nonce(nonce_prefix, chunk_index), Payload {
+            msg: sealed_chunk,
+            aad: &chunk_associated_data(blob_hash, chunk_index, last),
+        }).ok()
+    }
+}
+
+fn record_associated_data(transaction_type: u64, offset: u64) -> [u8; 22] {
+    let mut associated_data = [0u8; 22];
+    associated_data[..6].copy_from_slice(b"record");
+    associated_data[6..14].copy_from_slice(&transaction_type.to_be_bytes());
+    associated_data[14..].copy_from_slice(&offset.to_be_bytes());
+    associated_data
+}
+
+/// Binds a chunk to the file its blob holds and to its place in it, so no chunk can pass for one of another blob or be
+/// moved within its own. Chunks of a staged blob are bound to no file and can never pass for those of a stored one.
+fn chunk_associated_data(blob_hash: Option<&BlobHash>, chunk_index: u64, last: bool) -> [u8; 47] {
+    let mut associated_data = [0u8; 47];
+    match blob_hash {
+        Some(blob_hash) => {
+            associated_data[..6].copy_from_slice(b"stored");
+            associated_data[6..38].copy_from_slice(blob_hash);
+        },
+        None => associated_data[..6].copy_from_slice(b"staged"),
+    }
+    associated_data[38..46].copy_from_slice(&chunk_index.to_be_bytes());
+    associated_data[46] = last as u8;
+    associated_data
+}
+
+fn chunk_nonce(nonce_prefix: &[u8; BLOB_NONCE_PREFIX_LENGTH], chunk_index: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..BLOB_NONCE_PREFIX_LENGTH].copy_from_slice(nonce_prefix);
+    nonce[BLOB_NONCE_PREFIX_LENGTH..].copy_from_slice(&chunk_index.to_be_bytes());
+    nonce
+}
+
+/// Seals a blob one chunk at a time. The last chunk is sealed differently so a truncated blob can't pass as whole,
+/// which means each full chunk is held back until it's known whether another one follows.
+pub(crate) struct BlobSealer<'a> {
+    key: &'a StoreKey,
+    nonce_prefix: [u8; BLOB_NONCE_PREFIX_LENGTH],
+    blob_hash: Option<BlobHash>,
+    chunk_index: u64,
+    pending_chunk: Option<Vec<u8>>,
+}
+
+impl BlobSealer<'_> {
+    /// What goes right after the blob header.
+    pub(crate) fn nonce_prefix(&self) -> &[u8] {
+        &self.nonce_prefix
+    }
+
+    /// Takes the next full chunk, returning the sealed bytes of the one before it if there was one.
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Option<Vec<u8>> {
+        let previous_chunk = self.pending_chunk.replace(chunk.to_vec())?;
+        Some(self.seal_next(false, &previous_chunk))
+    }
+
+    /// Takes the final, possibly empty, partial chunk and returns everything left to write.
+    pub(crate) fn finish(mut self, chunk: &[u8]) -> Vec<u8> {
+        match self.pending_chunk.take() {
+            Some(previous_chunk) if chunk.is_empty() => self.seal_next(true, &previous_chunk),
+            Some(previous_chunk) => {
+                let mut sealed = self.seal_next(false, &previous_chunk);
+                sealed.extend(self.seal_next(true, chunk));
+                sealed
+            },
+            None => self.seal_next(true, chunk),
+        }
+    }
+
+    fn seal_next(&mut self, last: bool, chunk: &[u8]) -> Vec<u8> {
+        let sealed = self.key.seal_chunk(&self.nonce_prefix, self.blob_hash.as_ref(), self.chunk_index, last, chunk);
+        self.chunk_index += 1;
+        sealed
+    }
+}
+
+/// How many chunks a sealed blob of `size` plaintext bytes has. Even an empty blob has one.
+fn blob_chunks(size: u64) -> u64 {
+    size.div_ceil(BLOB_CHUNK_LENGTH).max(1)
+}
+
+/// How long a sealed blob of `size` plaintext bytes is after its header.
+pub fn sealed_blob_length(size: u64) -> u64 {
+    BLOB_NONCE_PREFIX_LENGTH as u64+size+blob_chunks(size)*TAG_LENGTH
+}
+
+/// How many plaintext bytes a sealed blob of `sealed_length` bytes after its header holds, or `None` if no
+/// sealed blob is that long.
+pub fn unsealed_blob_length(sealed_length: u64) -> Option<u64> {
+    let chunks_length = sealed_length.checked_sub(BLOB_NONCE_PREFIX_LENGTH as u64)?;
+    let chunks = chunks_length.div_ceil(BLOB_CHUNK_LENGTH+TAG_LENGTH).max(1);
+    let size = chunks_length.checked_sub(chunks*TAG_LENGTH)?;
+    (blob_chunks(size) == chunks).then_some(size)
+}
+
+pub type MediaReader = Pin<Box<dyn AsyncRead+Send>>;
+
+/// Reads and throws away the next `count` bytes of `reader`, failing if it ends first.
+pub async fn skip(reader: &mut MediaReader, count: u64) -> Result<(), tokio::io::Error> {
+    let skipped = tokio::io::copy(&mut reader.take(count), &mut tokio::io::sink()).await?;
+    if skipped < count {
+        return Err(tokio::io::Error::new(tokio::io::ErrorKind::UnexpectedEof, "file ended early"));
+    }
+    Ok(())
+}
+
+/// Decrypts a sealed blob of `size` plaintext bytes from `blob_file`, which must be positioned right after the header,
+/// starting `offset` bytes in. Only the chunk holding `offset` and the ones after it are read. The blob must have been
+/// sealed for `blob_hash`, or staged if that is `None`.
+pub(crate) async fn open_sealed_blob<R: AsyncRead+AsyncSeek+Send+Unpin+'static>(key: Arc<StoreKey>, mut blob_file: R, blob_hash: Option<BlobHash>, size: u64, offset: u64) -> Result<MediaReader, tokio::io::Error> {
+    let mut nonce_prefix = [0u8; BLOB_NONCE_PREFIX_LENGTH];
+    blob_file.read_exact(&mut nonce_prefix).await?;
+    let chunks = blob_chunks(size);
+    let first_chunk = (offset/BLOB_CHUNK_LENGTH).min(chunks-1);
+    blob_file.seek(SeekFrom::Current((first_chunk*(BLOB_CHUNK_LENGTH+TAG_LENGTH)) as i64)).await?;
+
+    let state = (blob_file, first_chunk);
+    let stream = futures_util::stream::try_unfold(state, move |(mut blob_file, chunk_index)| {
+        let key = key.clone();
+        async move {
+            if chunk_index == chunks {
+                return Ok(None);
+            }
+            let chunk = read_next_chunk(&key, &mut blob_file, &nonce_prefix, blob_hash.as_ref(), size, chunk_index).await?;
+            Ok::<_, tokio::io::Error>(Some((Bytes::from(chunk), (blob_file, chunk_index+1))))
+        }
+    });
+    let mut reader: MediaReader = Box::pin(StreamReader::new(Box::pin(stream)));
+    skip(&mut reader, offset-first_chunk*BLOB_CHUNK_LENGTH).await?;
+    Ok(reader)
+}
+
+/// Reads and decrypts the chunk `blob_file` is positioned at, which must be chunk `chunk_index` of a blob of `size`
+/// plaintext bytes sealed for `blob_hash`.
+async fn read_next_chunk<R: AsyncRead+Unpin>(key: &StoreKey, blob_file: &mut R, nonce_prefix: &[u8; BLOB_NONCE_PREFIX_LENGTH], blob_hash: Option<&BlobHash>, size: u64, chunk_index: u64) -> Result<Vec<u8>, tokio::io::Error> {
+    let last = chunk_index+1 == blob_chunks(size);
+    let chunk_length = if last { size-chunk_index*BLOB_CHUNK_LENGTH } else { BLOB_CHUNK_LENGTH };
+    let mut sealed_chunk = vec![0u8; (chunk_length+TAG_LENGTH) as usize];
+    blob_file.read_exact(&mut sealed_chunk).await?;
+    key.open_chunk(nonce_prefix, blob_hash, chunk_index, last, &sealed_chunk)
+        .ok_or_else(|| tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("chunk {} of the blob could not be decrypted", chunk_index)))
+}
+
+/// Decrypts any chunk of a sealed blob on request, for readers that jump around instead of streaming it from the start.
+pub(crate) struct SealedChunks {
+    key: Arc<StoreKey>,
+    nonce_prefix: [u8; BLOB_NONCE_PREFIX_LENGTH],
+    blob_hash: BlobHash,
+    /// Where the first chunk starts in the blob file.
+    start: u64,
+    size: u64,
+}
+
+impl SealedChunks {
+    /// Reads what is needed to decrypt a blob of `size` plaintext bytes sealed for `blob_hash` from `blob_file`,
+    /// which must be positioned right after the header.
+    pub(crate) async fn read<R: AsyncRead+AsyncSeek+Unpin>(key: Arc<StoreKey>, blob_file: &mut R, blob_hash: BlobHash, size: u64) -> Result<SealedChunks, tokio::io::Error> {
+        let mut nonce_prefix = [0u8; BLOB_NONCE_PREFIX_LENGTH];
+        blob_file.read_exact(&mut nonce_prefix).await?;
+        Ok(SealedChunks {
+            key,
+            nonce_prefix,
+            blob_hash,
+            start: blob_file.stream_position().await?,
+            size,
+        })
+    }
+
+    /// Reads and decrypts chunk `chunk_index` of the blob, which holds plaintext bytes from `chunk_index*BLOB_CHUNK_LENGTH` on.
+    pub(crate) async fn read_chunk<R: AsyncRead+AsyncSeek+Unpin>(&self, blob_file: &mut R, chunk_index: u64) -> Result<Vec<u8>, tokio::io::Error> {
+        blob_file.seek(SeekFrom::Start(self.start+chunk_index*(BLOB_CHUNK_LENGTH+TAG_LENGTH))).await?;
+        read_next_chunk(&self.key, blob_file, &self.nonce_prefix, Some(&self.blob_hash), self.size, chunk_index).await
+    }
+}
+
+pub async fn is_encrypted(path: &Path) -> Result<bool, tokio::io::Error> {
+    tokio::fs::try_exists(path.join(ENCRYPTION_FILE)).await
+}
+
+/// Derives the key of the store at `path` from `passphrase`, or returns `None` if the store isn't encrypted.
+/// Fails if the passphrase is wrong or the store is encrypted and none was given.
+pub async fn load_store_key(path: &Path, passphrase: Option<&str>) -> Result<Option<StoreKey>, tokio::io::Error> {
+    if !is_encrypted(path).await? {
+        return Ok(None);
+    }
+    let passphrase = passphrase.ok_or_else(|| tokio::io::Error::new(tokio::io::ErrorKind::PermissionDenied, "the transactions store is encrypted but no passphrase was given"))?;
+
+    let config: EncryptionConfig = serde_json::from_slice(&tokio::fs::read(path.join(ENCRYPTION_FILE)).await?)?;
+    if config.version != ENCRYPTION_VERSION {
+        return Err(tokio::io::Error::new(tokio::io::ErrorKind::Unsupported, format!("encryption version {} is not {}", config.version, ENCRYPTION_VERSION)));
+    }
+    let key = StoreKey::derive(passphrase, &config)?;
+    if key.open(b"key check", &hex_to_bytes(&config.key_check)?).as_deref() != Some(KEY_CHECK_PLAINTEXT) {
+        return Err(tokio::io::Error::new(tokio::io::ErrorKind::PermissionDenied, "wrong passphrase for the transactions store"));
+    }
+    Ok(Some(key))
+}
+
+/// Picks a new salt and derives a key from `passphrase`, returning it with the config that derives it again.
+pub(crate) fn new_store_key(passphrase: &str) -> Result<(StoreKey, EncryptionConfig), tokio::io::Error> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill(&mut salt);
+    let mut config = EncryptionConfig {
+        version: ENCRYPTION_VERSION,
+        salt: bytes_to_hex(&salt),
+        memory_kib: Params::DEFAULT_M_COST,
+        iterations: Params::DEFAULT_T_COST,
+        parallelism: Params::DEFAULT_P_COST,
+        key_check: String::new(),
+    };
+    let key = StoreKey::derive(passphrase, &config)?;
+    config.key_check = bytes_to_hex(&key.seal(b"key check", KEY_CHECK_PLAINTEXT));
+    Ok((key, config))
+}
+
+/// Marks the store at `path` as encrypted. Records and blobs written before are left as they are.
+pub(crate) async fn write_encryption_config(path: &Path, config: &EncryptionConfig) -> Result<(), tokio::io::Error> {
+    let temporary_path = path.join(format!("{}.tmp", ENCRYPTION_FILE));
+    let mut config_file = tokio::fs::File::create(&temporary_path).await?;
+    config_file.write_all(&serde_json::to_vec_pretty(config)?).await?;
+    config_file.sync_all().await?;
+    drop(config_file);
+    tokio::fs::rename(&temporary_path, path.join(ENCRYPTION_FILE)).await?;
+    tokio::fs::File::open(path).await?.sync_all().await
+}
+
+fn bytes_to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_to_bytes(hex: &str) -> Result<Vec<u8>, tokio::io::Error> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(invalid_config(format!("invalid hex {:?}", hex)));
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i+2], 16).map_err(invalid_config))
+        .collect()
+}
+
+fn invalid_config<E: fmt::Display>(err: E) -> tokio::io::Error {
+    tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("invalid encryption config: {}", err))
+}
@@ -1,18 +1,57 @@
-use std::{path::PathBuf, io::SeekFrom, collections::HashMap};
+use std::{path::{Path, PathBuf}, io::SeekFrom, pin::Pin, collections::{HashMap, HashSet, BTreeSet}, sync::Arc, task::{Context, Poll}, future::Future, time::{SystemTime, UNIX_EPOCH}};
 
-use tokio::{io::{AsyncWriteExt, AsyncReadExt, AsyncSeekExt, AsyncRead, AsyncSeek}, fs::{File, OpenOptions}};
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use tokio::{io::{AsyncWriteExt, AsyncReadExt, AsyncSeekExt, AsyncRead, AsyncSeek, AsyncWrite, BufReader, ReadBuf}, fs::{File, OpenOptions}};
+use tokio_util::io::InspectReader;
+use log::{info, warn};
 
-use crate::types::{MediaType, SizedReference, CachedMedia};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
 
-pub const LATEST_VERSION: u64 = 1;
+use crate::crypto::{StoreKey, BlobSealer, SealedChunks, MediaReader, BLOB_CHUNK_LENGTH, load_store_key, new_store_key, write_encryption_config, open_sealed_blob, unsealed_blob_length, skip};
+use crate::migrations::{read_version, pending_migrations, backup_store, LATEST_VERSION};
+use crate::replay::{ReplayMode, ReplayError, ReplayErrorKind};
+use iloveu_lib::transaction::{Transaction, MediaMetadata, VariantSize, ExifMetadata, VideoInfo, RecordHeader, DecodeError, DecodeErrorKind, record_checksum, frame_record_with_flags, RECORD_HEADER_LENGTH, RECORD_CHECKSUM_LENGTH, MAX_RECORD_LENGTH, RECORD_FLAG_ENCRYPTED, RECORD_FLAG_COMPRESSED};
+
+use crate::types::{SizedReference, CachedMedia, TrashedMedia, CompactionReport, MediaQuery, SortOrder, BlobReference, BlobHash, FileReference, hash_to_hex};
+
+/// Every blob starts with this magic followed by a u64 of flags saying how the bytes after the header are stored.
+pub(crate) const BLOB_MAGIC: &[u8; 8] = b"iloveu\0b";
+pub(crate) const BLOB_HEADER_LENGTH: u64 = 16;
+/// The blob is sealed in chunks with the store's key after its header.
+pub(crate) const BLOB_FLAG_ENCRYPTED: u64 = 1 << 0;
+/// The blob is a zstd frame, which is sealed in chunks like any other bytes if the blob is encrypted too.
+pub(crate) const BLOB_FLAG_COMPRESSED: u64 = 1 << 1;
+/// Blobs are only compressed if their first chunk shrinks to less than this fraction of its size,
+/// since most photos and videos are compressed already.
+const COMPRESSIBLE_RATIO: f64 = 0.9;
 
 #[derive(Debug)]
 pub struct IloveuTransactionsStore {
     path: PathBuf,
+    replay_mode: ReplayMode,
+    key: Option<Arc<StoreKey>>,
+    compress: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StoreOptions {
+    /// Skip damaged records instead of failing on them.
+    pub replay_mode: ReplayMode,
+    /// Needed to open an encrypted store. Giving one for a new, empty store encrypts it.
+    pub passphrase: Option<String>,
+    /// Compress new records and blobs with zstd wherever that makes them smaller. Whatever was already
+    /// written stays as it is, so this can be changed between runs.
+    pub compress: bool,
 }
 
 impl IloveuTransactionsStore {
     pub async fn open<P: Into<PathBuf>>(path: P) -> Result<IloveuTransactionsStore, tokio::io::Error> {
+        Self::open_with_options(path, StoreOptions::default()).await
+    }
+
+    pub async fn open_with_options<P: Into<PathBuf>>(path: P, options: StoreOptions) -> Result<IloveuTransactionsStore, tokio::io::Error> {
         let path = path.into();
         if !(tokio::fs::try_exists(&path).await?) {
             tokio::fs::create_dir(&path).await?;
@@ -21,210 +60,2893 @@ impl IloveuTransactionsStore {
 
         let mut iloveu_transactions_store = IloveuTransactionsStore {
             path,
+            replay_mode: options.replay_mode,
+            key: None,
+            compress: options.compress,
         };
 
+        // migrations write blobs through the uploads directory too
+        iloveu_transactions_store.clear_uploads().await?;
         iloveu_transactions_store.run_migrations().await?;
+        iloveu_transactions_store.recover_torn_tail().await?;
+        iloveu_transactions_store.load_key(options.passphrase.as_deref()).await?;
 
         Ok(iloveu_transactions_store)
     }
 
     async fn run_migrations(&mut self) -> Result<(), tokio::io::Error> {
-        let mut version_file = tokio::fs::OpenOptions::new()
-            .write(true).read(true).open(self.path.join("version")).await?;
-        let last_version = version_file.read_u64().await?;
-        
-        if last_version != LATEST_VERSION {
-            match last_version {
-                0 => {
-                    tokio::fs::File::create(self.path.join("transactions")).await?;
+        let version = read_version(&self.path).await?;
+        let pending = pending_migrations(version)?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        if version > 0 {
+            let backup_path = backup_store(&self.path, version).await?;
+            info!("Backed up transactions store version {} to {}", version, backup_path.display());
+        }
+
+        let mut version_file = OpenOptions::new().write(true).open(self.path.join("version")).await?;
+        for migration in pending {
+            info!("Migrating transactions store from version {} to {}: {}", migration.from_version(), migration.from_version()+1, migration.description());
+            migration.run(self).await?;
+            version_file.seek(SeekFrom::Start(0)).await?;
+            version_file.write_u64(migration.from_version()+1).await?;
+            version_file.sync_all().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn load_key(&mut self, passphrase: Option<&str>) -> Result<(), tokio::io::Error> {
+        self.key = load_store_key(&self.path, passphrase).await?.map(Arc::new);
+        if let (None, Some(passphrase)) = (&self.key, passphrase) {
+            if !self.is_empty().await? {
+                return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, "the transactions store isn't encrypted, run the encrypt command to encrypt what's already in it"));
+            }
+            let (key, config) = new_store_key(passphrase)?;
+            write_encryption_config(&self.path, &config).await?;
+            self.key = Some(Arc::new(key));
+            info!("Encrypted the new transactions store");
+        }
+        Ok(())
+    }
+
+    async fn is_empty(&self) -> Result<bool, tokio::io::Error> {
+        let transactions_length = self.get_transactions_raw().await?.metadata().await?.len();
+        Ok(transactions_length == 0 && tokio::fs::read_dir(self.path.join("blobs")).await?.next_entry().await?.is_none())
+    }
+
+    /// The key the store is encrypted with, if it is.
+    pub fn key(&self) -> Option<&StoreKey> {
+        self.key.as_deref()
+    }
+
+    /// How new records are written, sealed with the key if the store is encrypted.
+    fn record_format(&self) -> RecordFormat {
+        RecordFormat::Framed {
+            compress: self.compress,
+            key: self.key.clone(),
+        }
+    }
+
+    /// Seals every record and blob of an unencrypted store with a key derived from `passphrase`, after backing it up.
+    /// Returns where the backup is, which is the way back if this is interrupted.
+    pub async fn encrypt(&mut self, cache: &IloveuCache, passphrase: &str) -> Result<PathBuf, tokio::io::Error> {
+        if self.key.is_some() {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::AlreadyExists, "the transactions store is already encrypted"));
+        }
+        let backup_path = backup_store(&self.path, LATEST_VERSION).await?;
+
+        // sealed blobs get new names, so the plain ones stay readable until compaction drops them at the end
+        let (key, config) = new_store_key(passphrase)?;
+        let key = Arc::new(key);
+        let sealed_uploads = Uploads {
+            path: self.path.join("uploads"),
+            key: Some(key.clone()),
+            compress: self.compress,
+        };
+        let mut sealed_blobs = HashSet::new();
+        let all_media = cache.media.values().chain(cache.trash.values().map(|trashed_media| &trashed_media.media));
+        for blob_reference in all_media.flat_map(CachedMedia::blob_references) {
+            if !sealed_blobs.insert(blob_reference.hash) {
+                continue;
+            }
+            let plain_blob = self.open_file(&FileReference::Blob(blob_reference)).await?;
+            let mut upload = sealed_uploads.write(plain_blob.take(blob_reference.size)).await?;
+            if upload.blob_reference != blob_reference {
+                upload.discard().await?;
+                return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("blob {} doesn't match its hash", hash_to_hex(&blob_reference.hash))));
+            }
+            tokio::fs::rename(&upload.temporary_path, self.path.join("blobs").join(key.blob_name(&blob_reference.hash))).await?;
+            upload.take_temporary_path();
+        }
+
+        write_encryption_config(&self.path, &config).await?;
+        self.key = Some(key);
+        self.compact_as(cache, f64::NEG_INFINITY, self.record_format()).await?;
+
+        Ok(backup_path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rewrites an unframed version 2 log into framed records, keeping every transaction as it was.
+    pub(crate) async fn reframe_legacy_transactions(&mut self) -> Result<(), tokio::io::Error> {
+        let mut legacy_bytes = Vec::new();
+        self.get_transactions_raw().await?.read_to_end(&mut legacy_bytes).await?;
+
+        let reframing_path = self.path.join("transactions.reframing");
+        let mut reframed_file = File::create(&reframing_path).await?;
+        let mut remaining = legacy_bytes.as_slice();
+        let mut reframed_length = 0;
+        while !remaining.is_empty() {
+            let transaction_type = remaining.read_u64().await?;
+            match Transaction::read(transaction_type, &mut remaining)? {
+                // inline media only ever appears in version 1 logs
+                None | Some(Transaction::AddInlineMedia { .. }) => {
+                    return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("unknown transaction type {}", transaction_type)));
                 },
-                1 => {},
-                _ => todo!("Unknown version")
+                Some(transaction) => reframed_length += RecordFormat::Framed { compress: false, key: None }.write_record(&mut reframed_file, reframed_length, &transaction).await?,
             }
-            version_file.seek(SeekFrom::Start(0)).await?;
-            version_file.write_u64(LATEST_VERSION).await?;
+        }
+        reframed_file.sync_all().await?;
+        drop(reframed_file);
+
+        self.replace_transactions(&reframing_path).await
+    }
+
+    /// Atomically swaps the transactions log for the already synced file at `new_transactions_path`.
+    async fn replace_transactions(&mut self, new_transactions_path: &Path) -> Result<(), tokio::io::Error> {
+        tokio::fs::rename(new_transactions_path, self.path.join("transactions")).await?;
+        // the snapshot's offset points into the old log
+        if tokio::fs::try_exists(self.path.join("snapshot")).await? {
+            tokio::fs::remove_file(self.path.join("snapshot")).await?;
+        }
+        File::open(&self.path).await?.sync_all().await
+    }
+
+    /// Rebuilds the cache from the latest snapshot plus the transactions after it, falling back to replaying
+    /// the whole log if the snapshot is missing or stale. Writes a new snapshot if any transactions were replayed.
+    pub async fn load_cache(&self) -> Result<IloveuCache, tokio::io::Error> {
+        let (cache, snapshot_length, transactions_length) = self.replay_from_snapshot().await?;
+        if cache.corrupt_transactions > 0 {
+            warn!("Skipped {} corrupt transactions, compaction and snapshots are disabled until they are repaired", cache.corrupt_transactions);
+        } else if cache.skipped_transactions > 0 {
+            warn!("Skipped {} transactions of unknown types, compaction and snapshots are disabled until a newer version opens the store", cache.skipped_transactions);
+        } else if transactions_length != snapshot_length {
+            self.write_snapshot(&cache, transactions_length).await?;
+        }
+        Ok(cache)
+    }
+
+    /// Replays the transactions appended since the last snapshot into a new snapshot, returning whether there were any.
+    /// The store must be borrowed for the whole call so no transactions are appended in the middle of it.
+    pub async fn update_snapshot(&self) -> Result<bool, tokio::io::Error> {
+        let (cache, snapshot_length, transactions_length) = self.replay_from_snapshot().await?;
+        // a snapshot would hide the skipped records from the next strict replay, or from a newer version that knows them
+        if transactions_length == snapshot_length || cache.corrupt_transactions > 0 || cache.skipped_transactions > 0 {
+            return Ok(false);
+        }
+        self.write_snapshot(&cache, transactions_length).await?;
+        Ok(true)
+    }
+
+    /// Returns the replayed cache, how much of the log the snapshot covered and how long the log is.
+    async fn replay_from_snapshot(&self) -> Result<(IloveuCache, u64, u64), tokio::io::Error> {
+        let mut transactions_file = self.get_transactions_raw().await?;
+        let transactions_length = transactions_file.metadata().await?.len();
+
+        let (mut cache, snapshot_length) = match self.read_snapshot(&mut transactions_file, transactions_length).await {
+            Ok(Some(mut snapshot)) => {
+                snapshot.cache.rebuild_indexes();
+                (snapshot.cache, snapshot.transactions_length)
+            },
+            Ok(None) => (IloveuCache::new(), 0),
+            Err(err) => {
+                warn!("Replaying the whole transactions log, the snapshot can't be used: {}", err);
+                (IloveuCache::new(), 0)
+            }
+        };
+
+        transactions_file.seek(SeekFrom::Start(snapshot_length)).await?;
+        cache.run_raw_transactions(transactions_file.take(transactions_length-snapshot_length), self.key(), self.replay_mode).await?;
+
+        Ok((cache, snapshot_length, transactions_length))
+    }
+
+    async fn read_snapshot(&self, transactions_file: &mut File, transactions_length: u64) -> Result<Option<Snapshot>, tokio::io::Error> {
+        let snapshot_path = self.path.join("snapshot");
+        if !(tokio::fs::try_exists(&snapshot_path).await?) {
+            return Ok(None);
+        }
+        let snapshot_bytes = tokio::fs::read(&snapshot_path).await?;
+        let snapshot_bytes = match &self.key {
+            Some(key) => key.open_snapshot(&snapshot_bytes).ok_or_else(|| tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, "snapshot could not be decrypted"))?,
+            None => snapshot_bytes,
+        };
+        let snapshot: Snapshot = serde_json::from_slice(&snapshot_bytes)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("snapshot version {} is not {}", snapshot.version, SNAPSHOT_VERSION)));
+        }
+        // older versions snapshotted past records of types they didn't know, which this one may know
+        if snapshot.cache.skipped_transactions > 0 || snapshot.cache.corrupt_transactions > 0 {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, "snapshot was taken past transactions that couldn't be replayed"));
+        }
+        if snapshot.transactions_length > transactions_length {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, "snapshot covers more than the whole transactions log"));
+        }
+
+        // a log that was replaced since has a different record ending at the snapshot's offset
+        if last_record_checksum(transactions_file, snapshot.transactions_length).await? != snapshot.last_record_checksum {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, "snapshot doesn't match the transactions log"));
+        }
+
+        Ok(Some(snapshot))
+    }
+
+    async fn write_snapshot(&self, cache: &IloveuCache, transactions_length: u64) -> Result<(), tokio::io::Error> {
+        let snapshot_bytes = serde_json::to_vec(&SnapshotRef {
+            version: SNAPSHOT_VERSION,
+            transactions_length,
+            last_record_checksum: last_record_checksum(&mut self.get_transactions_raw().await?, transactions_length).await?,
+            cache,
+        })?;
+        let snapshot_bytes = match &self.key {
+            Some(key) => key.seal_snapshot(&snapshot_bytes),
+            None => snapshot_bytes,
+        };
+
+        let temporary_path = self.path.join("snapshot.tmp");
+        let mut snapshot_file = File::create(&temporary_path).await?;
+        snapshot_file.write_all(&snapshot_bytes).await?;
+        snapshot_file.sync_all().await?;
+        drop(snapshot_file);
+        tokio::fs::rename(&temporary_path, self.path.join("snapshot")).await?;
+        File::open(&self.path).await?.sync_all().await
+    }
+
+    /// Finds a record at the end of the log that was only partly written when the server died and truncates it away.
+    /// Damage anywhere but the last record is not a torn write, so it is reported as an error instead, or in lenient
+    /// mode set aside in `transactions.torn-<offset>` along with everything after it.
+    async fn recover_torn_tail(&mut self) -> Result<(), tokio::io::Error> {
+        let transactions_file = self.get_transactions_raw().await?;
+        let transactions_length = transactions_file.metadata().await?.len();
+        let mut transaction_stream = BufReader::new(transactions_file);
+
+        let mut offset = 0;
+        let mut record_index = 0;
+        while offset < transactions_length {
+            let remaining = transactions_length-offset;
+            let mut header_bytes = [0u8; RECORD_HEADER_LENGTH as usize];
+            if remaining < RECORD_HEADER_LENGTH {
+                return self.truncate_transactions(offset, transactions_length, "the header was cut short").await;
+            }
+            transaction_stream.read_exact(&mut header_bytes).await?;
+            let header = RecordHeader::parse(&header_bytes);
+            // a torn write still has the whole header it was appended with, so a header no writer could have
+            // written means the log is damaged and everything after it may be perfectly good records
+            if header.length > MAX_RECORD_LENGTH || header.flags & !(RECORD_FLAG_ENCRYPTED | RECORD_FLAG_COMPRESSED) != 0 {
+                let reason = format!("record {} at offset {} of the transactions log has a corrupt header", record_index, offset);
+                return self.set_aside_damaged_tail(offset, transactions_length, &reason).await;
+            }
+            let record_length = RECORD_HEADER_LENGTH+header.length+RECORD_CHECKSUM_LENGTH;
+            if record_length > remaining {
+                // a length that was damaged instead reaches over the records that follow
+                let mut rest = Vec::new();
+                transaction_stream.read_to_end(&mut rest).await?;
+                if contains_whole_record(&rest) {
+                    let reason = format!("record {} at offset {} of the transactions log has a corrupt length", record_index, offset);
+                    return self.set_aside_damaged_tail(offset, transactions_length, &reason).await;
+                }
+                return self.truncate_transactions(offset, transactions_length, "the payload was cut short").await;
+            }
+
+            let mut payload = vec![0u8; header.length as usize];
+            transaction_stream.read_exact(&mut payload).await?;
+            let checksum = transaction_stream.read_u32().await?;
+            if record_checksum(&header_bytes, &payload) != checksum {
+                if record_length == remaining {
+                    return self.truncate_transactions(offset, transactions_length, "its checksum does not match").await;
+                }
+                // lenient replay skips the record later on
+                if self.replay_mode == ReplayMode::Strict {
+                    return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("record {} at offset {} of the transactions log is corrupt", record_index, offset)));
+                }
+            }
+
+            offset += record_length;
+            record_index += 1;
         }
 
         Ok(())
     }
 
+    /// Refuses to open a log damaged at `offset`, or in lenient mode moves everything from there on out of the way.
+    async fn set_aside_damaged_tail(&mut self, offset: u64, transactions_length: u64, reason: &str) -> Result<(), tokio::io::Error> {
+        if self.replay_mode == ReplayMode::Strict {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("{}, refusing to drop the {} bytes from there on, replay leniently to set them aside and open the rest", reason, transactions_length-offset)));
+        }
+        warn!("{}", reason);
+        self.truncate_transactions(offset, transactions_length, "it comes after a damaged record").await
+    }
+
+    /// Copies the bytes of the log from `offset` on into `transactions.torn-<offset>` before cutting them off.
+    async fn truncate_transactions(&mut self, offset: u64, transactions_length: u64, reason: &str) -> Result<(), tokio::io::Error> {
+        let torn_path = self.path.join(format!("transactions.torn-{}", offset));
+        warn!("Moving {} bytes at offset {} of the transactions log to {} because {}", transactions_length-offset, offset, torn_path.display(), reason);
+        let mut transactions_file = OpenOptions::new().read(true).write(true).open(self.path.join("transactions")).await?;
+        transactions_file.seek(SeekFrom::Start(offset)).await?;
+        let mut torn_file = File::create(&torn_path).await?;
+        tokio::io::copy(&mut (&mut transactions_file).take(transactions_length-offset), &mut torn_file).await?;
+        torn_file.sync_all().await?;
+        File::open(&self.path).await?.sync_all().await?;
+
+        transactions_file.set_len(offset).await?;
+        transactions_file.sync_all().await
+    }
+
     pub async fn get_transactions_raw(&self) -> Result<File, tokio::io::Error> {
         File::open(self.path.join("transactions")).await
     }
 
+    /// Changes whenever a transaction is appended or the log is replaced, so clients can tell whether what they
+    /// were sent from the cache is still current.
+    pub async fn log_tag(&self) -> Result<String, tokio::io::Error> {
+        let mut transactions_file = self.get_transactions_raw().await?;
+        let transactions_length = transactions_file.metadata().await?.len();
+        let checksum = last_record_checksum(&mut transactions_file, transactions_length).await?;
+        Ok(format!("{:x}-{:08x}", transactions_length, checksum.unwrap_or(0)))
+    }
+
+    /// Identifies the bytes of a media file, which never change once stored. Encrypted stores use the keyed blob
+    /// name rather than the hash, for the same reason their blobs are named that way.
+    pub fn file_tag(&self, file_reference: &FileReference) -> String {
+        match file_reference {
+            FileReference::Inline(sized_reference) => format!("inline-{:x}-{:x}", sized_reference.offset, sized_reference.size),
+            FileReference::Blob(blob_reference) => self.blob_name(&blob_reference.hash),
+        }
+    }
+
     pub async fn add_tag(&mut self, name: &str) -> Result<(), tokio::io::Error> {
-        let mut transactions_file = OpenOptions::new().append(true).open(self.path.join("transactions")).await?;
-        transactions_file.write_u64(0).await?; // transaction type tag
-        let name_bytes = name.as_bytes();
-        transactions_file.write_u64(name_bytes.len() as u64).await?; // size of name
-        transactions_file.write_all(name_bytes).await?; // name (utf8)
-        
-        Ok(())
+        self.append_transaction(&Transaction::AddTag {
+            name: name.to_string(),
+        }).await
+    }
+
+    /// Records media whose bytes were already streamed in through `uploads`, so the store only has to be borrowed
+    /// for the rename and the transaction, not for the whole upload.
+    pub async fn add_media(&mut self, metadata: &MediaMetadata, upload: Upload)-> Result<BlobReference, tokio::io::Error> {
+        let blob_reference = self.commit_upload(upload).await?;
+
+        self.append_transaction(&Transaction::AddBlobMedia {
+            metadata: metadata.clone(),
+            hash: blob_reference.hash,
+            size: blob_reference.size,
+        }).await?;
+
+        Ok(blob_reference)
+    }
+
+    pub async fn set_media_exif(&mut self, media_id: u64, exif: &ExifMetadata) -> Result<(), tokio::io::Error> {
+        self.append_transaction(&Transaction::SetMediaExif {
+            media_id,
+            exif: exif.clone(),
+        }).await
+    }
+
+    pub async fn set_video_info(&mut self, media_id: u64, video_info: &VideoInfo) -> Result<(), tokio::io::Error> {
+        self.append_transaction(&Transaction::SetVideoInfo {
+            media_id,
+            video_info: video_info.clone(),
+        }).await
+    }
+
+    /// Records a smaller copy of a picture that was streamed in through `uploads`.
+    pub async fn add_media_variant(&mut self, media_id: u64, variant_size: VariantSize, upload: Upload) -> Result<BlobReference, tokio::io::Error> {
+        let blob_reference = self.commit_upload(upload).await?;
+        self.add_original_as_variant(media_id, variant_size, blob_reference).await?;
+        Ok(blob_reference)
+    }
+
+    /// Records that a picture is already no bigger than `variant_size`, so its original blob is that variant too.
+    pub async fn add_original_as_variant(&mut self, media_id: u64, variant_size: VariantSize, original: BlobReference) -> Result<(), tokio::io::Error> {
+        self.append_transaction(&Transaction::AddMediaVariant {
+            media_id,
+            variant_size,
+            hash: original.hash,
+            size: original.size,
+        }).await
+    }
+
+    /// Copies everything from `file_stream` into the blob directory, reusing the existing blob if the same bytes were stored before.
+    async fn write_blob<R: AsyncRead+Unpin>(&mut self, file_stream: R) -> Result<BlobReference, tokio::io::Error> {
+        let upload = self.uploads().write(file_stream).await?;
+        self.commit_upload(upload).await
+    }
+
+    pub fn uploads(&self) -> Uploads {
+        Uploads {
+            path: self.path.join("uploads"),
+            key: self.key.clone(),
+            compress: self.compress,
+        }
+    }
+
+    /// Moves a finished upload into the blob directory. Blobs must only appear while the store is borrowed mutably,
+    /// otherwise compaction could collect them before their transaction is written.
+    async fn commit_upload(&mut self, mut upload: Upload) -> Result<BlobReference, tokio::io::Error> {
+        let blob_path = self.blob_path(&upload.blob_reference.hash);
+        if tokio::fs::try_exists(&blob_path).await? {
+            tokio::fs::remove_file(upload.take_temporary_path()).await?;
+        } else {
+            tokio::fs::rename(&upload.temporary_path, &blob_path).await?;
+            upload.take_temporary_path();
+        }
+        Ok(upload.blob_reference)
+    }
+
+    /// Anything left in the uploads directory was cut off by a restart and will never be committed.
+    async fn clear_uploads(&self) -> Result<(), tokio::io::Error> {
+        let uploads_path = self.path.join("uploads");
+        if tokio::fs::try_exists(&uploads_path).await? {
+            tokio::fs::remove_dir_all(&uploads_path).await?;
+        }
+        tokio::fs::create_dir(&uploads_path).await
+    }
+
+    fn blob_path(&self, hash: &BlobHash) -> PathBuf {
+        self.path.join("blobs").join(self.blob_name(hash))
+    }
+
+    /// Blobs of encrypted stores are named by a keyed hash, so the names don't give away which files are stored.
+    pub fn blob_name(&self, hash: &BlobHash) -> String {
+        match &self.key {
+            Some(key) => key.blob_name(hash),
+            None => hash_to_hex(hash),
+        }
     }
 
-    pub async fn add_media(&mut self, title: &str, description: &str, tags_vec: &Vec<u64>, taken_datetime: f64, media_type: MediaType, filename: &str, file_bytes: &Vec<u8>)-> Result<SizedReference, tokio::io::Error> {
+    /// Opens a media file for reading from its start, decrypting and decompressing it as needed. Nothing stops
+    /// the reader at the end of an inline file, so only `file_reference.size()` bytes of it should be read.
+    pub async fn open_file(&self, file_reference: &FileReference) -> Result<MediaReader, tokio::io::Error> {
+        Ok(self.open_stored_file(file_reference).await?.decompressed())
+    }
+
+    /// Opens a media file for reading from `offset` bytes in. Only compressed files have to be read up to there.
+    pub async fn open_file_at(&self, file_reference: &FileReference, offset: u64) -> Result<MediaReader, tokio::io::Error> {
+        match file_reference {
+            FileReference::Inline(sized_reference) => {
+                let mut transactions_file = self.get_transactions_raw().await?;
+                transactions_file.seek(SeekFrom::Start(sized_reference.offset+offset)).await?;
+                Ok(Box::pin(transactions_file))
+            },
+            FileReference::Blob(blob_reference) => open_blob_at(&self.blob_path(&blob_reference.hash), blob_reference, self.key.as_ref(), offset).await,
+        }
+    }
+
+    /// Opens a media file without decompressing it, so compressed bytes can be passed on as they are.
+    pub async fn open_stored_file(&self, file_reference: &FileReference) -> Result<StoredFile, tokio::io::Error> {
+        match file_reference {
+            FileReference::Inline(sized_reference) => {
+                let mut transactions_file = self.get_transactions_raw().await?;
+                transactions_file.seek(SeekFrom::Start(sized_reference.offset)).await?;
+                Ok(StoredFile {
+                    reader: Box::pin(transactions_file),
+                    compressed: false,
+                })
+            },
+            FileReference::Blob(blob_reference) => open_blob(&self.blob_path(&blob_reference.hash), blob_reference, self.key.as_ref()).await,
+        }
+    }
+
+    async fn append_transaction(&mut self, transaction: &Transaction) -> Result<(), tokio::io::Error> {
         let mut transactions_file = OpenOptions::new().append(true).open(self.path.join("transactions")).await?;
-        transactions_file.write_u64(1).await?;
+        let transactions_length = transactions_file.metadata().await?.len();
+        self.record_format().write_record(&mut transactions_file, transactions_length, transaction).await?;
+        transactions_file.sync_data().await
+    }
 
-        let title_bytes = title.as_bytes();
-        transactions_file.write_u64(title_bytes.len() as u64).await?;
-        transactions_file.write_all(title_bytes).await?;
+    pub async fn update_media(&mut self, media_id: u64, title: &str, description: &str, tags_vec: &[u64], taken_datetime: f64) -> Result<(), tokio::io::Error> {
+        self.append_transaction(&Transaction::UpdateMedia {
+            media_id,
+            title: title.to_string(),
+            description: description.to_string(),
+            tags_vec: tags_vec.to_vec(),
+            taken_datetime,
+        }).await
+    }
 
-        let description_bytes = description.as_bytes();
-        transactions_file.write_u64(description_bytes.len() as u64).await?;
-        transactions_file.write_all(description_bytes).await?;
+    pub async fn delete_media(&mut self, media_id: u64, deleted_datetime: f64) -> Result<(), tokio::io::Error> {
+        self.append_transaction(&Transaction::DeleteMedia {
+            media_id,
+            deleted_datetime,
+        }).await
+    }
 
-        let tags_count = tags_vec.len() as u64;
-        transactions_file.write_u64(tags_count).await?;
-        for tag_id in tags_vec {
-            transactions_file.write_u64(*tag_id).await?;
+    pub async fn restore_media(&mut self, media_id: u64) -> Result<(), tokio::io::Error> {
+        self.append_transaction(&Transaction::RestoreMedia {
+            media_id,
+        }).await
+    }
+
+    pub async fn rename_tag(&mut self, tag_id: u64, name: &str) -> Result<(), tokio::io::Error> {
+        self.append_transaction(&Transaction::RenameTag {
+            tag_id,
+            name: name.to_string(),
+        }).await
+    }
+
+    pub async fn merge_tag(&mut self, tag_id: u64, into_tag_id: u64) -> Result<(), tokio::io::Error> {
+        self.append_transaction(&Transaction::MergeTag {
+            tag_id,
+            into_tag_id,
+        }).await
+    }
+
+    pub async fn delete_tag(&mut self, tag_id: u64) -> Result<(), tokio::io::Error> {
+        self.append_transaction(&Transaction::DeleteTag {
+            tag_id,
+        }).await
+    }
+
+    /// Rewrites the transactions log so it only contains the live tags and media of `cache`
+    /// and the trashed media deleted after `oldest_kept_deleted_datetime`, then atomically swaps it in.
+    /// Ids are kept stable, so the cache must be rebuilt from the new log afterwards to pick up the new file offsets.
+    pub async fn compact(&mut self, cache: &IloveuCache, oldest_kept_deleted_datetime: f64) -> Result<CompactionReport, tokio::io::Error> {
+        self.compact_as(cache, oldest_kept_deleted_datetime, self.record_format()).await
+    }
+
+    pub(crate) async fn compact_as(&mut self, cache: &IloveuCache, oldest_kept_deleted_datetime: f64, record_format: RecordFormat) -> Result<CompactionReport, tokio::io::Error> {
+        if cache.skipped_transactions > 0 {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::Unsupported, format!("refusing to compact away {} transactions from a newer version", cache.skipped_transactions)));
+        }
+        if cache.corrupt_transactions > 0 {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::Unsupported, format!("refusing to compact away {} corrupt transactions", cache.corrupt_transactions)));
         }
 
-        transactions_file.write_f64(taken_datetime).await?;
+        let compacting_path = self.path.join("transactions.compacting");
+        let mut old_transactions = self.get_transactions_raw().await?;
+        let bytes_before = old_transactions.metadata().await?.len();
+        let mut compacted_file = File::create(&compacting_path).await?;
+        let mut compacted_length = 0;
 
-        transactions_file.write_u64(match media_type {
-            MediaType::Picture => 0,
-            MediaType::Video => 1,
-        }).await?;
+        let mut skipped_tag_ids = 0;
+        for tag_id in 0..cache.next_tag_id {
+            match cache.tags.get(&tag_id) {
+                Some(name) => {
+                    compacted_length += write_skip_ids(&mut compacted_file, compacted_length, &record_format, skipped_tag_ids, 0).await?;
+                    skipped_tag_ids = 0;
+                    compacted_length += record_format.write_record(&mut compacted_file, compacted_length, &Transaction::AddTag {
+                        name: name.clone(),
+                    }).await?;
+                },
+                None => skipped_tag_ids += 1,
+            }
+        }
+        compacted_length += write_skip_ids(&mut compacted_file, compacted_length, &record_format, skipped_tag_ids, 0).await?;
+
+        let mut skipped_media_ids = 0;
+        let mut dropped_media = 0;
+        let mut referenced_blobs = HashSet::new();
+        for media_id in 0..cache.next_media_id {
+            let (cached_media, deleted_datetime) = match (cache.media.get(&media_id), cache.trash.get(&media_id)) {
+                (Some(cached_media), _) => (cached_media, None),
+                (None, Some(trashed_media)) if trashed_media.deleted_datetime >= oldest_kept_deleted_datetime => {
+                    (&trashed_media.media, Some(trashed_media.deleted_datetime))
+                },
+                (None, trashed_media) => {
+                    if trashed_media.is_some() {
+                        dropped_media += 1;
+                    }
+                    skipped_media_ids += 1;
+                    continue;
+                }
+            };
+
+            compacted_length += write_skip_ids(&mut compacted_file, compacted_length, &record_format, 0, skipped_media_ids).await?;
+            skipped_media_ids = 0;
 
-        let filename_bytes = filename.as_bytes();
-        transactions_file.write_u64(filename_bytes.len() as u64).await?;
-        transactions_file.write_all(filename_bytes).await?;
+            let blob_reference = match cached_media.file_reference {
+                FileReference::Blob(blob_reference) => blob_reference,
+                FileReference::Inline(sized_reference) => {
+                    old_transactions.seek(SeekFrom::Start(sized_reference.offset)).await?;
+                    let blob_reference = self.write_blob((&mut old_transactions).take(sized_reference.size)).await?;
+                    if blob_reference.size != sized_reference.size {
+                        return Err(tokio::io::Error::new(tokio::io::ErrorKind::UnexpectedEof, format!("media {} is cut short in the transactions log", media_id)));
+                    }
+                    blob_reference
+                }
+            };
+            referenced_blobs.insert(self.blob_name(&blob_reference.hash));
+            compacted_length += record_format.write_record(&mut compacted_file, compacted_length, &Transaction::AddBlobMedia {
+                metadata: cached_media.metadata(),
+                hash: blob_reference.hash,
+                size: blob_reference.size,
+            }).await?;
+            if let Some(exif) = &cached_media.exif {
+                compacted_length += record_format.write_record(&mut compacted_file, compacted_length, &Transaction::SetMediaExif {
+                    media_id,
+                    exif: exif.clone(),
+                }).await?;
+            }
+            if let Some(video_info) = &cached_media.video_info {
+                compacted_length += record_format.write_record(&mut compacted_file, compacted_length, &Transaction::SetVideoInfo {
+                    media_id,
+                    video_info: video_info.clone(),
+                }).await?;
+            }
+            for variant_size in [VariantSize::Thumb, VariantSize::Medium] {
+                if let Some(variant) = cached_media.variants.get(&variant_size) {
+                    referenced_blobs.insert(self.blob_name(&variant.hash));
+                    compacted_length += record_format.write_record(&mut compacted_file, compacted_length, &Transaction::AddMediaVariant {
+                        media_id,
+                        variant_size,
+                        hash: variant.hash,
+                        size: variant.size,
+                    }).await?;
+                }
+            }
+
+            if let Some(deleted_datetime) = deleted_datetime {
+                compacted_length += record_format.write_record(&mut compacted_file, compacted_length, &Transaction::DeleteMedia {
+                    media_id,
+                    deleted_datetime,
+                }).await?;
+            }
+        }
+        write_skip_ids(&mut compacted_file, compacted_length, &record_format, 0, skipped_media_ids).await?;
+
+        compacted_file.flush().await?;
+        compacted_file.sync_all().await?;
+        let bytes_after = compacted_file.metadata().await?.len();
+        drop(compacted_file);
 
-        transactions_file.write_u64(file_bytes.len() as u64).await?;
-        let file_offset = transactions_file.stream_position().await?;
-        transactions_file.write_all(&file_bytes).await?;
+        self.replace_transactions(&compacting_path).await?;
+
+        // blobs are only written while the store is borrowed mutably, so anything unreferenced now is dead
+        let mut blobs = tokio::fs::read_dir(self.path.join("blobs")).await?;
+        let mut dropped_blobs = 0;
+        while let Some(blob) = blobs.next_entry().await? {
+            if !referenced_blobs.contains(blob.file_name().to_string_lossy().as_ref()) {
+                tokio::fs::remove_file(blob.path()).await?;
+                dropped_blobs += 1;
+            }
+        }
 
-        Ok(SizedReference {
-            offset: file_offset,
-            size: file_bytes.len() as u64
+        Ok(CompactionReport {
+            bytes_before,
+            bytes_after,
+            dropped_media,
+            dropped_blobs,
         })
     }
 }
 
-pub enum Transaction {
-    AddTag(String),
+/// A media file as it is kept in the store, already decrypted but possibly still compressed.
+pub struct StoredFile {
+    pub reader: MediaReader,
+    /// Whether `reader` gives a zstd frame rather than the file itself.
+    pub compressed: bool,
 }
 
-#[derive(Debug)]
-pub struct IloveuCache {
-    next_tag_id: u64,
-    tags: HashMap<u64, String>,
-    next_media_id: u64,
-    media: HashMap<u64, CachedMedia>,
+impl StoredFile {
+    pub fn decompressed(self) -> MediaReader {
+        if self.compressed {
+            Box::pin(ZstdDecoder::new(BufReader::new(self.reader)))
+        } else {
+            self.reader
+        }
+    }
 }
 
-impl IloveuCache {
-    pub fn new() -> Self {
-        Self {
-            next_tag_id: 0,
-            tags: HashMap::new(),
-            next_media_id: 0,
-            media: HashMap::new(),
-        }
+/// Opens the blob at `blob_path` and checks its header, returning the file positioned right after it and its flags.
+async fn open_blob_file(blob_path: &Path, blob_reference: &BlobReference) -> Result<(File, u64), tokio::io::Error> {
+    let mut blob_file = File::open(blob_path).await?;
+    let mut magic = [0u8; 8];
+    blob_file.read_exact(&mut magic).await?;
+    if &magic != BLOB_MAGIC {
+        return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("blob {} has an invalid header", hash_to_hex(&blob_reference.hash))));
+    }
+    let flags = blob_file.read_u64().await?;
+    if flags & !(BLOB_FLAG_ENCRYPTED | BLOB_FLAG_COMPRESSED) != 0 {
+        return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("blob {} has unsupported flags {:#x}", hash_to_hex(&blob_reference.hash), flags)));
     }
+    Ok((blob_file, flags))
+}
 
-    pub async fn run_raw_transactions<T: AsyncRead+AsyncSeek+Unpin>(&mut self, mut transaction_stream: T) -> Result<(), tokio::io::Error> {
-        loop {
-            match transaction_stream.read_u64().await {
-                Ok(u) => match u {
-                    0 => {
-                        let length = transaction_stream.read_u64().await?;
-                        let mut name_bytes = (0..length).map(|_| 0u8).collect::<Vec<u8>>();
-                        transaction_stream.read_exact(name_bytes.as_mut_slice()).await?;
-                        let name = String::from_utf8(name_bytes).unwrap();
-                        self.tags.insert(self.next_tag_id, name);
-                        self.next_tag_id += 1;
-                    },
-                    1 => {
-                        let title_length = transaction_stream.read_u64().await?;
-                        let mut title_bytes = (0..title_length).map(|_| 0u8).collect::<Vec<u8>>();
-                        transaction_stream.read_exact(title_bytes.as_mut_slice()).await?;
-                        let title = String::from_utf8(title_bytes).unwrap();
-
-                        let description_length = transaction_stream.read_u64().await?;
-                        let mut description_bytes = (0..description_length).map(|_| 0u8).collect::<Vec<u8>>();
-                        transaction_stream.read_exact(description_bytes.as_mut_slice()).await?;
-                        let description = String::from_utf8(description_bytes).unwrap();
-
-                        let tags_count = transaction_stream.read_u64().await?;
-                        let mut tags_vec: Vec<u64> = Vec::with_capacity(tags_count as usize);
-                        for _ in 0..tags_count {
-                            tags_vec.push(transaction_stream.read_u64().await?);
-                        }
+fn blob_key<'a>(blob_reference: &BlobReference, key: Option<&'a Arc<StoreKey>>) -> Result<&'a Arc<StoreKey>, tokio::io::Error> {
+    key.ok_or_else(|| tokio::io::Error::new(tokio::io::ErrorKind::PermissionDenied, format!("blob {} is encrypted but the store has no key", hash_to_hex(&blob_reference.hash))))
+}
 
-                        let taken_datetime = transaction_stream.read_f64().await?;
-
-                        let media_type = match transaction_stream.read_u64().await? {
-                            0 => MediaType::Picture,
-                            1 => MediaType::Video,
-                            _ => panic!("unknown media type")
-                        };
-
-                        let filename_length = transaction_stream.read_u64().await?;
-                        let mut filename_bytes = (0..filename_length).map(|_| 0u8).collect::<Vec<u8>>();
-                        transaction_stream.read_exact(filename_bytes.as_mut_slice()).await?;
-                        let filename = String::from_utf8(filename_bytes).unwrap();
-
-                        let compressed_file_length = transaction_stream.read_u64().await?;
-                        let compressed_file_offset = transaction_stream.stream_position().await?;
-                        transaction_stream.seek(SeekFrom::Current(compressed_file_length as i64)).await?;
-                        let compressed_file_reference = SizedReference {
-                            offset: compressed_file_offset,
-                            size: compressed_file_length,
-                        };
-
-                        self.media.insert(self.next_media_id, CachedMedia {
-                            title,
-                            description,
-                            tags_vec,
-                            taken_datetime,
-                            media_type,
-                            filename,
-                            file_reference: compressed_file_reference,
-                        });
-
-                        self.next_media_id += 1;
-                    }
-                    _ => {
-                        return Err(tokio::io::Error::new(tokio::io::ErrorKind::Other, "unknown transaction type"))
-                    }
-                },
-                Err(err) => match err.kind() {
-                    tokio::io::ErrorKind::UnexpectedEof => return Ok(()),
-                    _ => {
-                        return Err(err)
-                    }
-                }
+/// Opens the blob at `blob_path` after checking its header, decrypting it with `key` if it is sealed.
+pub(crate) async fn open_blob(blob_path: &Path, blob_reference: &BlobReference, key: Option<&Arc<StoreKey>>) -> Result<StoredFile, tokio::io::Error> {
+    let (blob_file, flags) = open_blob_file(blob_path, blob_reference).await?;
+    let compressed = flags & BLOB_FLAG_COMPRESSED != 0;
+
+    let reader = if flags & BLOB_FLAG_ENCRYPTED != 0 {
+        // how long a compressed file is only shows in the length of the blob
+        let stored_length = if compressed {
+            let blob_length = blob_file.metadata().await?.len();
+            unsealed_blob_length(blob_length.saturating_sub(BLOB_HEADER_LENGTH))
+                .ok_or_else(|| tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("blob {} is cut short", hash_to_hex(&blob_reference.hash))))?
+        } else {
+            blob_reference.size
+        };
+        open_sealed_blob(blob_key(blob_reference, key)?.clone(), blob_file, Some(blob_reference.hash), stored_length, 0).await?
+    } else {
+        Box::pin(blob_file)
+    };
+    Ok(StoredFile {
+        reader,
+        compressed,
+    })
+}
+
+/// Opens the blob at `blob_path` for reading from `offset` bytes into the file it holds.
+async fn open_blob_at(blob_path: &Path, blob_reference: &BlobReference, key: Option<&Arc<StoreKey>>, offset: u64) -> Result<MediaReader, tokio::io::Error> {
+    let (mut blob_file, flags) = open_blob_file(blob_path, blob_reference).await?;
+    if flags & BLOB_FLAG_COMPRESSED != 0 {
+        // there's no telling where an offset ends up in a zstd frame, so everything before it is decompressed
+        let mut file = open_blob(blob_path, blob_reference, key).await?.decompressed();
+        skip(&mut file, offset).await?;
+        return Ok(file);
+    }
+    if flags & BLOB_FLAG_ENCRYPTED != 0 {
+        return open_sealed_blob(blob_key(blob_reference, key)?.clone(), blob_file, Some(blob_reference.hash), blob_reference.size, offset).await;
+    }
+    blob_file.seek(SeekFrom::Current(offset as i64)).await?;
+    Ok(Box::pin(blob_file))
+}
+
+/// Writes files next to the blob directory without borrowing the store, so long uploads don't hold up everything else.
+#[derive(Debug, Clone)]
+pub struct Uploads {
+    path: PathBuf,
+    key: Option<Arc<StoreKey>>,
+    compress: bool,
+}
+
+impl Uploads {
+    /// Streams `file_stream` to a temporary file with a blob header, hashing it along the way.
+    pub async fn write<R: AsyncRead+Unpin>(&self, file_stream: R) -> Result<Upload, tokio::io::Error> {
+        self.write_as(file_stream, self.compress).await
+    }
+
+    async fn write_as<R: AsyncRead+Unpin>(&self, file_stream: R, compress: bool) -> Result<Upload, tokio::io::Error> {
+        let temporary_path = self.path.join(format!("{:016x}.tmp", rand::thread_rng().gen::<u64>()));
+        match write_upload(&temporary_path, file_stream, self.key.as_ref(), compress).await {
+            Ok(blob_reference) => Ok(Upload {
+                temporary_path,
+                blob_reference,
+            }),
+            Err(err) => {
+                tokio::fs::remove_file(&temporary_path).await.ok();
+                Err(err)
             }
         }
     }
 
-    pub fn add_tag(&mut self, name: String) -> u64 {
-        let tag_id = self.next_tag_id;
-
-        self.tags.insert(tag_id, name);
+    /// Opens a finished upload for reading, so it can be looked into before it is added.
+    pub async fn open(&self, upload: &Upload) -> Result<MediaReader, tokio::io::Error> {
+        Ok(open_blob(&upload.temporary_path, &upload.blob_reference, self.key.as_ref()).await?.decompressed())
+    }
 
-        self.next_tag_id += 1;
+    /// Opens a finished upload for reading from `offset` bytes in.
+    pub async fn open_at(&self, upload: &Upload, offset: u64) -> Result<MediaReader, tokio::io::Error> {
+        open_blob_at(&upload.temporary_path, &upload.blob_reference, self.key.as_ref(), offset).await
+    }
 
-        tag_id
+    /// Streams `file_stream` to an uncompressed upload for files that aren't media themselves, like archives, so it
+    /// can be read back with [`Uploads::open_seekable`]. It is sealed like any other upload on an encrypted store.
+    pub async fn write_temporary<R: AsyncRead+Unpin>(&self, file_stream: R) -> Result<Upload, tokio::io::Error> {
+        self.write_as(file_stream, false).await
     }
 
-    pub fn get_tags(&self) -> &HashMap<u64, String> {
-        &self.tags
+    /// Opens an upload written by [`Uploads::write_temporary`] for reading from anywhere in it.
+    pub async fn open_seekable(&self, upload: &Upload) -> Result<SeekableUpload, tokio::io::Error> {
+        let (mut blob_file, flags) = open_blob_file(&upload.temporary_path, &upload.blob_reference).await?;
+        if flags & BLOB_FLAG_COMPRESSED != 0 {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, "a compressed upload can only be read from its start"));
+        }
+        let sealed_chunks = match flags & BLOB_FLAG_ENCRYPTED != 0 {
+            true => Some(Arc::new(SealedChunks::read(blob_key(&upload.blob_reference, self.key.as_ref())?.clone(), &mut blob_file, upload.blob_reference.hash, upload.blob_reference.size).await?)),
+            false => None,
+        };
+        Ok(SeekableUpload {
+            sealed_chunks,
+            size: upload.blob_reference.size,
+            position: 0,
+            chunk: None,
+            state: ChunkState::Idle(blob_file),
+        })
     }
+}
 
-    pub fn add_media(&mut self, cached_media: CachedMedia) -> u64 {
-        let media_id = self.next_media_id;
+type LoadingChunk = Pin<Box<dyn Future<Output = Result<(File, u64, Vec<u8>), tokio::io::Error>>+Send>>;
 
-        self.media.insert(media_id, cached_media);
+enum ChunkState {
+    Idle(File),
+    /// The file is owned by the read until it finishes.
+    Loading(LoadingChunk),
+    Failed,
+}
 
-        self.next_media_id += 1;
+/// Reads an uncompressed upload one chunk at a time from wherever it was last seeked to, which is what zip readers
+/// need. Sealed chunks are only ever decrypted in memory.
+pub struct SeekableUpload {
+    sealed_chunks: Option<Arc<SealedChunks>>,
+    size: u64,
+    position: u64,
+    /// The index and bytes of the chunk read last.
+    chunk: Option<(u64, Vec<u8>)>,
+    state: ChunkState,
+}
 
-        media_id
+async fn load_chunk(mut blob_file: File, sealed_chunks: Option<Arc<SealedChunks>>, size: u64, chunk_index: u64) -> Result<(File, u64, Vec<u8>), tokio::io::Error> {
+    let chunk = match sealed_chunks {
+        Some(sealed_chunks) => sealed_chunks.read_chunk(&mut blob_file, chunk_index).await?,
+        None => {
+            blob_file.seek(SeekFrom::Start(BLOB_HEADER_LENGTH+chunk_index*BLOB_CHUNK_LENGTH)).await?;
+            let mut chunk = vec![0u8; (size-chunk_index*BLOB_CHUNK_LENGTH).min(BLOB_CHUNK_LENGTH) as usize];
+            blob_file.read_exact(&mut chunk).await?;
+            chunk
+        },
+    };
+    Ok((blob_file, chunk_index, chunk))
+}
+
+impl AsyncRead for SeekableUpload {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let upload = &mut *self;
+        loop {
+            if upload.position >= upload.size || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let chunk_index = upload.position/BLOB_CHUNK_LENGTH;
+            if let Some((loaded_index, chunk)) = &upload.chunk {
+                if *loaded_index == chunk_index {
+                    let start = (upload.position-chunk_index*BLOB_CHUNK_LENGTH) as usize;
+                    let length = buf.remaining().min(chunk.len()-start);
+                    buf.put_slice(&chunk[start..start+length]);
+                    upload.position += length as u64;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            // a seek while a chunk was loading just means another one is loaded after it
+            match std::mem::replace(&mut upload.state, ChunkState::Failed) {
+                ChunkState::Idle(blob_file) => {
+                    upload.state = ChunkState::Loading(Box::pin(load_chunk(blob_file, upload.sealed_chunks.clone(), upload.size, chunk_index)));
+                },
+                ChunkState::Loading(mut loading) => match loading.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        upload.state = ChunkState::Loading(loading);
+                        return Poll::Pending;
+                    },
+                    Poll::Ready(Ok((blob_file, loaded_index, chunk))) => {
+                        upload.state = ChunkState::Idle(blob_file);
+                        upload.chunk = Some((loaded_index, chunk));
+                    },
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                },
+                ChunkState::Failed => {
+                    return Poll::Ready(Err(tokio::io::Error::other("an earlier read of the upload failed")));
+                },
+            }
+        }
     }
+}
 
-    pub fn get_media(&self) -> &HashMap<u64, CachedMedia> {
-        &self.media
+impl AsyncSeek for SeekableUpload {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, "seeking to before the start of the upload"))?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
     }
-}
\ No newline at end of file
+}
+
+/// Reads until `buffer` is full or the stream ends, returning how much was read.
+async fn read_chunk<R: AsyncRead+Unpin>(stream: &mut R, buffer: &mut [u8]) -> Result<usize, tokio::io::Error> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let read = stream.read(&mut buffer[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+/// Whether compressing the first chunk of a file shrinks it enough to be worth compressing all of it.
+fn is_compressible(first_chunk: &[u8]) -> bool {
+    match zstd::bulk::compress(first_chunk, 0) {
+        Ok(compressed) => (compressed.len() as f64) < first_chunk.len() as f64*COMPRESSIBLE_RATIO,
+        Err(_) => false,
+    }
+}
+
+async fn write_upload<R: AsyncRead+Unpin>(temporary_path: &Path, mut file_stream: R, key: Option<&Arc<StoreKey>>, compress: bool) -> Result<BlobReference, tokio::io::Error> {
+    let mut first_chunk = vec![0u8; BLOB_CHUNK_LENGTH as usize];
+    let first_chunk_length = read_chunk(&mut file_stream, &mut first_chunk).await?;
+    first_chunk.truncate(first_chunk_length);
+    let compress = compress && is_compressible(&first_chunk);
+
+    let mut flags = 0;
+    if key.is_some() {
+        flags |= BLOB_FLAG_ENCRYPTED;
+    }
+    if compress {
+        flags |= BLOB_FLAG_COMPRESSED;
+    }
+
+    // the hash and size are of the file itself, not of what ends up in the blob
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let file_stream = InspectReader::new(std::io::Cursor::new(first_chunk).chain(file_stream), |bytes: &[u8]| {
+        hasher.update(bytes);
+        size += bytes.len() as u64;
+    });
+    let stored_stream: Pin<Box<dyn AsyncRead+'_>> = if compress {
+        Box::pin(ZstdEncoder::new(BufReader::new(file_stream)))
+    } else {
+        Box::pin(file_stream)
+    };
+
+    // a sealed blob is bound to the hash of its file, which is only known once all of it has been read, so it is
+    // staged without one first and sealed again from there
+    let staging_path = temporary_path.with_extension("staging");
+    let written = write_blob_file(if key.is_some() { &staging_path } else { temporary_path }, flags, stored_stream, key.map(|key| key.blob_sealer(None))).await;
+    let blob_reference = BlobReference {
+        hash: hasher.finalize().into(),
+        size,
+    };
+
+    let Some(key) = key else {
+        written?.0.sync_all().await?;
+        return Ok(blob_reference);
+    };
+    let resealed = match written {
+        Ok((_, stored_length)) => reseal_staged_blob(key, &staging_path, stored_length, temporary_path, flags, &blob_reference).await,
+        Err(err) => Err(err),
+    };
+    tokio::fs::remove_file(&staging_path).await.ok();
+    resealed.map(|()| blob_reference)
+}
+
+/// Writes a blob header with `flags` and then everything in `stored_stream`, sealed by `blob_sealer` if there is one.
+/// Returns the file, flushed but not synced, and how many bytes of `stored_stream` it holds.
+async fn write_blob_file<R: AsyncRead+Unpin>(blob_path: &Path, flags: u64, mut stored_stream: R, mut blob_sealer: Option<BlobSealer<'_>>) -> Result<(File, u64), tokio::io::Error> {
+    let mut blob_file = File::create(blob_path).await?;
+    blob_file.write_all(BLOB_MAGIC).await?;
+    blob_file.write_u64(flags).await?;
+    if let Some(blob_sealer) = &blob_sealer {
+        blob_file.write_all(blob_sealer.nonce_prefix()).await?;
+    }
+
+    let mut stored_length = 0;
+    let mut buffer = vec![0u8; BLOB_CHUNK_LENGTH as usize];
+    loop {
+        // sealed chunks have to be full, so reads are gathered until the buffer is
+        let filled = read_chunk(&mut stored_stream, &mut buffer).await?;
+        stored_length += filled as u64;
+
+        if filled < buffer.len() {
+            match blob_sealer.take() {
+                Some(blob_sealer) => blob_file.write_all(&blob_sealer.finish(&buffer[..filled])).await?,
+                None => blob_file.write_all(&buffer[..filled]).await?,
+            }
+            break;
+        }
+        match &mut blob_sealer {
+            Some(blob_sealer) => if let Some(sealed_chunk) = blob_sealer.push(&buffer) {
+                blob_file.write_all(&sealed_chunk).await?;
+            },
+            None => blob_file.write_all(&buffer).await?,
+        }
+    }
+    blob_file.flush().await?;
+    Ok((blob_file, stored_length))
+}
+
+/// Seals the blob staged at `staging_path`, holding `stored_length` bytes, again for the file of `blob_reference`.
+async fn reseal_staged_blob(key: &Arc<StoreKey>, staging_path: &Path, stored_length: u64, blob_path: &Path, flags: u64, blob_reference: &BlobReference) -> Result<(), tokio::io::Error> {
+    let (staged_file, _) = open_blob_file(staging_path, blob_reference).await?;
+    let staged_stream = open_sealed_blob(key.clone(), staged_file, None, stored_length, 0).await?;
+    let (blob_file, _) = write_blob_file(blob_path, flags, staged_stream, Some(key.blob_sealer(Some(blob_reference.hash)))).await?;
+    blob_file.sync_all().await
+}
+
+/// A fully written upload that is not part of the store until passed to [`IloveuTransactionsStore::add_media`].
+/// Dropping it without adding or discarding it, like when a request fails or is cancelled halfway, deletes its file.
+#[derive(Debug)]
+pub struct Upload {
+    temporary_path: PathBuf,
+    blob_reference: BlobReference,
+}
+
+impl Upload {
+    pub fn blob_reference(&self) -> BlobReference {
+        self.blob_reference
+    }
+
+    /// Deletes the temporary file of an upload that won't be added after all.
+    pub async fn discard(mut self) -> Result<(), tokio::io::Error> {
+        tokio::fs::remove_file(self.take_temporary_path()).await
+    }
+
+    /// Hands the temporary file over to whoever moves or deletes it, so dropping the upload leaves it alone.
+    fn take_temporary_path(&mut self) -> PathBuf {
+        std::mem::take(&mut self.temporary_path)
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if !self.temporary_path.as_os_str().is_empty() {
+            let _ = std::fs::remove_file(&self.temporary_path);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum RecordFormat {
+    /// Each transaction is just its type followed by its payload, as written by version 1 and 2 stores.
+    Unframed,
+    /// Each transaction is its type, flags, payload length, the payload and finally a CRC-32 of everything before it.
+    /// Payloads are compressed if that makes them smaller and then sealed with the key if there is one.
+    Framed {
+        compress: bool,
+        key: Option<Arc<StoreKey>>,
+    },
+}
+
+impl RecordFormat {
+    /// Writes a whole transaction in one write, so a crash can only ever leave a torn record at the end.
+    /// Sealed records are bound to `offset`, where they start in the log. Returns how long the record is.
+    pub(crate) async fn write_record<T: AsyncWrite+Unpin>(&self, transaction_stream: &mut T, offset: u64, transaction: &Transaction) -> Result<u64, tokio::io::Error> {
+        let record = match self {
+            RecordFormat::Unframed => {
+                let mut record = transaction.transaction_type().to_be_bytes().to_vec();
+                record.extend_from_slice(&transaction.encode());
+                record
+            },
+            RecordFormat::Framed { compress, key } => {
+                let transaction_type = transaction.transaction_type();
+                let mut flags = 0;
+                let mut payload = transaction.encode();
+                if *compress {
+                    if let Some(compressed) = zstd::bulk::compress(&payload, 0).ok().filter(|compressed| compressed.len() < payload.len()) {
+                        payload = compressed;
+                        flags |= RECORD_FLAG_COMPRESSED;
+                    }
+                }
+                if let Some(key) = key {
+                    payload = key.seal_record(transaction_type, offset, &payload);
+                    flags |= RECORD_FLAG_ENCRYPTED;
+                }
+                frame_record_with_flags(transaction_type, flags, &payload)
+            },
+        };
+        transaction_stream.write_all(&record).await?;
+        Ok(record.len() as u64)
+    }
+}
+
+/// Whether a whole record with a matching checksum starts anywhere in `bytes`.
+fn contains_whole_record(bytes: &[u8]) -> bool {
+    let record_overhead = (RECORD_HEADER_LENGTH+RECORD_CHECKSUM_LENGTH) as usize;
+    (0..bytes.len().saturating_sub(record_overhead-1)).any(|start| {
+        let header_bytes: &[u8; RECORD_HEADER_LENGTH as usize] = bytes[start..start+RECORD_HEADER_LENGTH as usize].try_into().unwrap();
+        let header = RecordHeader::parse(header_bytes);
+        let payload_start = start+RECORD_HEADER_LENGTH as usize;
+        if header.flags & !(RECORD_FLAG_ENCRYPTED | RECORD_FLAG_COMPRESSED) != 0 || header.length > (bytes.len()-start-record_overhead) as u64 {
+            return false;
+        }
+        let payload_end = payload_start+header.length as usize;
+        let checksum = u32::from_be_bytes(bytes[payload_end..payload_end+RECORD_CHECKSUM_LENGTH as usize].try_into().unwrap());
+        record_checksum(header_bytes, &bytes[payload_start..payload_end]) == checksum
+    })
+}
+
+/// A framed transaction as read from the log. Its payload can't be trusted if the record is damaged,
+/// but its length still says where the next record starts.
+struct RawRecord {
+    header: RecordHeader,
+    payload: Vec<u8>,
+    damage: Option<String>,
+}
+
+/// Undoes whatever the record's flags say was done to the payload of a record starting `offset` bytes into the log.
+fn open_payload(record: &RawRecord, offset: u64, key: Option<&StoreKey>) -> Result<Vec<u8>, ReplayErrorKind> {
+    let flags = record.header.flags;
+    if flags & !(RECORD_FLAG_ENCRYPTED | RECORD_FLAG_COMPRESSED) != 0 {
+        return Err(ReplayErrorKind::Corrupt(format!("unsupported record flags {:#x}", flags)));
+    }
+    let payload = match (flags & RECORD_FLAG_ENCRYPTED != 0, key) {
+        (false, _) => record.payload.clone(),
+        (true, Some(key)) => key.open_record(record.header.transaction_type, offset, &record.payload)
+            .ok_or_else(|| ReplayErrorKind::Corrupt("record could not be decrypted".to_string()))?,
+        (true, None) => return Err(ReplayErrorKind::Corrupt("record is encrypted but no key was given".to_string())),
+    };
+    if flags & RECORD_FLAG_COMPRESSED != 0 {
+        return zstd::bulk::decompress(&payload, MAX_RECORD_LENGTH as usize)
+            .map_err(|err| ReplayErrorKind::Corrupt(format!("record could not be decompressed: {}", err)));
+    }
+    Ok(payload)
+}
+
+/// Reads the next framed transaction, returning `None` at a clean end of the log.
+async fn read_record<T: AsyncRead+Unpin>(transaction_stream: &mut T) -> Result<Option<RawRecord>, ReplayErrorKind> {
+    let mut header_bytes = [0u8; RECORD_HEADER_LENGTH as usize];
+    let read = transaction_stream.read(&mut header_bytes).await.map_err(ReplayErrorKind::Io)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    transaction_stream.read_exact(&mut header_bytes[read..]).await.map_err(ReplayErrorKind::Io)?;
+    let header = RecordHeader::parse(&header_bytes);
+    if header.length > MAX_RECORD_LENGTH {
+        return Err(ReplayErrorKind::Corrupt(format!("record length {} is too long", header.length)));
+    }
+
+    let mut payload = vec![0u8; header.length as usize];
+    transaction_stream.read_exact(&mut payload).await.map_err(ReplayErrorKind::Io)?;
+    let checksum = transaction_stream.read_u32().await.map_err(ReplayErrorKind::Io)?;
+
+    let damage = if record_checksum(&header_bytes, &payload) != checksum {
+        Some("record checksum mismatch".to_string())
+    } else {
+        None
+    };
+
+    Ok(Some(RawRecord {
+        header,
+        payload,
+        damage,
+    }))
+}
+
+/// The checksum closing the record that ends `transactions_length` bytes into the log, if any record does.
+async fn last_record_checksum(transactions_file: &mut File, transactions_length: u64) -> Result<Option<u32>, tokio::io::Error> {
+    if transactions_length < RECORD_CHECKSUM_LENGTH {
+        return Ok(None);
+    }
+    transactions_file.seek(SeekFrom::Start(transactions_length-RECORD_CHECKSUM_LENGTH)).await?;
+    Ok(Some(transactions_file.read_u32().await?))
+}
+
+/// Advances the next tag and media ids without adding anything, so compaction can keep ids stable.
+/// Returns how long the record is, if one was needed.
+async fn write_skip_ids<T: AsyncWrite+Unpin>(transaction_stream: &mut T, offset: u64, record_format: &RecordFormat, tag_ids: u64, media_ids: u64) -> Result<u64, tokio::io::Error> {
+    if tag_ids == 0 && media_ids == 0 {
+        return Ok(0);
+    }
+    record_format.write_record(transaction_stream, offset, &Transaction::SkipIds {
+        tag_ids,
+        media_ids,
+    }).await
+}
+
+/// The current time in milliseconds since the unix epoch, the same unit as `taken_datetime`.
+pub fn now_datetime() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_millis() as f64).unwrap_or(0.0)
+}
+
+/// Bumped whenever the serialized form of [`IloveuCache`] changes, so older snapshots are replayed from scratch instead.
+const SNAPSHOT_VERSION: u64 = 4;
+
+/// The cache after replaying the first `transactions_length` bytes of the log, along with the checksum
+/// of the last of those records so a snapshot of a since replaced log is noticed.
+#[derive(Deserialize)]
+struct Snapshot {
+    version: u64,
+    transactions_length: u64,
+    last_record_checksum: Option<u32>,
+    cache: IloveuCache,
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u64,
+    transactions_length: u64,
+    last_record_checksum: Option<u32>,
+    cache: &'a IloveuCache,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IloveuCache {
+    next_tag_id: u64,
+    tags: HashMap<u64, String>,
+    next_media_id: u64,
+    media: HashMap<u64, CachedMedia>,
+    trash: HashMap<u64, TrashedMedia>,
+    /// Transactions of unknown types, which must not be dropped by compaction.
+    skipped_transactions: u64,
+    /// Damaged transactions skipped by a lenient replay, which must not be dropped by compaction either.
+    corrupt_transactions: u64,
+    /// How many records and bytes of the log have been replayed, so errors can say where they happened.
+    replayed_records: u64,
+    replayed_length: u64,
+    /// Rebuilt from `media` after loading a snapshot instead of being stored in it.
+    #[serde(skip)]
+    indexes: MediaIndexes,
+}
+
+/// Orders media by when it was taken, breaking ties by id.
+#[derive(Debug, Clone, Copy)]
+struct DatetimeKey(f64, u64);
+
+impl PartialEq for DatetimeKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for DatetimeKey {}
+
+impl PartialOrd for DatetimeKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DatetimeKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+/// Secondary indexes over the live (not trashed) media.
+#[derive(Debug, Default)]
+struct MediaIndexes {
+    by_datetime: BTreeSet<DatetimeKey>,
+    by_tag: HashMap<u64, BTreeSet<DatetimeKey>>,
+    /// Each word of a title or description, to how much it counts towards each media it appears in.
+    by_word: HashMap<String, HashMap<u64, u32>>,
+}
+
+/// Words in titles count for more than words in descriptions.
+const TITLE_WORD_WEIGHT: u32 = 3;
+const DESCRIPTION_WORD_WEIGHT: u32 = 1;
+
+/// Splits text into lowercase words, ignoring punctuation.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+fn weighted_words(cached_media: &CachedMedia) -> HashMap<String, u32> {
+    let mut weighted_words = HashMap::new();
+    for word in words(&cached_media.title) {
+        *weighted_words.entry(word).or_default() += TITLE_WORD_WEIGHT;
+    }
+    for word in words(&cached_media.description) {
+        *weighted_words.entry(word).or_default() += DESCRIPTION_WORD_WEIGHT;
+    }
+    weighted_words
+}
+
+impl MediaIndexes {
+    fn insert(&mut self, media_id: u64, cached_media: &CachedMedia) {
+        let key = DatetimeKey(cached_media.taken_datetime, media_id);
+        self.by_datetime.insert(key);
+        for tag_id in &cached_media.tags_vec {
+            self.by_tag.entry(*tag_id).or_default().insert(key);
+        }
+        for (word, weight) in weighted_words(cached_media) {
+            self.by_word.entry(word).or_default().insert(media_id, weight);
+        }
+    }
+
+    fn remove(&mut self, media_id: u64, cached_media: &CachedMedia) {
+        let key = DatetimeKey(cached_media.taken_datetime, media_id);
+        self.by_datetime.remove(&key);
+        for tag_id in &cached_media.tags_vec {
+            if let Some(tag_index) = self.by_tag.get_mut(tag_id) {
+                tag_index.remove(&key);
+                if tag_index.is_empty() {
+                    self.by_tag.remove(tag_id);
+                }
+            }
+        }
+        for word in weighted_words(cached_media).keys() {
+            if let Some(word_index) = self.by_word.get_mut(word) {
+                word_index.remove(&media_id);
+                if word_index.is_empty() {
+                    self.by_word.remove(word);
+                }
+            }
+        }
+    }
+}
+
+impl Default for IloveuCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IloveuCache {
+    pub fn new() -> Self {
+        Self {
+            next_tag_id: 0,
+            tags: HashMap::new(),
+            next_media_id: 0,
+            media: HashMap::new(),
+            trash: HashMap::new(),
+            skipped_transactions: 0,
+            corrupt_transactions: 0,
+            replayed_records: 0,
+            replayed_length: 0,
+            indexes: MediaIndexes::default(),
+        }
+    }
+
+    fn rebuild_indexes(&mut self) {
+        self.indexes = MediaIndexes::default();
+        for (media_id, cached_media) in &self.media {
+            self.indexes.insert(*media_id, cached_media);
+        }
+    }
+
+    /// Replays a framed transactions log, as written by every store since version 3, continuing from wherever
+    /// the cache left off and opening records sealed with `key`. In lenient mode damaged records are skipped and
+    /// returned instead of failing the replay.
+    pub async fn run_raw_transactions<T: AsyncRead+Unpin>(&mut self, transaction_stream: T, key: Option<&StoreKey>, replay_mode: ReplayMode) -> Result<Vec<ReplayError>, ReplayError> {
+        let mut transaction_stream = BufReader::new(transaction_stream);
+        let mut skipped = Vec::new();
+        loop {
+            let record = match read_record(&mut transaction_stream).await {
+                Ok(Some(record)) => record,
+                Ok(None) => return Ok(skipped),
+                // without a trustworthy length there is no telling where the next record starts
+                Err(kind) => {
+                    self.replay_failed(replay_mode, kind, &mut skipped)?;
+                    return Ok(skipped);
+                },
+            };
+
+            let payload = match record.damage {
+                Some(reason) => Err(ReplayErrorKind::Corrupt(reason)),
+                None => open_payload(&record, self.replayed_length, key),
+            };
+            let result = match payload {
+                Err(kind) => Err(kind),
+                Ok(payload) => match Transaction::decode(record.header.transaction_type, &payload) {
+                    Ok(Some(transaction)) => self.apply_transaction(transaction).map_err(ReplayErrorKind::Invalid),
+                    Ok(None) => {
+                        warn!("Skipping transaction with unknown type {} ({} bytes), it was probably written by a newer version", record.header.transaction_type, record.header.length);
+                        self.skipped_transactions += 1;
+                        Ok(())
+                    },
+                    Err(err) => Err(ReplayErrorKind::Decode(err)),
+                },
+            };
+            if let Err(kind) = result {
+                self.replay_failed(replay_mode, kind, &mut skipped)?;
+            }
+
+            self.replayed_records += 1;
+            self.replayed_length += RECORD_HEADER_LENGTH+record.header.length+RECORD_CHECKSUM_LENGTH;
+        }
+    }
+
+    /// Fails the replay at the current record, or records it as skipped in lenient mode.
+    fn replay_failed(&mut self, replay_mode: ReplayMode, kind: ReplayErrorKind, skipped: &mut Vec<ReplayError>) -> Result<(), ReplayError> {
+        let err = ReplayError {
+            record_index: self.replayed_records,
+            offset: self.replayed_length,
+            kind,
+        };
+        match replay_mode {
+            ReplayMode::Strict => Err(err),
+            ReplayMode::Lenient => {
+                warn!("Skipping {}", err);
+                self.corrupt_transactions += 1;
+                skipped.push(err);
+                Ok(())
+            },
+        }
+    }
+
+    /// Replays an unframed transactions log from a version 1 or 2 store, where transactions follow each other
+    /// without lengths or checksums and version 1 stores media bytes inline.
+    pub(crate) async fn run_legacy_transactions<T: AsyncRead+AsyncSeek+Unpin>(&mut self, mut transaction_stream: T) -> Result<(), tokio::io::Error> {
+        // transactions are decoded from a buffer that is topped up until the next one fits,
+        // but the file bytes of inline media are seeked over instead of buffered
+        let mut buffer = Vec::new();
+        let mut buffer_offset = 0;
+        let mut end_of_stream = false;
+        loop {
+            if buffer.len() >= 8 {
+                let transaction_type = u64::from_be_bytes(buffer[..8].try_into().unwrap());
+                let mut remaining = &buffer[8..];
+                match Transaction::read(transaction_type, &mut remaining) {
+                    Ok(Some(transaction)) => {
+                        let transaction_length = buffer.len()-remaining.len();
+                        buffer.drain(..transaction_length);
+                        buffer_offset += transaction_length as u64;
+
+                        if let Transaction::AddInlineMedia { metadata, file_length } = transaction {
+                            self.add_media(CachedMedia::from_metadata(metadata, FileReference::Inline(SizedReference {
+                                offset: buffer_offset,
+                                size: file_length,
+                            })));
+
+                            let buffered_file_length = file_length.min(buffer.len() as u64);
+                            buffer.drain(..buffered_file_length as usize);
+                            if buffered_file_length < file_length {
+                                transaction_stream.seek(SeekFrom::Start(buffer_offset+file_length)).await?;
+                            }
+                            buffer_offset += file_length;
+                        } else {
+                            self.apply_transaction(transaction).map_err(|reason| tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, reason))?;
+                        }
+                        continue;
+                    },
+                    Ok(None) => return Err(tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("unknown transaction type {}", transaction_type))),
+                    Err(DecodeError { kind: DecodeErrorKind::UnexpectedEnd, .. }) if !end_of_stream => {},
+                    Err(err) => return Err(err.into()),
+                }
+            } else if end_of_stream {
+                return Ok(());
+            }
+
+            let mut chunk = vec![0u8; 64*1024];
+            let read = transaction_stream.read(&mut chunk).await?;
+            if read == 0 {
+                end_of_stream = true;
+            }
+            buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// Applies one transaction to the cache, failing if it refers to tags or media that don't exist.
+    fn apply_transaction(&mut self, transaction: Transaction) -> Result<(), String> {
+        match transaction {
+            Transaction::AddTag { name } => {
+                self.add_tag(name);
+            },
+            Transaction::AddInlineMedia { .. } => {
+                return Err("inline media outside of a version 1 log".to_string())
+            },
+            Transaction::UpdateMedia { media_id, title, description, tags_vec, taken_datetime } => {
+                if !self.update_media(media_id, title, description, tags_vec, taken_datetime) {
+                    return Err(format!("update for unknown media {}", media_id))
+                }
+            },
+            Transaction::DeleteMedia { media_id, deleted_datetime } => {
+                if !self.delete_media(media_id, deleted_datetime) {
+                    return Err(format!("delete for unknown media {}", media_id))
+                }
+            },
+            Transaction::RestoreMedia { media_id } => {
+                if !self.restore_media(media_id) {
+                    return Err(format!("restore for unknown trashed media {}", media_id))
+                }
+            },
+            Transaction::RenameTag { tag_id, name } => {
+                if !self.rename_tag(tag_id, name) {
+                    return Err(format!("rename for unknown tag {}", tag_id))
+                }
+            },
+            Transaction::MergeTag { tag_id, into_tag_id } => {
+                if !self.merge_tag(tag_id, into_tag_id) {
+                    return Err(format!("invalid merge of tag {} into tag {}", tag_id, into_tag_id))
+                }
+            },
+            Transaction::DeleteTag { tag_id } => {
+                if !self.delete_tag(tag_id) {
+                    return Err(format!("delete for unknown tag {}", tag_id))
+                }
+            },
+            Transaction::SkipIds { tag_ids, media_ids } => {
+                self.next_tag_id += tag_ids;
+                self.next_media_id += media_ids;
+            },
+            Transaction::AddBlobMedia { metadata, hash, size } => {
+                self.add_media(CachedMedia::from_metadata(metadata, FileReference::Blob(BlobReference {
+                    hash,
+                    size,
+                })));
+            },
+            Transaction::AddMediaVariant { media_id, variant_size, hash, size } => {
+                if !self.add_media_variant(media_id, variant_size, BlobReference { hash, size }) {
+                    return Err(format!("variant for unknown media {}", media_id))
+                }
+            },
+            Transaction::SetMediaExif { media_id, exif } => {
+                if !self.set_media_exif(media_id, exif) {
+                    return Err(format!("EXIF metadata for unknown media {}", media_id))
+                }
+            },
+            Transaction::SetVideoInfo { media_id, video_info } => {
+                if !self.set_video_info(media_id, video_info) {
+                    return Err(format!("video info for unknown media {}", media_id))
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// How many records of the framed log have been replayed into the cache.
+    pub fn replayed_records(&self) -> u64 {
+        self.replayed_records
+    }
+
+    /// How many transactions were skipped because their type is unknown to this binary.
+    pub fn skipped_transactions(&self) -> u64 {
+        self.skipped_transactions
+    }
+
+    pub fn add_tag(&mut self, name: String) -> u64 {
+        let tag_id = self.next_tag_id;
+
+        self.tags.insert(tag_id, name);
+
+        self.next_tag_id += 1;
+
+        tag_id
+    }
+
+    pub fn get_tags(&self) -> &HashMap<u64, String> {
+        &self.tags
+    }
+
+    /// Finds a tag by name, ignoring case so "beach" and "Beach" are the same tag.
+    pub fn find_tag(&self, name: &str) -> Option<u64> {
+        self.tags.iter()
+            .find(|(_, tag_name)| tag_name.to_lowercase() == name.to_lowercase())
+            .map(|(tag_id, _)| *tag_id)
+    }
+
+    /// Returns false if there is no tag with that id.
+    pub fn rename_tag(&mut self, tag_id: u64, name: String) -> bool {
+        match self.tags.get_mut(&tag_id) {
+            Some(tag_name) => {
+                *tag_name = name;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Replaces every reference to `tag_id` with `into_tag_id` and removes `tag_id`.
+    /// Returns false if either tag does not exist or they are the same tag.
+    pub fn merge_tag(&mut self, tag_id: u64, into_tag_id: u64) -> bool {
+        if tag_id == into_tag_id || !self.tags.contains_key(&into_tag_id) || self.tags.remove(&tag_id).is_none() {
+            return false;
+        }
+        let indexed_media_ids: Vec<u64> = self.indexes.by_tag.get(&tag_id).into_iter().flatten().map(|key| key.1).collect();
+        for media_id in &indexed_media_ids {
+            self.indexes.remove(*media_id, &self.media[media_id]);
+        }
+
+        let trashed_media = self.trash.values_mut().map(|trashed_media| &mut trashed_media.media);
+        for cached_media in self.media.values_mut().chain(trashed_media) {
+            if cached_media.tags_vec.contains(&tag_id) {
+                if cached_media.tags_vec.contains(&into_tag_id) {
+                    cached_media.tags_vec.retain(|media_tag_id| *media_tag_id != tag_id);
+                } else {
+                    for media_tag_id in cached_media.tags_vec.iter_mut() {
+                        if *media_tag_id == tag_id {
+                            *media_tag_id = into_tag_id;
+                        }
+                    }
+                }
+            }
+        }
+        for media_id in &indexed_media_ids {
+            self.indexes.insert(*media_id, &self.media[media_id]);
+        }
+
+        true
+    }
+
+    /// Removes a tag and every reference to it, returning false if there is no tag with that id.
+    pub fn delete_tag(&mut self, tag_id: u64) -> bool {
+        if self.tags.remove(&tag_id).is_none() {
+            return false;
+        }
+        let indexed_media_ids: Vec<u64> = self.indexes.by_tag.get(&tag_id).into_iter().flatten().map(|key| key.1).collect();
+        for media_id in &indexed_media_ids {
+            self.indexes.remove(*media_id, &self.media[media_id]);
+        }
+
+        let trashed_media = self.trash.values_mut().map(|trashed_media| &mut trashed_media.media);
+        for cached_media in self.media.values_mut().chain(trashed_media) {
+            cached_media.tags_vec.retain(|media_tag_id| *media_tag_id != tag_id);
+        }
+        for media_id in &indexed_media_ids {
+            self.indexes.insert(*media_id, &self.media[media_id]);
+        }
+
+        true
+    }
+
+    pub fn add_media(&mut self, cached_media: CachedMedia) -> u64 {
+        let media_id = self.next_media_id;
+
+        self.indexes.insert(media_id, &cached_media);
+        self.media.insert(media_id, cached_media);
+
+        self.next_media_id += 1;
+
+        media_id
+    }
+
+    /// Replaces the metadata of existing media, returning false if there is no media with that id.
+    pub fn update_media(&mut self, media_id: u64, title: String, description: String, tags_vec: Vec<u64>, taken_datetime: f64) -> bool {
+        match self.media.get_mut(&media_id) {
+            Some(cached_media) => {
+                self.indexes.remove(media_id, cached_media);
+                cached_media.title = title;
+                cached_media.description = description;
+                cached_media.tags_vec = tags_vec;
+                cached_media.taken_datetime = taken_datetime;
+                self.indexes.insert(media_id, cached_media);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Finds media whether it's in the trash or not.
+    fn media_or_trashed_mut(&mut self, media_id: u64) -> Option<&mut CachedMedia> {
+        match (self.media.get_mut(&media_id), self.trash.get_mut(&media_id)) {
+            (Some(cached_media), _) => Some(cached_media),
+            (None, Some(trashed_media)) => Some(&mut trashed_media.media),
+            (None, None) => None,
+        }
+    }
+
+    /// Sets the variant of media in or out of the trash, returning false if there is no media with that id.
+    pub fn add_media_variant(&mut self, media_id: u64, variant_size: VariantSize, blob_reference: BlobReference) -> bool {
+        match self.media_or_trashed_mut(media_id) {
+            Some(cached_media) => {
+                cached_media.variants.insert(variant_size, blob_reference);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Sets the EXIF metadata of media in or out of the trash, returning false if there is no media with that id.
+    pub fn set_media_exif(&mut self, media_id: u64, exif: ExifMetadata) -> bool {
+        match self.media_or_trashed_mut(media_id) {
+            Some(cached_media) => {
+                cached_media.exif = Some(exif);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Sets the container metadata of a video in or out of the trash, returning false if there is no media with that id.
+    pub fn set_video_info(&mut self, media_id: u64, video_info: VideoInfo) -> bool {
+        match self.media_or_trashed_mut(media_id) {
+            Some(cached_media) => {
+                cached_media.video_info = Some(video_info);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn get_media(&self) -> &HashMap<u64, CachedMedia> {
+        &self.media
+    }
+
+    /// Finds live media matching every filter of `query`, in the requested order of `taken_datetime`.
+    pub fn query_media(&self, query: &MediaQuery) -> Vec<(u64, &CachedMedia)> {
+        // start from the smallest index that every result has to be in
+        let candidates = if query.tags.is_empty() {
+            &self.indexes.by_datetime
+        } else {
+            match query.tags.iter().map(|tag_id| self.indexes.by_tag.get(tag_id)).collect::<Option<Vec<_>>>() {
+                Some(tag_indexes) => tag_indexes.into_iter().min_by_key(|tag_index| tag_index.len()).unwrap(),
+                None => return Vec::new(),
+            }
+        };
+
+        let start = DatetimeKey(query.from.unwrap_or(f64::NEG_INFINITY), 0);
+        let end = DatetimeKey(query.to.unwrap_or(f64::INFINITY), u64::MAX);
+        if start > end {
+            return Vec::new();
+        }
+        let range = candidates.range(start..=end);
+        let keys: Box<dyn Iterator<Item = &DatetimeKey>> = match query.order {
+            SortOrder::Newest => Box::new(range.rev()),
+            SortOrder::Oldest => Box::new(range),
+        };
+
+        keys
+            .map(|key| (key.1, &self.media[&key.1]))
+            .filter(|(_, cached_media)| {
+                query.tags.iter().all(|tag_id| cached_media.tags_vec.contains(tag_id))
+                    && !query.exclude_tags.iter().any(|tag_id| cached_media.tags_vec.contains(tag_id))
+                    && query.media_type.is_none_or(|media_type| cached_media.media_type == media_type)
+            })
+            .collect()
+    }
+
+    /// Finds live media whose title or description contains any word of `query`, best match first.
+    /// Each matching word scores its weight in the media times how rare the word is across all media.
+    pub fn search_media(&self, query: &str) -> Vec<u64> {
+        let mut scores: HashMap<u64, f64> = HashMap::new();
+        let query_words: HashSet<String> = words(query).collect();
+        for word in &query_words {
+            if let Some(word_index) = self.indexes.by_word.get(word) {
+                let rarity = (self.media.len() as f64/word_index.len() as f64).ln()+1.0;
+                for (media_id, weight) in word_index {
+                    *scores.entry(*media_id).or_default() += *weight as f64*rarity;
+                }
+            }
+        }
+
+        let mut ranked: Vec<(u64, f64)> = scores.into_iter().collect();
+        // ties go to the most recently taken media
+        ranked.sort_by(|(a_id, a_score), (b_id, b_score)| {
+            b_score.total_cmp(a_score).then_with(|| self.media[b_id].taken_datetime.total_cmp(&self.media[a_id].taken_datetime))
+        });
+        ranked.into_iter().map(|(media_id, _)| media_id).collect()
+    }
+
+    /// Moves media into the trash, returning false if there is no media with that id.
+    pub fn delete_media(&mut self, media_id: u64, deleted_datetime: f64) -> bool {
+        match self.media.remove(&media_id) {
+            Some(media) => {
+                self.indexes.remove(media_id, &media);
+                self.trash.insert(media_id, TrashedMedia {
+                    media,
+                    deleted_datetime,
+                });
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Moves media out of the trash, returning false if there is no trashed media with that id.
+    pub fn restore_media(&mut self, media_id: u64) -> bool {
+        match self.trash.remove(&media_id) {
+            Some(trashed_media) => {
+                self.indexes.insert(media_id, &trashed_media.media);
+                self.media.insert(media_id, trashed_media.media);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn get_trash(&self) -> &HashMap<u64, TrashedMedia> {
+        &self.trash
+    }
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use iloveu_lib::transaction::{MediaType, frame_record};
+    use crate::migrations::{Migration, MIGRATIONS, LATEST_VERSION};
+    use crate::verify::verify_store;
+    use crate::import::{import_directory, import_archive};
+    use crate::export::{plan_export, write_media_zip};
+    use crate::variants::{resize_picture, generate_media_variants};
+    use crate::types::{VariantSize, MediaFileSize};
+
+    /// A store in a fresh directory that is removed again when the test is done with it.
+    pub(crate) struct TestStore {
+        path: PathBuf,
+    }
+
+    impl TestStore {
+        pub(crate) fn new() -> Self {
+            TestStore {
+                path: std::env::temp_dir().join(format!("iloveu-test-{:016x}", rand::thread_rng().gen::<u64>())),
+            }
+        }
+
+        pub(crate) async fn open(&self, replay_mode: ReplayMode) -> Result<IloveuTransactionsStore, tokio::io::Error> {
+            IloveuTransactionsStore::open_with_options(&self.path, StoreOptions { replay_mode, ..StoreOptions::default() }).await
+        }
+
+        async fn open_encrypted(&self) -> Result<IloveuTransactionsStore, tokio::io::Error> {
+            IloveuTransactionsStore::open_with_options(&self.path, StoreOptions { passphrase: Some("passphrase".to_string()), ..StoreOptions::default() }).await
+        }
+
+        fn transactions(&self) -> Vec<u8> {
+            std::fs::read(self.path.join("transactions")).unwrap()
+        }
+
+        fn set_transactions(&self, transactions: &[u8]) {
+            std::fs::write(self.path.join("transactions"), transactions).unwrap();
+        }
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    async fn replay(store: &IloveuTransactionsStore) -> Result<IloveuCache, tokio::io::Error> {
+        let mut cache = IloveuCache::new();
+        cache.run_raw_transactions(store.get_transactions_raw().await?, store.key(), ReplayMode::Strict).await?;
+        Ok(cache)
+    }
+
+    fn metadata(title: &str, description: &str, tags_vec: &[u64], taken_datetime: f64, media_type: MediaType, filename: &str) -> MediaMetadata {
+        MediaMetadata {
+            title: title.to_string(),
+            description: description.to_string(),
+            tags_vec: tags_vec.to_vec(),
+            taken_datetime,
+            media_type,
+            filename: filename.to_string(),
+        }
+    }
+
+    fn media(title: &str, description: &str, tags_vec: &[u64], taken_datetime: f64, media_type: MediaType) -> CachedMedia {
+        CachedMedia::from_metadata(metadata(title, description, tags_vec, taken_datetime, media_type, &format!("{}.jpg", title)), FileReference::Blob(BlobReference {
+            hash: [0; 32],
+            size: 0,
+        }))
+    }
+
+    #[tokio::test]
+    async fn media_updates_are_replayed() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        store.add_tag("beach").await.unwrap();
+        store.add_tag("family").await.unwrap();
+        let file_reference = store.add_media(&metadata("first", "", &[0], 1.0, MediaType::Picture, "first.jpg"), upload(&store, b"first bytes").await).await.unwrap();
+        store.add_media(&metadata("second", "", &[], 2.0, MediaType::Video, "second.mp4"), upload(&store, b"second bytes").await).await.unwrap();
+        store.update_media(0, "renamed", "at the beach", &[0, 1], 10.0).await.unwrap();
+
+        let cache = replay(&store).await.unwrap();
+        let updated = &cache.get_media()[&0];
+        assert_eq!((updated.title.as_str(), updated.description.as_str()), ("renamed", "at the beach"));
+        assert_eq!(updated.tags_vec, [0, 1]);
+        assert_eq!(updated.taken_datetime, 10.0);
+        assert_eq!(updated.filename, "first.jpg");
+        assert!(matches!(updated.file_reference, FileReference::Blob(blob_reference) if blob_reference == file_reference));
+        assert_eq!(cache.get_media()[&1].title, "second");
+    }
+
+    #[tokio::test]
+    async fn updates_of_unknown_media_are_refused() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        store.add_media(&metadata("only", "", &[], 1.0, MediaType::Picture, "only.jpg"), upload(&store, b"bytes").await).await.unwrap();
+        let mut cache = replay(&store).await.unwrap();
+        assert!(!cache.update_media(1, "missing".to_string(), String::new(), vec![], 0.0));
+        assert!(cache.update_media(0, "found".to_string(), String::new(), vec![], 0.0));
+
+        store.update_media(1, "missing", "", &[], 0.0).await.unwrap();
+        assert_eq!(replay(&store).await.unwrap_err().kind(), tokio::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn deleted_media_moves_to_the_trash_and_back() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        store.add_media(&metadata("kept", "", &[], 1.0, MediaType::Picture, "kept.jpg"), upload(&store, b"kept").await).await.unwrap();
+        store.add_media(&metadata("trashed", "", &[], 2.0, MediaType::Picture, "trashed.jpg"), upload(&store, b"trashed").await).await.unwrap();
+        store.add_media(&metadata("restored", "", &[], 3.0, MediaType::Picture, "restored.jpg"), upload(&store, b"restored").await).await.unwrap();
+        store.delete_media(1, 100.0).await.unwrap();
+        store.delete_media(2, 200.0).await.unwrap();
+        store.restore_media(2).await.unwrap();
+
+        let cache = replay(&store).await.unwrap();
+        let mut media_ids: Vec<_> = cache.get_media().keys().copied().collect();
+        media_ids.sort_unstable();
+        assert_eq!(media_ids, [0, 2]);
+        assert_eq!(cache.get_trash().len(), 1);
+        assert_eq!(cache.get_trash()[&1].deleted_datetime, 100.0);
+        assert_eq!(cache.get_trash()[&1].media.title, "trashed");
+        assert_eq!(cache.get_media()[&2].title, "restored");
+    }
+
+    #[tokio::test]
+    async fn deleting_or_restoring_the_wrong_media_is_refused() {
+        let mut cache = IloveuCache::new();
+        let media_id = cache.add_media(media("only", "", &[], 0.0, MediaType::Picture));
+        assert!(!cache.restore_media(media_id));
+        assert!(cache.delete_media(media_id, 1.0));
+        assert!(!cache.delete_media(media_id, 2.0));
+        assert!(!cache.update_media(media_id, "trashed".to_string(), String::new(), vec![], 0.0));
+        assert!(cache.restore_media(media_id));
+        assert!(!cache.restore_media(media_id));
+
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        store.add_media(&metadata("only", "", &[], 1.0, MediaType::Picture, "only.jpg"), upload(&store, b"bytes").await).await.unwrap();
+        store.restore_media(0).await.unwrap();
+        assert!(replay(&store).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tag_changes_are_replayed_into_media() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        for name in ["beach", "sea", "holiday", "unused"] {
+            store.add_tag(name).await.unwrap();
+        }
+        store.add_media(&metadata("both", "", &[0, 1], 1.0, MediaType::Picture, "both.jpg"), upload(&store, b"both").await).await.unwrap();
+        store.add_media(&metadata("beach", "", &[0, 2], 2.0, MediaType::Picture, "beach.jpg"), upload(&store, b"beach").await).await.unwrap();
+        store.add_media(&metadata("trashed", "", &[2], 3.0, MediaType::Picture, "trashed.jpg"), upload(&store, b"trashed").await).await.unwrap();
+        store.delete_media(2, 10.0).await.unwrap();
+        store.rename_tag(1, "Sea").await.unwrap();
+        store.merge_tag(0, 1).await.unwrap();
+        store.delete_tag(2).await.unwrap();
+
+        let cache = replay(&store).await.unwrap();
+        let mut tags: Vec<_> = cache.get_tags().iter().map(|(tag_id, name)| (*tag_id, name.as_str())).collect();
+        tags.sort_unstable();
+        assert_eq!(tags, [(1, "Sea"), (3, "unused")]);
+        assert_eq!(cache.get_media()[&0].tags_vec, [1]);
+        assert_eq!(cache.get_media()[&1].tags_vec, [1]);
+        assert!(cache.get_trash()[&2].media.tags_vec.is_empty());
+        assert_eq!(cache.find_tag("sea"), Some(1));
+        assert_eq!(cache.find_tag("beach"), None);
+    }
+
+    #[tokio::test]
+    async fn impossible_tag_changes_are_refused() {
+        let mut cache = IloveuCache::new();
+        let beach = cache.add_tag("beach".to_string());
+        let sea = cache.add_tag("sea".to_string());
+        assert!(!cache.merge_tag(beach, beach));
+        assert!(!cache.merge_tag(beach, 7));
+        assert!(!cache.merge_tag(7, beach));
+        assert!(!cache.rename_tag(7, "seven".to_string()));
+        assert!(!cache.delete_tag(7));
+        assert_eq!(cache.get_tags().len(), 2);
+        assert!(cache.merge_tag(beach, sea));
+        assert!(!cache.delete_tag(beach));
+
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        store.add_tag("beach").await.unwrap();
+        store.merge_tag(0, 0).await.unwrap();
+        assert!(replay(&store).await.is_err());
+    }
+
+    async fn upload(store: &IloveuTransactionsStore, file_bytes: &[u8]) -> Upload {
+        store.uploads().write(file_bytes).await.unwrap()
+    }
+
+    async fn file_bytes(store: &IloveuTransactionsStore, file_reference: &FileReference) -> Vec<u8> {
+        let mut file_bytes = Vec::new();
+        store.open_file(file_reference).await.unwrap().take(file_reference.size()).read_to_end(&mut file_bytes).await.unwrap();
+        file_bytes
+    }
+
+    #[tokio::test]
+    async fn compaction_keeps_ids_and_moves_file_offsets() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        for name in ["deleted", "merged", "kept"] {
+            store.add_tag(name).await.unwrap();
+        }
+        for (title, bytes) in [("old", "dropped for good"), ("recent", "still restorable"), ("live", "live bytes"), ("updated", "updated bytes")] {
+            store.add_media(&metadata(title, "", &[1, 2], 1.0, MediaType::Picture, "file.jpg"), upload(&store, bytes.as_bytes()).await).await.unwrap();
+        }
+        store.update_media(3, "updated twice", "", &[1], 2.0).await.unwrap();
+        store.update_media(3, "updated", "once more", &[2], 3.0).await.unwrap();
+        store.delete_media(0, 100.0).await.unwrap();
+        store.delete_media(1, 300.0).await.unwrap();
+        store.delete_tag(0).await.unwrap();
+        store.merge_tag(1, 2).await.unwrap();
+
+        let cache = replay(&store).await.unwrap();
+        let report = store.compact(&cache, 200.0).await.unwrap();
+        assert_eq!(report.dropped_media, 1);
+        assert!(report.bytes_after < report.bytes_before);
+        assert_eq!(report.bytes_after, std::fs::metadata(test_store.path.join("transactions")).unwrap().len());
+        assert!(!test_store.path.join("transactions.compacting").exists());
+
+        let compacted = replay(&store).await.unwrap();
+        assert_eq!(compacted.get_tags().len(), 1);
+        assert_eq!(compacted.get_tags()[&2], "kept");
+        assert!(compacted.get_trash().get(&0).is_none());
+        assert_eq!(compacted.get_trash()[&1].deleted_datetime, 300.0);
+        assert_eq!(file_bytes(&store, &compacted.get_trash()[&1].media.file_reference).await, b"still restorable");
+        assert_eq!(file_bytes(&store, &compacted.get_media()[&2].file_reference).await, b"live bytes");
+        let updated = &compacted.get_media()[&3];
+        assert_eq!((updated.title.as_str(), updated.description.as_str(), updated.taken_datetime), ("updated", "once more", 3.0));
+        assert_eq!(updated.tags_vec, [2]);
+        assert_eq!(file_bytes(&store, &updated.file_reference).await, b"updated bytes");
+
+        // ids of dropped and deleted tags and media are never handed out again
+        store.add_tag("new").await.unwrap();
+        store.add_media(&metadata("new", "", &[], 4.0, MediaType::Picture, "new.jpg"), upload(&store, b"new").await).await.unwrap();
+        let cache = replay(&store).await.unwrap();
+        assert_eq!(cache.get_tags()[&3], "new");
+        assert_eq!(cache.get_media()[&4].title, "new");
+    }
+
+    #[tokio::test]
+    async fn compacting_twice_changes_nothing() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        store.add_tag("beach").await.unwrap();
+        store.add_media(&metadata("kept", "", &[0], 1.0, MediaType::Picture, "kept.jpg"), upload(&store, b"kept").await).await.unwrap();
+        store.add_media(&metadata("trashed", "", &[0], 1.0, MediaType::Video, "trashed.mp4"), upload(&store, b"trashed").await).await.unwrap();
+        store.delete_media(1, 100.0).await.unwrap();
+
+        store.compact(&replay(&store).await.unwrap(), f64::NEG_INFINITY).await.unwrap();
+        let compacted = std::fs::read(test_store.path.join("transactions")).unwrap();
+        let report = store.compact(&replay(&store).await.unwrap(), f64::NEG_INFINITY).await.unwrap();
+        assert_eq!(report.dropped_media, 0);
+        assert_eq!(std::fs::read(test_store.path.join("transactions")).unwrap(), compacted);
+    }
+
+    fn blob_names(test_store: &TestStore) -> Vec<String> {
+        let mut blob_names: Vec<_> = std::fs::read_dir(test_store.path.join("blobs")).unwrap()
+            .map(|blob| blob.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        blob_names.sort_unstable();
+        blob_names
+    }
+
+    #[tokio::test]
+    async fn identical_files_share_a_blob() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let first = store.add_media(&metadata("first", "", &[], 1.0, MediaType::Picture, "first.jpg"), upload(&store, b"same bytes").await).await.unwrap();
+        let copy = store.add_media(&metadata("copy", "", &[], 2.0, MediaType::Picture, "copy.jpg"), upload(&store, b"same bytes").await).await.unwrap();
+        let other = store.add_media(&metadata("other", "", &[], 3.0, MediaType::Picture, "other.jpg"), upload(&store, b"other bytes").await).await.unwrap();
+        assert_eq!(first, copy);
+        assert_ne!(first.hash, other.hash);
+        assert_eq!(first.size, 10);
+        assert_eq!(hash_to_hex(&first.hash), "58100dc8fc06562ce3e578231dc948e083520ee49c4b4ee5a5a28bb4b4003feb");
+        assert_eq!(blob_names(&test_store).len(), 2);
+
+        let cache = replay(&store).await.unwrap();
+        assert_eq!(file_bytes(&store, &cache.get_media()[&1].file_reference).await, b"same bytes");
+    }
+
+    #[tokio::test]
+    async fn compaction_drops_blobs_nothing_refers_to() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let shared = store.add_media(&metadata("shared", "", &[], 1.0, MediaType::Picture, "shared.jpg"), upload(&store, b"shared").await).await.unwrap();
+        store.add_media(&metadata("shared copy", "", &[], 1.0, MediaType::Picture, "copy.jpg"), upload(&store, b"shared").await).await.unwrap();
+        let gone = store.add_media(&metadata("gone", "", &[], 1.0, MediaType::Picture, "gone.jpg"), upload(&store, b"gone").await).await.unwrap();
+        store.delete_media(0, 100.0).await.unwrap();
+        store.delete_media(2, 100.0).await.unwrap();
+        assert_eq!(blob_names(&test_store).len(), 2);
+
+        let report = store.compact(&replay(&store).await.unwrap(), 200.0).await.unwrap();
+        assert_eq!((report.dropped_media, report.dropped_blobs), (2, 1));
+        assert_eq!(blob_names(&test_store), [hash_to_hex(&shared.hash)]);
+        assert!(store.open_file(&FileReference::Blob(gone)).await.is_err());
+        assert_eq!(file_bytes(&store, &replay(&store).await.unwrap().get_media()[&1].file_reference).await, b"shared");
+    }
+
+    /// A version 1 transaction adding media with its file bytes inline.
+    fn inline_media_transaction(title: &str, tags_vec: &[u64], file_bytes: &[u8]) -> Vec<u8> {
+        let mut transaction = 1u64.to_be_bytes().to_vec();
+        for string in [title, ""] {
+            transaction.extend_from_slice(&(string.len() as u64).to_be_bytes());
+            transaction.extend_from_slice(string.as_bytes());
+        }
+        transaction.extend_from_slice(&(tags_vec.len() as u64).to_be_bytes());
+        for tag_id in tags_vec {
+            transaction.extend_from_slice(&tag_id.to_be_bytes());
+        }
+        transaction.extend_from_slice(&5.0f64.to_be_bytes());
+        transaction.extend_from_slice(&0u64.to_be_bytes());
+        transaction.extend_from_slice(&5u64.to_be_bytes());
+        transaction.extend_from_slice(b"a.jpg");
+        transaction.extend_from_slice(&(file_bytes.len() as u64).to_be_bytes());
+        transaction.extend_from_slice(file_bytes);
+        transaction
+    }
+
+    /// A version 1 store with one tag and two media stored inline.
+    fn version_1_store() -> TestStore {
+        let test_store = TestStore::new();
+        std::fs::create_dir(&test_store.path).unwrap();
+        std::fs::write(test_store.path.join("version"), 1u64.to_be_bytes()).unwrap();
+        let mut transactions = 0u64.to_be_bytes().to_vec();
+        transactions.extend_from_slice(&4u64.to_be_bytes());
+        transactions.extend_from_slice(b"lake");
+        transactions.extend(inline_media_transaction("first", &[0], b"first inline bytes"));
+        transactions.extend(inline_media_transaction("second", &[], b"second inline bytes"));
+        std::fs::write(test_store.path.join("transactions"), &transactions).unwrap();
+        test_store
+    }
+
+    /// The sibling directories `backup_store` made of `test_store`.
+    fn backups(test_store: &TestStore) -> Vec<PathBuf> {
+        let prefix = format!("{}.backup-", test_store.path.file_name().unwrap().to_str().unwrap());
+        let mut backups: Vec<_> = std::fs::read_dir(test_store.path.parent().unwrap()).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap().to_str().unwrap().starts_with(&prefix))
+            .collect();
+        backups.sort();
+        backups
+    }
+
+    #[tokio::test]
+    async fn inline_files_of_version_1_stores_move_into_blobs() {
+        let test_store = version_1_store();
+
+        let store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let cache = replay(&store).await.unwrap();
+        assert_eq!(cache.get_tags()[&0], "lake");
+        let first = &cache.get_media()[&0];
+        assert_eq!((first.title.as_str(), first.tags_vec.as_slice(), first.taken_datetime), ("first", &[0][..], 5.0));
+        assert!(matches!(first.file_reference, FileReference::Blob(_)));
+        assert_eq!(file_bytes(&store, &first.file_reference).await, b"first inline bytes");
+        assert_eq!(file_bytes(&store, &cache.get_media()[&1].file_reference).await, b"second inline bytes");
+        assert_eq!(blob_names(&test_store).len(), 2);
+    }
+
+    /// A store holding `names` as tags, returning the offset each of their records starts at.
+    async fn store_with_tags(names: &[&str]) -> (TestStore, Vec<u64>) {
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let mut offsets = Vec::new();
+        for name in names {
+            offsets.push(test_store.transactions().len() as u64);
+            store.add_tag(name).await.unwrap();
+        }
+        (test_store, offsets)
+    }
+
+    fn set_record_length(transactions: &mut [u8], offset: u64, length: u64) {
+        let length_offset = offset as usize+16;
+        transactions[length_offset..length_offset+8].copy_from_slice(&length.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn torn_tail_is_truncated_and_kept_aside() {
+        let (test_store, _) = store_with_tags(&["a", "b"]).await;
+        let whole = test_store.transactions();
+        let torn_record = frame_record(0, b"\0\0\0\0\0\0\0\x05ab");
+        let mut torn = whole.clone();
+        torn.extend_from_slice(&torn_record[..torn_record.len()-5]);
+        test_store.set_transactions(&torn);
+
+        let store = test_store.open(ReplayMode::Strict).await.unwrap();
+        assert_eq!(test_store.transactions(), whole);
+        assert_eq!(std::fs::read(test_store.path.join(format!("transactions.torn-{}", whole.len()))).unwrap(), &torn[whole.len()..]);
+        assert_eq!(replay(&store).await.unwrap().get_tags().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn torn_header_is_truncated() {
+        let (test_store, _) = store_with_tags(&["a"]).await;
+        let whole = test_store.transactions();
+        let mut torn = whole.clone();
+        torn.extend_from_slice(&[0, 0, 0]);
+        test_store.set_transactions(&torn);
+
+        test_store.open(ReplayMode::Strict).await.unwrap();
+        assert_eq!(test_store.transactions(), whole);
+    }
+
+    #[tokio::test]
+    async fn corrupt_length_in_the_middle_is_not_truncated() {
+        let (test_store, offsets) = store_with_tags(&["a", "b", "c", "d", "e"]).await;
+        let whole = test_store.transactions();
+        // still a plausible length, but it reaches past the end of the log over the records after it
+        let mut corrupt = whole.clone();
+        set_record_length(&mut corrupt, offsets[1], whole.len() as u64);
+        test_store.set_transactions(&corrupt);
+
+        assert!(test_store.open(ReplayMode::Strict).await.is_err());
+        assert_eq!(test_store.transactions(), corrupt);
+        assert!(!test_store.path.join(format!("transactions.torn-{}", offsets[1])).exists());
+
+        test_store.open(ReplayMode::Lenient).await.unwrap();
+        assert_eq!(test_store.transactions(), &whole[..offsets[1] as usize]);
+        assert_eq!(std::fs::read(test_store.path.join(format!("transactions.torn-{}", offsets[1]))).unwrap(), &corrupt[offsets[1] as usize..]);
+    }
+
+    #[tokio::test]
+    async fn unknown_transactions_are_never_snapshotted() {
+        let (test_store, _) = store_with_tags(&["a"]).await;
+        // a record from a newer version, followed by one this version knows
+        let mut transactions = test_store.transactions();
+        transactions.extend_from_slice(&frame_record_with_flags(1000, 0, b"from the future"));
+        test_store.set_transactions(&transactions);
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        store.add_tag("b").await.unwrap();
+
+        let cache = store.load_cache().await.unwrap();
+        assert_eq!(cache.skipped_transactions(), 1);
+        assert_eq!(cache.get_tags().len(), 2);
+        assert!(!test_store.path.join("snapshot").exists());
+        assert!(!store.update_snapshot().await.unwrap());
+        assert!(!test_store.path.join("snapshot").exists());
+        assert!(store.compact(&cache, f64::NEG_INFINITY).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dropped_uploads_delete_their_file() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let uploads_in_progress = || std::fs::read_dir(test_store.path.join("uploads")).unwrap().count();
+
+        let upload = store.uploads().write(&b"never added"[..]).await.unwrap();
+        assert_eq!(uploads_in_progress(), 1);
+        drop(upload);
+        assert_eq!(uploads_in_progress(), 0);
+
+        let upload = store.uploads().write(&b"added"[..]).await.unwrap();
+        let metadata = MediaMetadata {
+            title: "added".to_string(),
+            description: String::new(),
+            tags_vec: Vec::new(),
+            taken_datetime: 0.0,
+            media_type: crate::types::MediaType::Picture,
+            filename: "added.jpg".to_string(),
+        };
+        let blob_reference = store.add_media(&metadata, upload).await.unwrap();
+        assert_eq!(uploads_in_progress(), 0);
+        assert!(store.blob_path(&blob_reference.hash).exists());
+    }
+
+    #[tokio::test]
+    async fn impossible_length_is_not_truncated() {
+        let (test_store, offsets) = store_with_tags(&["a", "b", "c"]).await;
+        let mut corrupt = test_store.transactions();
+        set_record_length(&mut corrupt, offsets[0], MAX_RECORD_LENGTH+1);
+        test_store.set_transactions(&corrupt);
+
+        assert!(test_store.open(ReplayMode::Strict).await.is_err());
+        assert_eq!(test_store.transactions(), corrupt);
+    }
+
+    #[tokio::test]
+    async fn unknown_transaction_types_are_skipped_and_kept_by_compaction() {
+        let (test_store, _) = store_with_tags(&["a"]).await;
+        let mut transactions = test_store.transactions();
+        transactions.extend(frame_record(1000, b"from the future"));
+        transactions.extend(Transaction::AddTag { name: "b".to_string() }.encode_record());
+        test_store.set_transactions(&transactions);
+
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let cache = replay(&store).await.unwrap();
+        assert_eq!(cache.skipped_transactions, 1);
+        let mut names: Vec<_> = cache.get_tags().values().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, ["a", "b"]);
+
+        assert_eq!(store.compact(&cache, 0.0).await.unwrap_err().kind(), tokio::io::ErrorKind::Unsupported);
+        assert_eq!(test_store.transactions(), transactions);
+    }
+
+    #[tokio::test]
+    async fn new_stores_are_created_at_the_latest_version() {
+        let test_store = TestStore::new();
+        test_store.open(ReplayMode::Strict).await.unwrap();
+
+        assert_eq!(read_version(&test_store.path).await.unwrap(), LATEST_VERSION);
+        assert!(test_store.transactions().is_empty());
+        assert!(test_store.path.join("blobs").is_dir());
+        assert!(backups(&test_store).is_empty());
+    }
+
+    #[tokio::test]
+    async fn each_migration_step_keeps_every_transaction() {
+        for (version, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.from_version(), version as u64);
+        }
+        let test_store = version_1_store();
+        let mut store = IloveuTransactionsStore { path: test_store.path.clone(), replay_mode: ReplayMode::Strict, key: None, compress: false };
+        store.clear_uploads().await.unwrap();
+
+        Migration::MoveInlineFilesToBlobs.run(&mut store).await.unwrap();
+        let mut unframed = IloveuCache::new();
+        unframed.run_legacy_transactions(store.get_transactions_raw().await.unwrap()).await.unwrap();
+        assert_eq!(unframed.get_tags()[&0], "lake");
+        assert!(unframed.get_media().values().all(|cached_media| matches!(cached_media.file_reference, FileReference::Blob(_))));
+        assert_eq!(file_bytes(&store, &unframed.get_media()[&0].file_reference).await, b"first inline bytes");
+        assert_eq!(blob_names(&test_store).len(), 2);
+
+        Migration::FrameTransactions.run(&mut store).await.unwrap();
+        let framed = replay(&store).await.unwrap();
+        assert_eq!(framed.get_tags(), unframed.get_tags());
+        assert_eq!(framed.get_media().len(), 2);
+        for (media_id, cached_media) in framed.get_media() {
+            assert_eq!(cached_media.title, unframed.get_media()[media_id].title);
+            assert_eq!(file_bytes(&store, &cached_media.file_reference).await, file_bytes(&store, &unframed.get_media()[media_id].file_reference).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn migrating_backs_up_the_store_first() {
+        let test_store = version_1_store();
+        let transactions = test_store.transactions();
+
+        test_store.open(ReplayMode::Strict).await.unwrap();
+        assert_eq!(read_version(&test_store.path).await.unwrap(), LATEST_VERSION);
+        let backups = backups(&test_store);
+        let [backup_path] = &backups[..] else { panic!("expected one backup, found {:?}", backups) };
+        assert!(backup_path.file_name().unwrap().to_str().unwrap().contains(".backup-v1-"));
+        assert_eq!(std::fs::read(backup_path.join("transactions")).unwrap(), transactions);
+        assert_eq!(read_version(backup_path).await.unwrap(), 1);
+        std::fs::remove_dir_all(backup_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stores_from_a_newer_version_are_refused() {
+        let test_store = TestStore::new();
+        test_store.open(ReplayMode::Strict).await.unwrap();
+        std::fs::write(test_store.path.join("version"), (LATEST_VERSION+1).to_be_bytes()).unwrap();
+
+        assert_eq!(test_store.open(ReplayMode::Strict).await.unwrap_err().kind(), tokio::io::ErrorKind::Unsupported);
+        assert_eq!(read_version(&test_store.path).await.unwrap(), LATEST_VERSION+1);
+        assert!(backups(&test_store).is_empty());
+    }
+
+    fn queried_ids(cache: &IloveuCache, query: &MediaQuery) -> Vec<u64> {
+        cache.query_media(query).into_iter().map(|(media_id, _)| media_id).collect()
+    }
+
+    /// Tags 0 and 1, then pictures taken at 10, 20 and 30 and a video taken at 20.
+    fn cache_to_query() -> IloveuCache {
+        use MediaType::{Picture, Video};
+        let mut cache = IloveuCache::new();
+        cache.add_tag("lake".to_string());
+        cache.add_tag("family".to_string());
+        cache.add_media(media("a", "", &[0], 10.0, Picture));
+        cache.add_media(media("b", "", &[0, 1], 20.0, Picture));
+        cache.add_media(media("c", "", &[1], 30.0, Picture));
+        cache.add_media(media("d", "", &[0], 20.0, Video));
+        cache
+    }
+
+    #[test]
+    fn query_orders_by_taken_datetime_then_id() {
+        let cache = cache_to_query();
+        assert_eq!(queried_ids(&cache, &MediaQuery::default()), [2, 3, 1, 0]);
+        assert_eq!(queried_ids(&cache, &MediaQuery { order: SortOrder::Oldest, ..MediaQuery::default() }), [0, 1, 3, 2]);
+    }
+
+    #[test]
+    fn query_filters_by_tags_dates_and_type() {
+        let cache = cache_to_query();
+        assert_eq!(queried_ids(&cache, &MediaQuery { tags: vec![0], ..MediaQuery::default() }), [3, 1, 0]);
+        assert_eq!(queried_ids(&cache, &MediaQuery { tags: vec![0, 1], ..MediaQuery::default() }), [1]);
+        assert_eq!(queried_ids(&cache, &MediaQuery { tags: vec![0], exclude_tags: vec![1], ..MediaQuery::default() }), [3, 0]);
+        assert!(queried_ids(&cache, &MediaQuery { tags: vec![7], ..MediaQuery::default() }).is_empty());
+        assert_eq!(queried_ids(&cache, &MediaQuery { from: Some(20.0), to: Some(20.0), ..MediaQuery::default() }), [3, 1]);
+        assert_eq!(queried_ids(&cache, &MediaQuery { from: Some(15.0), ..MediaQuery::default() }), [2, 3, 1]);
+        assert!(queried_ids(&cache, &MediaQuery { from: Some(30.0), to: Some(10.0), ..MediaQuery::default() }).is_empty());
+        assert_eq!(queried_ids(&cache, &MediaQuery { media_type: Some(MediaType::Video), ..MediaQuery::default() }), [3]);
+    }
+
+    #[test]
+    fn query_indexes_follow_updates_and_the_trash() {
+        let mut cache = cache_to_query();
+        let lake = MediaQuery { tags: vec![0], ..MediaQuery::default() };
+
+        assert!(cache.update_media(0, "a".to_string(), String::new(), vec![1], 40.0));
+        assert_eq!(queried_ids(&cache, &lake), [3, 1]);
+        assert_eq!(queried_ids(&cache, &MediaQuery::default()), [0, 2, 3, 1]);
+
+        assert!(cache.delete_media(3, 50.0));
+        assert_eq!(queried_ids(&cache, &lake), [1]);
+        assert!(cache.restore_media(3));
+        assert_eq!(queried_ids(&cache, &lake), [3, 1]);
+
+        // snapshots leave the indexes out, so they are rebuilt after loading one
+        let mut restored: IloveuCache = serde_json::from_slice(&serde_json::to_vec(&cache).unwrap()).unwrap();
+        restored.rebuild_indexes();
+        assert_eq!(queried_ids(&restored, &lake), [3, 1]);
+    }
+
+    #[test]
+    fn words_are_lowercase_and_split_on_punctuation() {
+        assert_eq!(words("At the Lake, 2019!  Été—fun").collect::<Vec<_>>(), ["at", "the", "lake", "2019", "été", "fun"]);
+        assert_eq!(words(" ... ").count(), 0);
+    }
+
+    #[test]
+    fn title_words_weigh_more_than_description_words() {
+        let weighted = weighted_words(&media("Lake lake", "a lake trip", &[], 0.0, MediaType::Picture));
+        assert_eq!(weighted["lake"], 2*TITLE_WORD_WEIGHT+DESCRIPTION_WORD_WEIGHT);
+        assert_eq!(weighted["trip"], DESCRIPTION_WORD_WEIGHT);
+        assert_eq!(weighted.len(), 3);
+    }
+
+    #[test]
+    fn search_ranks_by_weight_and_rarity() {
+        use MediaType::Picture;
+        let mut cache = IloveuCache::new();
+        cache.add_media(media("Lake", "summer", &[], 10.0, Picture));
+        cache.add_media(media("Beach", "summer at the lake", &[], 20.0, Picture));
+        cache.add_media(media("Forest", "summer", &[], 30.0, Picture));
+        cache.add_media(media("Beach", "summer at the lake", &[], 40.0, Picture));
+
+        assert_eq!(cache.search_media("lake"), [0, 3, 1]);
+        // "forest" is in one media and "summer" in all of them, so the rarer word wins
+        assert_eq!(cache.search_media("Summer, forest"), [2, 3, 1, 0]);
+        assert!(cache.search_media("mountain").is_empty());
+        assert!(cache.search_media("").is_empty());
+    }
+
+    #[test]
+    fn search_index_follows_updates_and_the_trash() {
+        let mut cache = IloveuCache::new();
+        cache.add_media(media("Lake", "", &[], 10.0, MediaType::Picture));
+
+        assert!(cache.update_media(0, "Mountain".to_string(), String::new(), Vec::new(), 10.0));
+        assert!(cache.search_media("lake").is_empty());
+        assert!(!cache.indexes.by_word.contains_key("lake"));
+        assert_eq!(cache.search_media("mountain"), [0]);
+
+        assert!(cache.delete_media(0, 20.0));
+        assert!(cache.search_media("mountain").is_empty());
+        assert!(cache.indexes.by_word.is_empty());
+        assert!(cache.restore_media(0));
+        assert_eq!(cache.search_media("mountain"), [0]);
+    }
+
+    /// Tags "a" and "b", then a rename of a tag that doesn't exist, an add tag cut short inside its checksum
+    /// and finally tag "c". Returns the log and the offsets of the rename and the cut short record.
+    async fn store_with_bad_records() -> (TestStore, Vec<u8>, u64, u64) {
+        let (test_store, _) = store_with_tags(&["a", "b"]).await;
+        let mut transactions = test_store.transactions();
+        let rename_offset = transactions.len() as u64;
+        transactions.extend(Transaction::RenameTag { tag_id: 7, name: "x".to_string() }.encode_record());
+        let cut_short_offset = transactions.len() as u64;
+        transactions.extend(frame_record(0, b"\0\0\0\0\0\0\0\x05ab"));
+        transactions.extend(Transaction::AddTag { name: "c".to_string() }.encode_record());
+        test_store.set_transactions(&transactions);
+        (test_store, transactions, rename_offset, cut_short_offset)
+    }
+
+    #[tokio::test]
+    async fn strict_replay_names_the_failing_record() {
+        let (test_store, _, rename_offset, _) = store_with_bad_records().await;
+        let store = test_store.open(ReplayMode::Strict).await.unwrap();
+
+        let err = IloveuCache::new().run_raw_transactions(store.get_transactions_raw().await.unwrap(), store.key(), ReplayMode::Strict).await.unwrap_err();
+        assert_eq!((err.record_index, err.offset), (2, rename_offset));
+        assert!(matches!(err.kind, ReplayErrorKind::Invalid(_)));
+        assert!(err.to_string().starts_with(&format!("record 2 at offset {} of the transactions log: ", rename_offset)));
+        assert!(store.load_cache().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn lenient_replay_skips_and_reports_bad_records() {
+        let (test_store, mut transactions, rename_offset, cut_short_offset) = store_with_bad_records().await;
+        // damage the name of tag "b", which follows the 9 byte payload of tag "a", so its checksum no longer matches
+        let b_offset = RECORD_HEADER_LENGTH+9+RECORD_CHECKSUM_LENGTH;
+        transactions[(b_offset+RECORD_HEADER_LENGTH+8) as usize] ^= 0xff;
+        test_store.set_transactions(&transactions);
+        assert!(test_store.open(ReplayMode::Strict).await.is_err());
+
+        let store = test_store.open(ReplayMode::Lenient).await.unwrap();
+        assert_eq!(test_store.transactions(), transactions);
+        let mut cache = IloveuCache::new();
+        let skipped = cache.run_raw_transactions(store.get_transactions_raw().await.unwrap(), store.key(), ReplayMode::Lenient).await.unwrap();
+        let positions: Vec<_> = skipped.iter().map(|err| (err.record_index, err.offset)).collect();
+        assert_eq!(positions, [(1, b_offset), (2, rename_offset), (3, cut_short_offset)]);
+        assert!(matches!(skipped[0].kind, ReplayErrorKind::Corrupt(_)));
+        assert!(matches!(skipped[1].kind, ReplayErrorKind::Invalid(_)));
+        assert!(matches!(&skipped[2].kind, ReplayErrorKind::Decode(err) if err.field == "name"));
+        assert_eq!(cache.corrupt_transactions, 3);
+        let mut names: Vec<_> = cache.get_tags().values().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, ["a", "c"]);
+        assert_eq!(store.load_cache().await.unwrap().get_tags().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn verify_finds_nothing_wrong_with_a_healthy_store() {
+        let (test_store, _) = store_with_tags(&["lake"]).await;
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        store.add_media(&metadata("kept", "", &[0], 1.0, MediaType::Picture, "kept.jpg"), upload(&store, b"kept").await).await.unwrap();
+        store.add_media(&metadata("trashed", "", &[], 2.0, MediaType::Video, "trashed.mp4"), upload(&store, b"trashed").await).await.unwrap();
+        store.delete_media(1, 3.0).await.unwrap();
+
+        let report = verify_store(&test_store.path, None).await.unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!((report.version, report.transactions_length), (LATEST_VERSION, test_store.transactions().len() as u64));
+        assert_eq!((report.records, report.unknown_transactions), (4, 0));
+        assert_eq!((report.tags, report.media, report.trashed_media), (1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn verify_reports_every_problem_without_changing_anything() {
+        let (test_store, _) = store_with_tags(&["lake"]).await;
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let short_blob = store.add_media(&metadata("short", "", &[0], 1.0, MediaType::Picture, "short.jpg"), upload(&store, b"short blob").await).await.unwrap();
+        store.add_media(&metadata("untagged", "", &[7], 2.0, MediaType::Picture, "untagged.jpg"), upload(&store, b"untagged").await).await.unwrap();
+        drop(store);
+        let blob_path = test_store.path.join("blobs").join(hash_to_hex(&short_blob.hash));
+        let mut blob_bytes = std::fs::read(&blob_path).unwrap();
+        blob_bytes.truncate(blob_bytes.len()-4);
+        std::fs::write(&blob_path, &blob_bytes).unwrap();
+        let mut transactions = test_store.transactions();
+        transactions.extend(frame_record(1000, b"from the future"));
+        transactions.extend(Transaction::RenameTag { tag_id: 9, name: "x".to_string() }.encode_record());
+        test_store.set_transactions(&transactions);
+
+        let report = verify_store(&test_store.path, None).await.unwrap();
+        assert_eq!((report.records, report.unknown_transactions), (5, 1));
+        assert_eq!(report.problems.len(), 3, "{:?}", report.problems);
+        assert!(report.problems[0].starts_with("record 4 at offset"));
+        assert_eq!(report.problems[1], format!("media 0: blob {} holds 6 bytes but 10 were expected", hash_to_hex(&short_blob.hash)));
+        assert_eq!(report.problems[2], "media 1 refers to unknown tag 7");
+        assert_eq!(test_store.transactions(), transactions);
+        assert_eq!(std::fs::read(&blob_path).unwrap(), blob_bytes);
+    }
+
+    #[tokio::test]
+    async fn verify_refuses_to_read_a_newer_store() {
+        let (test_store, _) = store_with_tags(&["lake"]).await;
+        std::fs::write(test_store.path.join("version"), (LATEST_VERSION+1).to_be_bytes()).unwrap();
+
+        let report = verify_store(&test_store.path, None).await.unwrap();
+        assert_eq!(report.records, 0);
+        assert_eq!(report.problems.len(), 1);
+    }
+
+    /// Writes `files` of relative paths and contents into a fresh directory.
+    fn source_directory(files: &[(&str, &[u8])]) -> TestStore {
+        let source = TestStore::new();
+        for (relative_path, file_bytes) in files {
+            let file_path = source.path.join(relative_path);
+            std::fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+            std::fs::write(file_path, file_bytes).unwrap();
+        }
+        source
+    }
+
+    #[tokio::test]
+    async fn importing_a_directory_tags_by_folder_and_skips_duplicates() {
+        let source = source_directory(&[
+            ("lake/2019/a.jpg", b"a"),
+            ("lake/b.MP4", b"b"),
+            ("notes.txt", b"notes"),
+            (".hidden/c.jpg", b"c"),
+            ("z-copy.jpeg", b"a"),
+        ]);
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let mut cache = store.load_cache().await.unwrap();
+
+        let report = import_directory(&mut store, &mut cache, &source.path, true).await.unwrap();
+        assert_eq!((report.imported, report.duplicates, report.unsupported, report.added_tags), (2, 1, 1, 2));
+        let lake = cache.find_tag("lake").unwrap();
+        let year = cache.find_tag("2019").unwrap();
+        let a = &cache.get_media()[&0];
+        assert_eq!((a.title.as_str(), a.filename.as_str(), a.tags_vec.as_slice()), ("a", "a.jpg", &[lake, year][..]));
+        assert!(matches!(a.media_type, MediaType::Picture));
+        let b = &cache.get_media()[&1];
+        assert_eq!((b.title.as_str(), b.tags_vec.as_slice()), ("b", &[lake][..]));
+        assert!(matches!(b.media_type, MediaType::Video));
+        assert_eq!(file_bytes(&store, &b.file_reference).await, b"b");
+        assert_eq!(std::fs::read_dir(test_store.path.join("uploads")).unwrap().count(), 0);
+
+        let replayed = replay(&store).await.unwrap();
+        assert_eq!(replayed.get_tags(), cache.get_tags());
+        assert_eq!(replayed.get_media().len(), 2);
+
+        let report = import_directory(&mut store, &mut cache, &source.path, true).await.unwrap();
+        assert_eq!((report.imported, report.duplicates, report.added_tags), (0, 3, 0));
+        assert_eq!(replay(&store).await.unwrap().get_media().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn importing_without_folder_tags_adds_no_tags() {
+        let source = source_directory(&[("lake/a.png", b"a")]);
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let mut cache = store.load_cache().await.unwrap();
+
+        let report = import_directory(&mut store, &mut cache, &source.path, false).await.unwrap();
+        assert_eq!((report.imported, report.added_tags), (1, 0));
+        assert!(cache.get_tags().is_empty());
+        assert!(cache.get_media()[&0].tags_vec.is_empty());
+    }
+
+    #[tokio::test]
+    async fn exported_archives_import_into_another_store() {
+        let (exported_store, _) = store_with_tags(&["lake", "family"]).await;
+        let mut store = exported_store.open(ReplayMode::Strict).await.unwrap();
+        store.add_media(&metadata("a", "", &[0, 1], 1.0, MediaType::Picture, "same.jpg"), upload(&store, b"a").await).await.unwrap();
+        store.add_media(&metadata("b", "at home", &[1], 2.0, MediaType::Video, "same.jpg"), upload(&store, b"b").await).await.unwrap();
+        store.add_media(&metadata("trashed", "", &[], 3.0, MediaType::Picture, "trashed.jpg"), upload(&store, b"trashed").await).await.unwrap();
+        store.delete_media(2, 4.0).await.unwrap();
+        let exported_media = plan_export(&store.load_cache().await.unwrap());
+        let mut archive = Vec::new();
+        write_media_zip(&mut archive, &tokio::sync::RwLock::new(store), &exported_media).await.unwrap();
+        let source = source_directory(&[("export.zip", &archive)]);
+        let archive_path = source.path.join("export.zip");
+
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        store.add_tag("LAKE").await.unwrap();
+        let cache = tokio::sync::RwLock::new(store.load_cache().await.unwrap());
+        let store = tokio::sync::RwLock::new(store);
+        let report = import_archive(&store, &cache, tokio::fs::File::open(&archive_path).await.unwrap()).await.unwrap();
+        assert_eq!((report.imported, report.duplicates, report.added_tags), (2, 0, 1));
+        {
+            let (store, cache) = (store.read().await, cache.read().await);
+            let family = cache.find_tag("family").unwrap();
+            let a = &cache.get_media()[&0];
+            assert_eq!((a.title.as_str(), a.filename.as_str(), a.tags_vec.as_slice(), a.taken_datetime), ("a", "same.jpg", &[0, family][..], 1.0));
+            let b = &cache.get_media()[&1];
+            assert_eq!((b.title.as_str(), b.description.as_str(), b.filename.as_str(), b.tags_vec.as_slice()), ("b", "at home", "same.jpg", &[family][..]));
+            assert!(matches!(b.media_type, MediaType::Video));
+            assert_eq!(file_bytes(&store, &b.file_reference).await, b"b");
+            assert_eq!(replay(&store).await.unwrap().get_media().len(), 2);
+        }
+
+        let report = import_archive(&store, &cache, tokio::fs::File::open(&archive_path).await.unwrap()).await.unwrap();
+        assert_eq!((report.imported, report.duplicates, report.added_tags), (0, 2, 0));
+        assert_eq!(std::fs::read_dir(test_store.path.join("uploads")).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn invalid_archives_are_refused_but_empty_ones_are_not() {
+        let mut archive = Vec::new();
+        write_media_zip(&mut archive, &tokio::sync::RwLock::new(TestStore::new().open(ReplayMode::Strict).await.unwrap()), &[]).await.unwrap();
+        let source = source_directory(&[("export.zip", &archive), ("not-a.zip", b"not a zip")]);
+        let test_store = TestStore::new();
+        let store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let cache = tokio::sync::RwLock::new(store.load_cache().await.unwrap());
+        let store = tokio::sync::RwLock::new(store);
+
+        assert_eq!(import_archive(&store, &cache, tokio::fs::File::open(source.path.join("export.zip")).await.unwrap()).await.unwrap().imported, 0);
+        assert_eq!(import_archive(&store, &cache, tokio::fs::File::open(source.path.join("not-a.zip")).await.unwrap()).await.unwrap_err().kind(), tokio::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn encrypted_stores_reopen_and_recover_torn_writes() {
+        let test_store = TestStore::new();
+        let options = StoreOptions { passphrase: Some("correct horse".to_string()), ..StoreOptions::default() };
+        let mut store = IloveuTransactionsStore::open_with_options(&test_store.path, options.clone()).await.unwrap();
+        store.add_tag("lake").await.unwrap();
+        let whole = test_store.transactions();
+        assert!(!whole.windows(4).any(|window| window == b"lake"));
+        let mut torn = whole.clone();
+        torn.extend_from_slice(&whole[..whole.len()-3]);
+        test_store.set_transactions(&torn);
+
+        let store = IloveuTransactionsStore::open_with_options(&test_store.path, options).await.unwrap();
+        assert_eq!(test_store.transactions(), whole);
+        assert_eq!(store.load_cache().await.unwrap().get_tags()[&0], "lake");
+        assert!(test_store.open(ReplayMode::Strict).await.is_err());
+    }
+
+    /// The flags of the blob `blob_reference` points to, as written in its header.
+    fn blob_flags(store: &IloveuTransactionsStore, blob_reference: &BlobReference) -> u64 {
+        let blob_bytes = std::fs::read(store.blob_path(&blob_reference.hash)).unwrap();
+        u64::from_be_bytes(blob_bytes[8..16].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn compressed_records_and_blobs_round_trip() {
+        let test_store = TestStore::new();
+        let options = StoreOptions { compress: true, ..StoreOptions::default() };
+        let mut store = IloveuTransactionsStore::open_with_options(&test_store.path, options).await.unwrap();
+        let long_name = "lake ".repeat(100);
+        store.add_tag(&long_name).await.unwrap();
+        let transactions = test_store.transactions();
+        assert_eq!(u64::from_be_bytes(transactions[8..16].try_into().unwrap()), RECORD_FLAG_COMPRESSED);
+        assert!((transactions.len() as u64) < RECORD_HEADER_LENGTH+8+long_name.len() as u64);
+
+        let text = "a long and very repetitive text file ".repeat(2000);
+        let noise: Vec<u8> = (0..64*1024).map(|_| rand::thread_rng().gen()).collect();
+        let text_blob = store.add_media(&metadata("text", "", &[], 1.0, MediaType::Picture, "text.tif"), upload(&store, text.as_bytes()).await).await.unwrap();
+        let noise_blob = store.add_media(&metadata("noise", "", &[], 2.0, MediaType::Picture, "noise.jpg"), upload(&store, &noise).await).await.unwrap();
+        assert_eq!(blob_flags(&store, &text_blob), BLOB_FLAG_COMPRESSED);
+        assert_eq!(blob_flags(&store, &noise_blob), 0);
+        assert_eq!(text_blob.size, text.len() as u64);
+        assert!(std::fs::metadata(store.blob_path(&text_blob.hash)).unwrap().len() < text.len() as u64/10);
+        let stored_file = store.open_stored_file(&FileReference::Blob(text_blob)).await.unwrap();
+        assert!(stored_file.compressed);
+
+        // compression is only a choice for new writes, so a store opened without it still reads everything back
+        let store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let cache = store.load_cache().await.unwrap();
+        assert_eq!(cache.get_tags()[&0], long_name);
+        assert_eq!(file_bytes(&store, &cache.get_media()[&0].file_reference).await, text.as_bytes());
+        assert_eq!(file_bytes(&store, &cache.get_media()[&1].file_reference).await, noise);
+        assert!(verify_store(&test_store.path, None).await.unwrap().problems.is_empty());
+    }
+
+    #[tokio::test]
+    async fn compressed_blobs_of_encrypted_stores_round_trip() {
+        let test_store = TestStore::new();
+        let options = StoreOptions { passphrase: Some("correct horse".to_string()), compress: true, ..StoreOptions::default() };
+        let mut store = IloveuTransactionsStore::open_with_options(&test_store.path, options).await.unwrap();
+        // longer than one sealed chunk, so chunks of the compressed frame are sealed one after the other
+        let text: String = (0..100_000).map(|_| format!("line {:08x}\n", rand::thread_rng().gen::<u32>())).collect();
+        let blob_reference = store.add_media(&metadata("text", "", &[], 1.0, MediaType::Picture, "text.tif"), upload(&store, text.as_bytes()).await).await.unwrap();
+        assert_eq!(blob_flags(&store, &blob_reference), BLOB_FLAG_ENCRYPTED | BLOB_FLAG_COMPRESSED);
+        let blob_length = std::fs::metadata(store.blob_path(&blob_reference.hash)).unwrap().len();
+        assert!(blob_length > 2*BLOB_CHUNK_LENGTH && blob_length < text.len() as u64);
+
+        let cache = store.load_cache().await.unwrap();
+        assert_eq!(file_bytes(&store, &cache.get_media()[&0].file_reference).await, text.as_bytes());
+        assert!(verify_store(&test_store.path, Some("correct horse")).await.unwrap().problems.is_empty());
+    }
+
+    #[tokio::test]
+    async fn log_tag_changes_with_every_transaction_and_compaction() {
+        let (test_store, _) = store_with_tags(&["lake", "family"]).await;
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let first_tag = store.log_tag().await.unwrap();
+        assert_eq!(test_store.open(ReplayMode::Strict).await.unwrap().log_tag().await.unwrap(), first_tag);
+
+        store.rename_tag(0, "lakes").await.unwrap();
+        let renamed_tag = store.log_tag().await.unwrap();
+        assert_ne!(renamed_tag, first_tag);
+
+        store.delete_tag(1).await.unwrap();
+        let deleted_tag = store.log_tag().await.unwrap();
+        store.compact(&store.load_cache().await.unwrap(), 0.0).await.unwrap();
+        let compacted_tag = store.log_tag().await.unwrap();
+        assert!(![first_tag, renamed_tag, deleted_tag].contains(&compacted_tag));
+    }
+
+    #[tokio::test]
+    async fn file_tags_identify_the_bytes_without_giving_away_their_hash() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let blob_reference = store.add_media(&metadata("a", "", &[], 1.0, MediaType::Picture, "a.jpg"), upload(&store, b"same bytes").await).await.unwrap();
+        let file_reference = FileReference::Blob(blob_reference);
+        assert_eq!(store.file_tag(&file_reference), hash_to_hex(&blob_reference.hash));
+        assert_eq!(store.file_tag(&FileReference::Inline(SizedReference { offset: 16, size: 255 })), "inline-10-ff");
+
+        let encrypted_test_store = TestStore::new();
+        let options = StoreOptions { passphrase: Some("correct horse".to_string()), ..StoreOptions::default() };
+        let mut encrypted_store = IloveuTransactionsStore::open_with_options(&encrypted_test_store.path, options).await.unwrap();
+        let encrypted_blob_reference = encrypted_store.add_media(&metadata("a", "", &[], 1.0, MediaType::Picture, "a.jpg"), upload(&encrypted_store, b"same bytes").await).await.unwrap();
+        assert_eq!(encrypted_blob_reference.hash, blob_reference.hash);
+        let encrypted_file_tag = encrypted_store.file_tag(&FileReference::Blob(encrypted_blob_reference));
+        assert_ne!(encrypted_file_tag, hash_to_hex(&blob_reference.hash));
+        assert!(encrypted_store.blob_path(&blob_reference.hash).ends_with(&encrypted_file_tag));
+    }
+
+    /// A PNG of `width` by `height` pixels, with an alpha channel if `alpha`.
+    fn png(width: u32, height: u32, alpha: bool) -> Vec<u8> {
+        let picture = if alpha {
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(width, height, |x, y| image::Rgba([x as u8, y as u8, 0, 128])))
+        } else {
+            image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| image::Rgb([x as u8, y as u8, 0])))
+        };
+        let mut encoded = std::io::Cursor::new(Vec::new());
+        picture.write_to(&mut encoded, image::ImageFormat::Png).unwrap();
+        encoded.into_inner()
+    }
+
+    fn format_and_dimensions(encoded: &[u8]) -> (image::ImageFormat, (u32, u32)) {
+        let picture = image::load_from_memory(encoded).unwrap();
+        (image::guess_format(encoded).unwrap(), (picture.width(), picture.height()))
+    }
+
+    #[test]
+    fn pictures_are_resized_to_every_variant_they_are_bigger_than() {
+        let resized = resize_picture(&png(3000, 1000, false)).unwrap();
+        let [(VariantSize::Thumb, Some(thumb)), (VariantSize::Medium, Some(medium))] = &resized[..] else { panic!("unexpected variants {:?}", resized) };
+        assert_eq!(format_and_dimensions(thumb), (image::ImageFormat::Jpeg, (640, 213)));
+        assert_eq!(format_and_dimensions(medium), (image::ImageFormat::Jpeg, (1920, 640)));
+
+        let resized = resize_picture(&png(1000, 1200, true)).unwrap();
+        let [(VariantSize::Thumb, Some(thumb)), (VariantSize::Medium, None)] = &resized[..] else { panic!("unexpected variants {:?}", resized) };
+        assert_eq!(format_and_dimensions(thumb), (image::ImageFormat::Png, (533, 640)));
+
+        let resized = resize_picture(&png(640, 480, false)).unwrap();
+        assert!(resized.iter().all(|(_, encoded)| encoded.is_none()));
+        assert!(resize_picture(b"not a picture").is_err());
+    }
+
+    #[tokio::test]
+    async fn generated_variants_are_recorded_and_replayed() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open(ReplayMode::Strict).await.unwrap();
+        let big = store.add_media(&metadata("big", "", &[], 1.0, MediaType::Picture, "big.png"), upload(&store, &png(2000, 1000, false)).await).await.unwrap();
+        let small = store.add_media(&metadata("small", "", &[], 2.0, MediaType::Picture, "small.png"), upload(&store, &png(300, 200, false)).await).await.unwrap();
+        store.add_media(&metadata("heic", "", &[], 3.0, MediaType::Picture, "a.heic"), upload(&store, b"\0\0\0\x18ftypheic").await).await.unwrap();
+        store.add_media(&metadata("video", "", &[], 4.0, MediaType::Video, "a.mp4"), upload(&store, b"video bytes").await).await.unwrap();
+        let cache = tokio::sync::RwLock::new(store.load_cache().await.unwrap());
+        let transactions = tokio::sync::RwLock::new(store);
+
+        assert!(generate_media_variants(&transactions, &cache, 0).await.unwrap());
+        assert!(generate_media_variants(&transactions, &cache, 1).await.unwrap());
+        assert!(!generate_media_variants(&transactions, &cache, 2).await.unwrap());
+        assert!(!generate_media_variants(&transactions, &cache, 3).await.unwrap());
+        assert!(!generate_media_variants(&transactions, &cache, 0).await.unwrap());
+
+        let store = transactions.into_inner();
+        let replayed = replay(&store).await.unwrap();
+        assert_eq!(replayed.get_media()[&0].variants, cache.read().await.get_media()[&0].variants);
+        let big_variants = &replayed.get_media()[&0].variants;
+        assert_eq!(big_variants.len(), 2);
+        assert!(big_variants.values().all(|blob_reference| *blob_reference != big));
+        let (thumb, settled) = replayed.get_media()[&0].file_for_size(MediaFileSize::Thumb);
+        assert!(settled);
+        assert_eq!(format_and_dimensions(&file_bytes(&store, &thumb).await).1, (640, 320));
+        // a picture smaller than every variant is its own variant, without storing it again
+        assert!(replayed.get_media()[&1].variants.values().all(|blob_reference| *blob_reference == small));
+        assert!(replayed.get_media()[&2].variants.is_empty());
+        assert!(replayed.get_media()[&3].variants.is_empty());
+        assert_eq!(blob_names(&test_store).len(), 6);
+    }
+
+    #[tokio::test]
+    async fn swapped_sealed_records_are_refused() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open_encrypted().await.unwrap();
+        store.add_tag("a").await.unwrap();
+        let record_length = test_store.transactions().len();
+        store.add_tag("b").await.unwrap();
+        assert_eq!(store.load_cache().await.unwrap().get_tags().len(), 2);
+
+        let transactions = test_store.transactions();
+        let mut swapped = transactions[record_length..].to_vec();
+        swapped.extend_from_slice(&transactions[..record_length]);
+        test_store.set_transactions(&swapped);
+        assert!(store.load_cache().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn swapped_sealed_blobs_are_refused() {
+        let test_store = TestStore::new();
+        let mut store = test_store.open_encrypted().await.unwrap();
+        let a = store.add_media(&metadata("a", "", &[], 1.0, MediaType::Picture, "a.jpg"), upload(&store, b"first file").await).await.unwrap();
+        let b = store.add_media(&metadata("b", "", &[], 2.0, MediaType::Picture, "b.jpg"), upload(&store, b"other file").await).await.unwrap();
+        assert_eq!(std::fs::read_dir(test_store.path.join("uploads")).unwrap().count(), 0);
+        assert_eq!(file_bytes(&store, &FileReference::Blob(a)).await, b"first file");
+
+        let (a_path, b_path) = (store.blob_path(&a.hash), store.blob_path(&b.hash));
+        let a_blob = std::fs::read(&a_path).unwrap();
+        std::fs::write(&a_path, std::fs::read(&b_path).unwrap()).unwrap();
+        std::fs::write(&b_path, a_blob).unwrap();
+        let mut file_bytes = Vec::new();
+        assert!(store.open_file(&FileReference::Blob(a)).await.unwrap().read_to_end(&mut file_bytes).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sealed_temporary_uploads_are_read_from_anywhere() {
+        let test_store = TestStore::new();
+        let store = test_store.open_encrypted().await.unwrap();
+        let archive: Vec<u8> = (0..200_000u32).map(|i| (i%251) as u8).collect();
+        let upload = store.uploads().write_temporary(archive.as_slice()).await.unwrap();
+
+        let stored = std::fs::read(&upload.temporary_path).unwrap();
+        assert!(!stored.windows(64).any(|window| window == &archive[1000..1064]));
+
+        let mut reader = store.uploads().open_seekable(&upload).await.unwrap();
+        let mut middle = vec![0u8; 100_000];
+        reader.seek(SeekFrom::Start(60_000)).await.unwrap();
+        reader.read_exact(&mut middle).await.unwrap();
+        assert_eq!(middle, &archive[60_000..160_000]);
+
+        let mut end = Vec::new();
+        assert_eq!(reader.seek(SeekFrom::End(-10)).await.unwrap(), 199_990);
+        reader.read_to_end(&mut end).await.unwrap();
+        assert_eq!(end, &archive[199_990..]);
+
+        let mut start = vec![0u8; 10];
+        reader.seek(SeekFrom::Start(0)).await.unwrap();
+        reader.read_exact(&mut start).await.unwrap();
+        assert_eq!(start, &archive[..10]);
+    }
+}
@@ -0,0 +1,305 @@
+use std::io::Cursor;
+
+use exif::{Exif, Field, In, Reader, Tag, Value};
+use tokio::io::AsyncReadExt;
+
+use crate::crypto::MediaReader;
+use crate::db::{Upload, Uploads};
+use crate::types::{ExifMetadata, MediaType};
+
+/// How much of a picture is searched for EXIF metadata. JPEGs keep it at the very start, but HEIC files
+/// can have it anywhere.
+const EXIF_SCAN_LENGTH: u64 = 64*1024*1024;
+
+fn ascii_bytes(exif: &Exif, tag: Tag) -> Option<&[u8]> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values.first().map(Vec::as_slice),
+        _ => None,
+    }
+}
+
+/// The text of an ASCII field, without the padding some cameras leave at the end.
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    let text = String::from_utf8_lossy(ascii_bytes(exif, tag)?);
+    let text = text.trim_end_matches('\0').trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn rationals(field: &Field) -> Option<Vec<f64>> {
+    match &field.value {
+        Value::Rational(values) => Some(values.iter().map(|value| value.to_f64()).collect()),
+        _ => None,
+    }
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year-1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year-era*400;
+    let day_of_year = (153*((month+9)%12)+2)/5+day-1;
+    let day_of_era = year_of_era*365+year_of_era/4-year_of_era/100+day_of_year;
+    era*146097+day_of_era-719468
+}
+
+/// When the photo was taken, preferring the time the shutter fired over when it was saved, along with the UTC
+/// offset the camera was set to if it says.
+fn taken_datetime(exif: &Exif) -> Option<(f64, Option<i64>)> {
+    let (datetime_bytes, subsec_tag, offset_tag) = [
+        (Tag::DateTimeOriginal, Tag::SubSecTimeOriginal, Tag::OffsetTimeOriginal),
+        (Tag::DateTimeDigitized, Tag::SubSecTimeDigitized, Tag::OffsetTimeDigitized),
+        (Tag::DateTime, Tag::SubSecTime, Tag::OffsetTime),
+    ].into_iter().find_map(|(datetime_tag, subsec_tag, offset_tag)| Some((ascii_bytes(exif, datetime_tag)?, subsec_tag, offset_tag)))?;
+
+    let mut datetime = exif::DateTime::from_ascii(datetime_bytes).ok()?;
+    // damaged subseconds or offsets still leave a usable date
+    if let Some(subsec_bytes) = ascii_bytes(exif, subsec_tag) {
+        let _ = datetime.parse_subsec(subsec_bytes);
+    }
+    if let Some(offset_bytes) = ascii_bytes(exif, offset_tag) {
+        let _ = datetime.parse_offset(offset_bytes);
+    }
+    if !(1..=12).contains(&datetime.month) || !(1..=31).contains(&datetime.day) || datetime.hour > 23 || datetime.minute > 59 || datetime.second > 60 {
+        return None;
+    }
+
+    let utc_offset_minutes = datetime.offset.map(i64::from);
+    let days = days_from_civil(datetime.year.into(), datetime.month.into(), datetime.day.into());
+    let seconds = days*24*60*60+i64::from(datetime.hour)*60*60+i64::from(datetime.minute)*60+i64::from(datetime.second)-utc_offset_minutes.unwrap_or(0)*60;
+    let millis = seconds as f64*1000.0+(datetime.nanosecond.unwrap_or(0)/1_000_000) as f64;
+    Some((millis, utc_offset_minutes))
+}
+
+/// A GPS coordinate from its degrees, minutes and seconds, negative if its reference is `negative_reference`.
+fn coordinate(exif: &Exif, tag: Tag, reference_tag: Tag, negative_reference: &str) -> Option<f64> {
+    let parts = rationals(exif.get_field(tag, In::PRIMARY)?)?;
+    let degrees = parts.first()?+parts.get(1).unwrap_or(&0.0)/60.0+parts.get(2).unwrap_or(&0.0)/3600.0;
+    let negative = ascii(exif, reference_tag).is_some_and(|reference| reference.eq_ignore_ascii_case(negative_reference));
+    degrees.is_finite().then_some(if negative { -degrees } else { degrees })
+}
+
+/// Pulls the date, orientation, camera and location out of the EXIF metadata of a JPEG, HEIC, PNG, WebP or TIFF.
+/// Returns `None` if there is none, or it is too damaged to read.
+pub fn read_exif_metadata(picture: &[u8]) -> Option<ExifMetadata> {
+    let exif = Reader::new().read_from_container(&mut Cursor::new(picture)).ok()?;
+    let (taken_datetime, utc_offset_minutes) = match taken_datetime(&exif) {
+        Some((taken_datetime, utc_offset_minutes)) => (Some(taken_datetime), utc_offset_minutes),
+        None => (None, None),
+    };
+    let altitude = exif.get_field(Tag::GPSAltitude, In::PRIMARY).and_then(rationals).and_then(|parts| parts.first().copied()).map(|altitude| {
+        let below_sea_level = exif.get_field(Tag::GPSAltitudeRef, In::PRIMARY).and_then(|field| field.value.get_uint(0)) == Some(1);
+        if below_sea_level { -altitude } else { altitude }
+    });
+
+    let exif_metadata = ExifMetadata {
+        taken_datetime,
+        utc_offset_minutes,
+        orientation: exif.get_field(Tag::Orientation, In::PRIMARY).and_then(|field| field.value.get_uint(0)).filter(|orientation| (1..=8).contains(orientation)).map(u64::from),
+        camera_make: ascii(&exif, Tag::Make),
+        camera_model: ascii(&exif, Tag::Model),
+        latitude: coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S"),
+        longitude: coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W"),
+        altitude: altitude.filter(|altitude| altitude.is_finite()),
+    };
+    (exif_metadata != ExifMetadata::default()).then_some(exif_metadata)
+}
+
+/// Reads the EXIF metadata of the `size` byte picture `file`.
+pub async fn exif_metadata_of(file: MediaReader, size: u64) -> Result<Option<ExifMetadata>, tokio::io::Error> {
+    let mut picture = Vec::with_capacity(size.min(EXIF_SCAN_LENGTH) as usize);
+    file.take(size.min(EXIF_SCAN_LENGTH)).read_to_end(&mut picture).await?;
+    Ok(read_exif_metadata(&picture))
+}
+
+/// Reads the EXIF metadata of an uploaded picture, or `None` for videos.
+pub async fn upload_exif_metadata(uploads: &Uploads, upload: &Upload, media_type: MediaType) -> Result<Option<ExifMetadata>, tokio::io::Error> {
+    if media_type != MediaType::Picture {
+        return Ok(None);
+    }
+    exif_metadata_of(uploads.open(upload).await?, upload.blob_reference().size).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An IFD entry as it is laid out in a TIFF: its tag, type, count and value.
+    type Entry = (Tag, u16, u32, Vec<u8>);
+
+    fn ascii_entry(tag: Tag, text: &str) -> Entry {
+        let mut value = text.as_bytes().to_vec();
+        value.push(0);
+        (tag, 2, value.len() as u32, value)
+    }
+
+    fn short_entry(tag: Tag, value: u16) -> Entry {
+        (tag, 3, 1, value.to_le_bytes().to_vec())
+    }
+
+    fn byte_entry(tag: Tag, value: u8) -> Entry {
+        (tag, 1, 1, vec![value])
+    }
+
+    fn rationals_entry(tag: Tag, values: &[(u32, u32)]) -> Entry {
+        let value = values.iter().flat_map(|(numerator, denominator)| [numerator.to_le_bytes(), denominator.to_le_bytes()].concat()).collect();
+        (tag, 5, values.len() as u32, value)
+    }
+
+    /// Appends an IFD with `entries` to `tiff`, followed by the values too long to fit in an entry, and returns
+    /// where it starts.
+    fn write_ifd(tiff: &mut Vec<u8>, mut entries: Vec<Entry>) -> u32 {
+        entries.sort_by_key(|(tag, ..)| tag.number());
+        let start = tiff.len();
+        let values_start = start+2+entries.len()*12+4;
+        let mut values = Vec::new();
+        tiff.extend((entries.len() as u16).to_le_bytes());
+        for (tag, entry_type, count, mut value) in entries {
+            tiff.extend(tag.number().to_le_bytes());
+            tiff.extend(entry_type.to_le_bytes());
+            tiff.extend(count.to_le_bytes());
+            if value.len() <= 4 {
+                value.resize(4, 0);
+                tiff.extend(value);
+            } else {
+                tiff.extend(((values_start+values.len()) as u32).to_le_bytes());
+                values.extend(value);
+                values.resize(values.len().next_multiple_of(2), 0);
+            }
+        }
+        tiff.extend(0u32.to_le_bytes());
+        tiff.extend(values);
+        start as u32
+    }
+
+    /// A little endian TIFF with `primary` entries in IFD0, pointing to an Exif and a GPS IFD if they have any.
+    fn tiff(mut primary: Vec<Entry>, exif: Vec<Entry>, gps: Vec<Entry>) -> Vec<u8> {
+        let mut tiff = b"II*\0\0\0\0\0".to_vec();
+        if !exif.is_empty() {
+            let exif_start = write_ifd(&mut tiff, exif);
+            primary.push((Tag::ExifIFDPointer, 4, 1, exif_start.to_le_bytes().to_vec()));
+        }
+        if !gps.is_empty() {
+            let gps_start = write_ifd(&mut tiff, gps);
+            primary.push((Tag::GPSInfoIFDPointer, 4, 1, gps_start.to_le_bytes().to_vec()));
+        }
+        let primary_start = write_ifd(&mut tiff, primary);
+        tiff[4..8].copy_from_slice(&primary_start.to_le_bytes());
+        tiff
+    }
+
+    /// A JPEG that is nothing but an APP1 segment holding `tiff`.
+    fn jpeg(tiff: &[u8]) -> Vec<u8> {
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1];
+        jpeg.extend(((2+6+tiff.len()) as u16).to_be_bytes());
+        jpeg.extend(b"Exif\0\0");
+        jpeg.extend(tiff);
+        jpeg.extend([0xff, 0xd9]);
+        jpeg
+    }
+
+    #[test]
+    fn days_from_civil_counts_from_the_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+        assert_eq!(days_from_civil(1600, 1, 1), -135140);
+    }
+
+    #[test]
+    fn taken_date_is_moved_to_utc_by_its_offset() {
+        let picture = jpeg(&tiff(
+            vec![ascii_entry(Tag::DateTime, "2022:01:01 00:00:00")],
+            vec![
+                ascii_entry(Tag::DateTimeOriginal, "2021:07:14 18:30:05"),
+                ascii_entry(Tag::SubSecTimeOriginal, "25"),
+                ascii_entry(Tag::OffsetTimeOriginal, "+02:00"),
+            ],
+            vec![],
+        ));
+        let exif_metadata = read_exif_metadata(&picture).unwrap();
+        assert_eq!(exif_metadata.taken_datetime, Some(1626280205250.0));
+        assert_eq!(exif_metadata.utc_offset_minutes, Some(120));
+    }
+
+    #[test]
+    fn taken_date_without_offset_is_read_as_utc() {
+        let exif_metadata = read_exif_metadata(&tiff(vec![ascii_entry(Tag::DateTime, "2021:07:14 18:30:05")], vec![], vec![])).unwrap();
+        assert_eq!(exif_metadata.taken_datetime, Some(1626287405000.0));
+        assert_eq!(exif_metadata.utc_offset_minutes, None);
+
+        let damaged_offset = tiff(vec![], vec![
+            ascii_entry(Tag::DateTimeOriginal, "2021:07:14 18:30:05"),
+            ascii_entry(Tag::OffsetTimeOriginal, "noon"),
+        ], vec![]);
+        assert_eq!(read_exif_metadata(&damaged_offset).unwrap().taken_datetime, Some(1626287405000.0));
+    }
+
+    #[test]
+    fn impossible_dates_are_ignored() {
+        let picture = tiff(vec![ascii_entry(Tag::DateTime, "2021:13:14 18:30:05"), ascii_entry(Tag::Make, "Canon")], vec![], vec![]);
+        let exif_metadata = read_exif_metadata(&picture).unwrap();
+        assert_eq!(exif_metadata.taken_datetime, None);
+        assert_eq!(exif_metadata.camera_make.as_deref(), Some("Canon"));
+    }
+
+    #[test]
+    fn orientation_is_kept_only_if_valid() {
+        for orientation in 1..=8 {
+            let exif_metadata = read_exif_metadata(&jpeg(&tiff(vec![short_entry(Tag::Orientation, orientation)], vec![], vec![]))).unwrap();
+            assert_eq!(exif_metadata.orientation, Some(u64::from(orientation)));
+        }
+        assert_eq!(read_exif_metadata(&jpeg(&tiff(vec![short_entry(Tag::Orientation, 0)], vec![], vec![]))), None);
+        assert_eq!(read_exif_metadata(&jpeg(&tiff(vec![short_entry(Tag::Orientation, 9)], vec![], vec![]))), None);
+    }
+
+    #[test]
+    fn camera_names_lose_their_padding() {
+        let picture = tiff(vec![ascii_entry(Tag::Make, "NIKON CORPORATION  "), ascii_entry(Tag::Model, "NIKON D750\0\0\0"), ascii_entry(Tag::Software, "   ")], vec![], vec![]);
+        let exif_metadata = read_exif_metadata(&picture).unwrap();
+        assert_eq!(exif_metadata.camera_make.as_deref(), Some("NIKON CORPORATION"));
+        assert_eq!(exif_metadata.camera_model.as_deref(), Some("NIKON D750"));
+    }
+
+    #[test]
+    fn gps_coordinates_are_signed_by_their_reference() {
+        let picture = jpeg(&tiff(vec![], vec![], vec![
+            ascii_entry(Tag::GPSLatitudeRef, "N"),
+            rationals_entry(Tag::GPSLatitude, &[(52, 1), (30, 1), (36, 1)]),
+            ascii_entry(Tag::GPSLongitudeRef, "W"),
+            rationals_entry(Tag::GPSLongitude, &[(13, 1), (24, 1), (0, 1)]),
+            byte_entry(Tag::GPSAltitudeRef, 1),
+            rationals_entry(Tag::GPSAltitude, &[(345, 10)]),
+        ]));
+        let exif_metadata = read_exif_metadata(&picture).unwrap();
+        assert!((exif_metadata.latitude.unwrap()-52.51).abs() < 1e-9);
+        assert!((exif_metadata.longitude.unwrap()+13.4).abs() < 1e-9);
+        assert_eq!(exif_metadata.altitude, Some(-34.5));
+
+        let picture = tiff(vec![], vec![], vec![
+            ascii_entry(Tag::GPSLatitudeRef, "S"),
+            rationals_entry(Tag::GPSLatitude, &[(33, 1), (52, 1), (3, 1)]),
+            ascii_entry(Tag::GPSLongitudeRef, "E"),
+            rationals_entry(Tag::GPSLongitude, &[(1512, 10), (0, 1), (0, 1)]),
+            byte_entry(Tag::GPSAltitudeRef, 0),
+            rationals_entry(Tag::GPSAltitude, &[(58, 1)]),
+        ]);
+        let exif_metadata = read_exif_metadata(&picture).unwrap();
+        assert!(exif_metadata.latitude.unwrap() < -33.86);
+        assert_eq!(exif_metadata.longitude, Some(151.2));
+        assert_eq!(exif_metadata.altitude, Some(58.0));
+    }
+
+    #[test]
+    fn coordinates_with_a_zero_denominator_are_dropped() {
+        let picture = tiff(vec![ascii_entry(Tag::Make, "Canon")], vec![], vec![rationals_entry(Tag::GPSLatitude, &[(52, 0), (0, 1), (0, 1)])]);
+        assert_eq!(read_exif_metadata(&picture).unwrap().latitude, None);
+    }
+
+    #[test]
+    fn pictures_without_exif_have_no_metadata() {
+        assert_eq!(read_exif_metadata(&[0xff, 0xd8, 0xff, 0xd9]), None);
+        assert_eq!(read_exif_metadata(b"not a picture"), None);
+        assert_eq!(read_exif_metadata(&jpeg(&tiff(vec![ascii_entry(Tag::Software, "GIMP")], vec![], vec![]))), None);
+    }
+}
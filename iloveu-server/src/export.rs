@@ -0,0 +1,413 @@
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, sync::RwLock};
+use tokio_util::io::InspectReader;
+
+use crate::db::{IloveuCache, IloveuTransactionsStore};
+use crate::types::{FileReference, MediaType};
+
+/// Sizes and offsets from this value up only fit in zip64 records, since it is what says to look there instead.
+const ZIP64_THRESHOLD: u64 = u32::MAX as u64;
+/// Entry counts from this value up only fit in the zip64 end of central directory record.
+const ZIP64_ENTRIES_THRESHOLD: u64 = u16::MAX as u64;
+const ZIP_LOCAL_HEADER_LENGTH: u64 = 30;
+const ZIP_CENTRAL_HEADER_LENGTH: u64 = 46;
+/// Follows the data of every entry, since its CRC is only known once all of it has been streamed.
+const ZIP_DATA_DESCRIPTOR_LENGTH: u64 = 16;
+/// The data descriptor of an entry with zip64 sizes holds them as 64 bits.
+const ZIP64_DATA_DESCRIPTOR_LENGTH: u64 = 24;
+const ZIP64_EXTRA_FIELD_HEADER_LENGTH: u64 = 4;
+/// The zip64 end of central directory record followed by its locator.
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LENGTH: u64 = 56+20;
+const ZIP_END_OF_CENTRAL_DIRECTORY_LENGTH: u64 = 22;
+const ZIP_LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const ZIP_DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const ZIP_CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const ZIP_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+/// Sizes and CRC follow the data, and the path is UTF-8.
+const ZIP_FLAGS: u16 = 1 << 3 | 1 << 11;
+const ZIP_VERSION: u16 = 20;
+const ZIP64_VERSION: u16 = 45;
+const METADATA_PATH: &str = "metadata.json";
+
+/// One media file in a zip export, along with the entry describing it in `metadata.json`.
+pub struct ExportedMedia {
+    pub file_reference: FileReference,
+    pub metadata: ExportedMetadata,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedMetadata {
+    pub id: u64,
+    /// Where the file is inside the archive, usually just its original filename.
+    pub path: String,
+    pub title: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub taken_datetime: f64,
+    pub media_type: MediaType,
+    pub filename: String,
+}
+
+/// Lists every media that isn't in the trash, oldest first, giving files with clashing filenames unique paths.
+pub fn plan_export(cache: &IloveuCache) -> Vec<ExportedMedia> {
+    let mut media: Vec<_> = cache.get_media().iter().collect();
+    media.sort_unstable_by(|(a_id, a), (b_id, b)| a.taken_datetime.total_cmp(&b.taken_datetime).then(a_id.cmp(b_id)));
+
+    let mut used_paths = HashSet::new();
+    media.into_iter().map(|(media_id, cached_media)| {
+        // filenames come from uploads, so they must not be able to escape the archive
+        let mut path = cached_media.filename.replace(['/', '\\'], "_");
+        if path.is_empty() || path == "." || path == ".." || used_paths.contains(&path) {
+            path = format!("{}-{}", media_id, path);
+        }
+        used_paths.insert(path.clone());
+
+        ExportedMedia {
+            file_reference: cached_media.file_reference,
+            metadata: ExportedMetadata {
+                id: *media_id,
+                path,
+                title: cached_media.title.clone(),
+                description: cached_media.description.clone(),
+                tags: cached_media.tags_vec.iter()
+                    .filter_map(|tag_id| cache.get_tags().get(tag_id).cloned())
+                    .collect(),
+                taken_datetime: cached_media.taken_datetime,
+                media_type: cached_media.media_type,
+                filename: cached_media.filename.clone(),
+            },
+        }
+    }).collect()
+}
+
+fn metadata_json(exported_media: &[ExportedMedia]) -> Result<Vec<u8>, tokio::io::Error> {
+    let metadata: Vec<&ExportedMetadata> = exported_media.iter().map(|media| &media.metadata).collect();
+    Ok(serde_json::to_vec_pretty(&metadata)?)
+}
+
+/// Whether the sizes of a file of `size` bytes have to go in zip64 records.
+fn needs_zip64_sizes(size: u64) -> bool {
+    size >= ZIP64_THRESHOLD
+}
+
+fn local_entry_length(path: &str, size: u64) -> u64 {
+    let (extra_field_length, data_descriptor_length) = match needs_zip64_sizes(size) {
+        true => (ZIP64_EXTRA_FIELD_HEADER_LENGTH+16, ZIP64_DATA_DESCRIPTOR_LENGTH),
+        false => (0, ZIP_DATA_DESCRIPTOR_LENGTH),
+    };
+    ZIP_LOCAL_HEADER_LENGTH+path.len() as u64+extra_field_length+size+data_descriptor_length
+}
+
+/// The zip64 extra field of the central directory header of a file of `size` bytes whose local header is at
+/// `offset`, holding only the values too big for the header itself. Empty if there are none.
+fn central_zip64_extra_field(size: u64, offset: u64) -> Vec<u8> {
+    let mut values = Vec::new();
+    if needs_zip64_sizes(size) {
+        values.extend_from_slice(&size.to_le_bytes());
+        values.extend_from_slice(&size.to_le_bytes());
+    }
+    if offset >= ZIP64_THRESHOLD {
+        values.extend_from_slice(&offset.to_le_bytes());
+    }
+    if values.is_empty() {
+        return values;
+    }
+    let mut extra_field = Vec::with_capacity(ZIP64_EXTRA_FIELD_HEADER_LENGTH as usize+values.len());
+    extra_field.extend_from_slice(&ZIP64_EXTRA_FIELD_ID.to_le_bytes());
+    extra_field.extend_from_slice(&(values.len() as u16).to_le_bytes());
+    extra_field.extend_from_slice(&values);
+    extra_field
+}
+
+/// Whether the central directory of `entries` entries, `length` bytes long and starting at `offset`, can only be
+/// found through a zip64 end of central directory record.
+fn needs_zip64_end(entries: u64, offset: u64, length: u64) -> bool {
+    entries >= ZIP64_ENTRIES_THRESHOLD || offset >= ZIP64_THRESHOLD || length >= ZIP64_THRESHOLD
+}
+
+/// How long a zip of stored entries with these paths and sizes is, following the same offsets the writer does.
+fn zip_length<'a>(entries: impl Iterator<Item = (&'a str, u64)>) -> u64 {
+    let mut entry_count = 0;
+    let mut offset = 0;
+    let mut central_directory_length = 0;
+    for (path, size) in entries {
+        entry_count += 1;
+        central_directory_length += ZIP_CENTRAL_HEADER_LENGTH+path.len() as u64+central_zip64_extra_field(size, offset).len() as u64;
+        offset += local_entry_length(path, size);
+    }
+    let end_length = if needs_zip64_end(entry_count, offset, central_directory_length) {
+        ZIP64_END_OF_CENTRAL_DIRECTORY_LENGTH+ZIP_END_OF_CENTRAL_DIRECTORY_LENGTH
+    } else {
+        ZIP_END_OF_CENTRAL_DIRECTORY_LENGTH
+    };
+    offset+central_directory_length+end_length
+}
+
+/// How long the zip written by [`write_media_zip`] for `exported_media` is, so it can be sent with its length.
+/// Zip64 records are only written where a size, offset or count doesn't fit the usual ones.
+pub fn media_zip_length(exported_media: &[ExportedMedia]) -> Result<u64, tokio::io::Error> {
+    let metadata_length = metadata_json(exported_media)?.len() as u64;
+    Ok(zip_length(exported_media.iter()
+        .map(|media| (media.metadata.path.as_str(), media.file_reference.size()))
+        .chain([(METADATA_PATH, metadata_length)])))
+}
+
+/// The MS-DOS time and date zip entries are stamped with, in UTC since zips don't say which time zone they're in.
+fn dos_datetime(unix_seconds: u64) -> (u16, u16) {
+    let days = (unix_seconds/86400) as i64;
+    let seconds_of_day = unix_seconds%86400;
+    // days since 1970-01-01 to a civil date, from Howard Hinnant's chrono-compatible algorithms
+    let era_days = days+719468;
+    let era = era_days.div_euclid(146097);
+    let day_of_era = era_days.rem_euclid(146097);
+    let year_of_era = (day_of_era-day_of_era/1460+day_of_era/36524-day_of_era/146096)/365;
+    let day_of_year = day_of_era-(365*year_of_era+year_of_era/4-year_of_era/100);
+    let shifted_month = (5*day_of_year+2)/153;
+    let day = day_of_year-(153*shifted_month+2)/5+1;
+    let month = if shifted_month < 10 { shifted_month+3 } else { shifted_month-9 };
+    let year = year_of_era+era*400+(month <= 2) as i64;
+
+    // zips can't be dated before 1980
+    if year < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let time = ((seconds_of_day/3600) << 11) | ((seconds_of_day%3600/60) << 5) | (seconds_of_day%60/2);
+    let date = (((year-1980).min(127) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    (time as u16, date)
+}
+
+/// Writes a zip one stored entry at a time, keeping only their central directory headers until the end.
+struct ZipWriter<W> {
+    writer: W,
+    offset: u64,
+    entries: u64,
+    central_directory: Vec<u8>,
+    dos_datetime: (u16, u16),
+}
+
+impl<W: AsyncWrite+Unpin> ZipWriter<W> {
+    fn new(writer: W) -> Self {
+        let unix_seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+        ZipWriter {
+            writer,
+            offset: 0,
+            entries: 0,
+            central_directory: Vec::new(),
+            dos_datetime: dos_datetime(unix_seconds),
+        }
+    }
+
+    async fn write_all(&mut self, bytes: &[u8]) -> Result<(), tokio::io::Error> {
+        self.writer.write_all(bytes).await?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Streams the `size` bytes of `file` into an entry at `path`, failing if it ends before that.
+    async fn write_entry<R: AsyncRead+Unpin>(&mut self, path: &str, size: u64, file: R) -> Result<(), tokio::io::Error> {
+        let local_header_offset = self.offset;
+        let zip64_sizes = needs_zip64_sizes(size);
+        let version = if zip64_sizes { ZIP64_VERSION } else { ZIP_VERSION };
+        let (time, date) = self.dos_datetime;
+
+        // with a data descriptor the local header leaves the CRC and sizes out, except that a zip64 extra field
+        // is what says the data descriptor has 64 bit sizes
+        let mut local_header = Vec::with_capacity((ZIP_LOCAL_HEADER_LENGTH+ZIP64_EXTRA_FIELD_HEADER_LENGTH+16) as usize+path.len());
+        local_header.extend_from_slice(&ZIP_LOCAL_HEADER_SIGNATURE.to_le_bytes());
+        local_header.extend_from_slice(&version.to_le_bytes());
+        local_header.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+        local_header.extend_from_slice(&0u16.to_le_bytes());
+        local_header.extend_from_slice(&time.to_le_bytes());
+        local_header.extend_from_slice(&date.to_le_bytes());
+        local_header.extend_from_slice(&0u32.to_le_bytes());
+        let local_sizes = if zip64_sizes { u32::MAX } else { 0 };
+        local_header.extend_from_slice(&local_sizes.to_le_bytes());
+        local_header.extend_from_slice(&local_sizes.to_le_bytes());
+        local_header.extend_from_slice(&(path.len() as u16).to_le_bytes());
+        local_header.extend_from_slice(&(if zip64_sizes { ZIP64_EXTRA_FIELD_HEADER_LENGTH as u16+16 } else { 0 }).to_le_bytes());
+        local_header.extend_from_slice(path.as_bytes());
+        if zip64_sizes {
+            local_header.extend_from_slice(&ZIP64_EXTRA_FIELD_ID.to_le_bytes());
+            local_header.extend_from_slice(&16u16.to_le_bytes());
+            local_header.extend_from_slice(&size.to_le_bytes());
+            local_header.extend_from_slice(&size.to_le_bytes());
+        }
+        self.write_all(&local_header).await?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let copied = tokio::io::copy(&mut InspectReader::new(file.take(size), |bytes: &[u8]| hasher.update(bytes)), &mut self.writer).await?;
+        self.offset += copied;
+        if copied != size {
+            return Err(tokio::io::Error::new(tokio::io::ErrorKind::UnexpectedEof, format!("{} ended after {} of its {} bytes", path, copied, size)));
+        }
+        let crc = hasher.finalize();
+
+        let mut data_descriptor = Vec::with_capacity(ZIP64_DATA_DESCRIPTOR_LENGTH as usize);
+        data_descriptor.extend_from_slice(&ZIP_DATA_DESCRIPTOR_SIGNATURE.to_le_bytes());
+        data_descriptor.extend_from_slice(&crc.to_le_bytes());
+        if zip64_sizes {
+            data_descriptor.extend_from_slice(&size.to_le_bytes());
+            data_descriptor.extend_from_slice(&size.to_le_bytes());
+        } else {
+            data_descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+            data_descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+        self.write_all(&data_descriptor).await?;
+
+        let extra_field = central_zip64_extra_field(size, local_header_offset);
+        let central_sizes = if zip64_sizes { u32::MAX } else { size as u32 };
+        let version = if extra_field.is_empty() { ZIP_VERSION } else { ZIP64_VERSION };
+        let central_directory = &mut self.central_directory;
+        central_directory.extend_from_slice(&ZIP_CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+        central_directory.extend_from_slice(&version.to_le_bytes());
+        central_directory.extend_from_slice(&version.to_le_bytes());
+        central_directory.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+        central_directory.extend_from_slice(&0u16.to_le_bytes());
+        central_directory.extend_from_slice(&time.to_le_bytes());
+        central_directory.extend_from_slice(&date.to_le_bytes());
+        central_directory.extend_from_slice(&crc.to_le_bytes());
+        central_directory.extend_from_slice(&central_sizes.to_le_bytes());
+        central_directory.extend_from_slice(&central_sizes.to_le_bytes());
+        central_directory.extend_from_slice(&(path.len() as u16).to_le_bytes());
+        central_directory.extend_from_slice(&(extra_field.len() as u16).to_le_bytes());
+        // comment length, disk number, internal and external attributes
+        central_directory.extend_from_slice(&[0; 10]);
+        central_directory.extend_from_slice(&(local_header_offset.min(ZIP64_THRESHOLD) as u32).to_le_bytes());
+        central_directory.extend_from_slice(path.as_bytes());
+        central_directory.extend_from_slice(&extra_field);
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Writes the central directory and what points to it.
+    async fn finish(mut self) -> Result<(), tokio::io::Error> {
+        let central_directory_offset = self.offset;
+        let central_directory = std::mem::take(&mut self.central_directory);
+        let central_directory_length = central_directory.len() as u64;
+        self.write_all(&central_directory).await?;
+
+        let mut end = Vec::with_capacity((ZIP64_END_OF_CENTRAL_DIRECTORY_LENGTH+ZIP_END_OF_CENTRAL_DIRECTORY_LENGTH) as usize);
+        if needs_zip64_end(self.entries, central_directory_offset, central_directory_length) {
+            let zip64_end_offset = self.offset;
+            end.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+            // the length of the rest of the record
+            end.extend_from_slice(&44u64.to_le_bytes());
+            end.extend_from_slice(&ZIP64_VERSION.to_le_bytes());
+            end.extend_from_slice(&ZIP64_VERSION.to_le_bytes());
+            // this disk and the one the central directory starts on
+            end.extend_from_slice(&[0; 8]);
+            end.extend_from_slice(&self.entries.to_le_bytes());
+            end.extend_from_slice(&self.entries.to_le_bytes());
+            end.extend_from_slice(&central_directory_length.to_le_bytes());
+            end.extend_from_slice(&central_directory_offset.to_le_bytes());
+
+            end.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes());
+            end.extend_from_slice(&zip64_end_offset.to_le_bytes());
+            end.extend_from_slice(&1u32.to_le_bytes());
+        }
+        end.extend_from_slice(&ZIP_END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        end.extend_from_slice(&[0; 4]);
+        let entries = self.entries.min(ZIP64_ENTRIES_THRESHOLD) as u16;
+        end.extend_from_slice(&entries.to_le_bytes());
+        end.extend_from_slice(&entries.to_le_bytes());
+        end.extend_from_slice(&(central_directory_length.min(ZIP64_THRESHOLD) as u32).to_le_bytes());
+        end.extend_from_slice(&(central_directory_offset.min(ZIP64_THRESHOLD) as u32).to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.write_all(&end).await?;
+        self.writer.flush().await
+    }
+}
+
+/// Writes a zip of every exported file followed by `metadata.json`, streaming each file from the store
+/// so only one chunk of it is in memory at a time.
+pub async fn write_media_zip<W: AsyncWrite+Unpin>(writer: W, transactions: &RwLock<IloveuTransactionsStore>, exported_media: &[ExportedMedia]) -> Result<(), tokio::io::Error> {
+    let mut zip_writer = ZipWriter::new(writer);
+    // photos and videos are already compressed, so every entry is stored as is, which also keeps the length of the
+    // archive known up front
+    for media in exported_media {
+        let file = transactions.read().await.open_file(&media.file_reference).await?;
+        zip_writer.write_entry(&media.metadata.path, media.file_reference.size(), file).await?;
+    }
+    let metadata_json = metadata_json(exported_media)?;
+    zip_writer.write_entry(METADATA_PATH, metadata_json.len() as u64, metadata_json.as_slice()).await?;
+    zip_writer.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_zip::base::read1::seek::ZipArchiveReader;
+    use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+    use crate::types::BlobReference;
+
+    fn exported_media(path: &str, size: u64) -> ExportedMedia {
+        ExportedMedia {
+            file_reference: FileReference::Blob(BlobReference {
+                hash: [0; 32],
+                size,
+            }),
+            metadata: ExportedMetadata {
+                id: 0,
+                path: path.to_string(),
+                title: String::new(),
+                description: String::new(),
+                tags: Vec::new(),
+                taken_datetime: 0.0,
+                media_type: MediaType::Picture,
+                filename: path.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn zip_length_counts_every_header() {
+        let exported = [exported_media("a.jpg", 1000), exported_media("bb.mp4", 0)];
+        let metadata_length = metadata_json(&exported).unwrap().len() as u64;
+        let expected = (30+5+1000+16+46+5)+(30+6+16+46+6)+(30+13+metadata_length+16+46+13)+22;
+        assert_eq!(media_zip_length(&exported).unwrap(), expected);
+    }
+
+    #[test]
+    fn zip64_records_are_only_used_where_needed() {
+        let big = 5*1024*1024*1024;
+        // the sizes of the big file and the offset of the one after it
+        let expected = (30+5+20+big+24+46+5+20)+(30+5+1+16+46+5+12)+76+22;
+        assert_eq!(zip_length([("a.mp4", big), ("b.jpg", 1)].into_iter()), expected);
+        assert_eq!(zip_length([("b.jpg", 1)].into_iter()), 30+5+1+16+46+5+22);
+        assert!(central_zip64_extra_field(ZIP64_THRESHOLD-1, ZIP64_THRESHOLD-1).is_empty());
+    }
+
+    #[test]
+    fn dos_datetimes() {
+        assert_eq!(dos_datetime(0), (0, 1 << 5 | 1));
+        // 2024-02-29 13:45:30 UTC
+        assert_eq!(dos_datetime(1709214330), (13 << 11 | 45 << 5 | 15, 44 << 9 | 2 << 5 | 29));
+    }
+
+    #[tokio::test]
+    async fn zips_with_too_many_entries_for_zip32_can_be_read_back() {
+        let paths: Vec<String> = (0..ZIP64_ENTRIES_THRESHOLD+1).map(|i| format!("{}.jpg", i)).collect();
+        let mut archive = Vec::new();
+        let mut zip_writer = ZipWriter::new(&mut archive);
+        for path in &paths {
+            zip_writer.write_entry(path, path.len() as u64, path.as_bytes()).await.unwrap();
+        }
+        zip_writer.finish().await.unwrap();
+        assert_eq!(archive.len() as u64, zip_length(paths.iter().map(|path| (path.as_str(), path.len() as u64))));
+
+        let mut zip_reader = ZipArchiveReader::open(std::io::Cursor::new(archive).compat()).await.unwrap();
+        assert_eq!(zip_reader.cdrs().len() as u64, ZIP64_ENTRIES_THRESHOLD+1);
+        let last_path = paths.last().unwrap();
+        let index = zip_reader.find(last_path.as_bytes()).unwrap().next().unwrap();
+        let mut contents = Vec::new();
+        zip_reader.file(index).await.unwrap().compat().read_to_end(&mut contents).await.unwrap();
+        assert_eq!(contents, last_path.as_bytes());
+    }
+}
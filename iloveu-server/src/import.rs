@@ -0,0 +1,236 @@
+use std::{collections::HashSet, path::{Path, PathBuf}, time::UNIX_EPOCH};
+
+use async_zip::base::read1::seek::ZipArchiveReader;
+use iloveu_lib::transaction::MediaMetadata;
+use log::info;
+use serde::Serialize;
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncSeek, BufReader}, sync::RwLock};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+use crate::db::{IloveuCache, IloveuTransactionsStore, now_datetime};
+use crate::exif_metadata::upload_exif_metadata;
+use crate::video_info::upload_video_info;
+use crate::export::ExportedMetadata;
+use crate::types::{BlobHash, CachedMedia, FileReference, MediaType};
+
+const PICTURE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "heic", "heif", "avif", "bmp", "tif", "tiff"];
+const VIDEO_EXTENSIONS: &[&str] = &["mp4", "m4v", "mov", "webm", "mkv", "avi", "3gp", "mts", "m2ts"];
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: u64,
+    /// Files already in the store, so importing the same directory or archive twice is harmless.
+    pub duplicates: u64,
+    /// Files that don't look like photos or videos, never counted for archives.
+    pub unsupported: u64,
+    pub added_tags: u64,
+}
+
+/// Guesses whether a file is a photo or a video from its extension.
+pub fn media_type_for(path: &Path) -> Option<MediaType> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    if PICTURE_EXTENSIONS.contains(&extension.as_str()) {
+        Some(MediaType::Picture)
+    } else if VIDEO_EXTENSIONS.contains(&extension.as_str()) {
+        Some(MediaType::Video)
+    } else {
+        None
+    }
+}
+
+/// When a file was taken in milliseconds since the unix epoch, going by its modification time.
+pub async fn taken_datetime_for(path: &Path) -> Result<f64, tokio::io::Error> {
+    let modified = tokio::fs::metadata(path).await?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH).map(|duration| duration.as_millis() as f64).unwrap_or_else(|_| now_datetime()))
+}
+
+/// Every media in the cache, trashed or not.
+fn all_media(cache: &IloveuCache) -> impl Iterator<Item = &CachedMedia> {
+    cache.get_media().values()
+        .chain(cache.get_trash().values().map(|trashed_media| &trashed_media.media))
+}
+
+fn blob_hash(cached_media: &CachedMedia) -> Option<BlobHash> {
+    match cached_media.file_reference {
+        FileReference::Blob(blob_reference) => Some(blob_reference.hash),
+        FileReference::Inline(_) => None,
+    }
+}
+
+/// Finds a tag by name regardless of case, adding it if there is none.
+async fn find_or_add_tag(transactions: &mut IloveuTransactionsStore, cache: &mut IloveuCache, name: &str, report: &mut ImportReport) -> Result<u64, tokio::io::Error> {
+    if let Some(tag_id) = cache.find_tag(name) {
+        return Ok(tag_id);
+    }
+    transactions.add_tag(name).await?;
+    report.added_tags += 1;
+    Ok(cache.add_tag(name.to_string()))
+}
+
+/// Lists every file under `root`, sorted so imports happen in a stable order, skipping hidden files and folders.
+async fn walk_files(root: &Path) -> Result<Vec<PathBuf>, tokio::io::Error> {
+    let mut files = Vec::new();
+    let mut directories = vec![root.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        let mut entries = tokio::fs::read_dir(&directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            // follows symlinks to files but never to folders, which could loop
+            let entry_type = entry.file_type().await?;
+            if entry_type.is_dir() {
+                directories.push(entry.path());
+            } else if entry_type.is_file() || tokio::fs::metadata(entry.path()).await?.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Adds every photo and video under `root` to the store and the cache. With `folder_tags`, the names of the folders
+/// between `root` and each file become its tags, creating any that don't exist yet.
+pub async fn import_directory(transactions: &mut IloveuTransactionsStore, cache: &mut IloveuCache, root: &Path, folder_tags: bool) -> Result<ImportReport, tokio::io::Error> {
+    let mut report = ImportReport::default();
+    let mut stored_hashes: HashSet<BlobHash> = all_media(cache).filter_map(blob_hash).collect();
+
+    for path in walk_files(root).await? {
+        let media_type = match media_type_for(&path) {
+            Some(media_type) => media_type,
+            None => {
+                report.unsupported += 1;
+                continue;
+            }
+        };
+
+        let upload = transactions.uploads().write(tokio::fs::File::open(&path).await?).await?;
+        if !stored_hashes.insert(upload.blob_reference().hash) {
+            upload.discard().await?;
+            report.duplicates += 1;
+            continue;
+        }
+
+        let exif = upload_exif_metadata(&transactions.uploads(), &upload, media_type).await?;
+        let video_info = upload_video_info(&transactions.uploads(), &upload, media_type).await?;
+        let embedded_datetime = exif.as_ref().and_then(|exif| exif.taken_datetime)
+            .or(video_info.as_ref().and_then(|video_info| video_info.created_datetime));
+        let taken_datetime = match embedded_datetime {
+            Some(taken_datetime) => taken_datetime,
+            None => taken_datetime_for(&path).await?,
+        };
+
+        let mut tags_vec = Vec::new();
+        if folder_tags {
+            let folders = path.parent().and_then(|parent| parent.strip_prefix(root).ok()).into_iter().flat_map(|relative| relative.iter());
+            for folder in folders {
+                let tag_id = find_or_add_tag(transactions, cache, &folder.to_string_lossy(), &mut report).await?;
+                if !tags_vec.contains(&tag_id) {
+                    tags_vec.push(tag_id);
+                }
+            }
+        }
+
+        let metadata = MediaMetadata {
+            title: path.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+            description: String::new(),
+            tags_vec,
+            taken_datetime,
+            media_type,
+            filename: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+        };
+        let blob_reference = transactions.add_media(&metadata, upload).await?;
+        let media_id = cache.add_media(CachedMedia::from_metadata(metadata, FileReference::Blob(blob_reference)));
+        if let Some(exif) = exif {
+            transactions.set_media_exif(media_id, &exif).await?;
+            cache.set_media_exif(media_id, exif);
+        }
+        if let Some(video_info) = video_info {
+            transactions.set_video_info(media_id, &video_info).await?;
+            cache.set_video_info(media_id, video_info);
+        }
+        info!("Imported {}", path.display());
+        report.imported += 1;
+    }
+
+    Ok(report)
+}
+
+/// Adds everything in a zip written by the media files export back into the store and the cache, recreating
+/// tags by name. Media already in the store is skipped, so an archive can be merged into a store it came from.
+/// Each entry is decompressed, hashed and looked into without holding either lock, which are only taken to add it.
+pub async fn import_archive<R: AsyncRead+AsyncSeek+Unpin>(transactions: &RwLock<IloveuTransactionsStore>, cache: &RwLock<IloveuCache>, archive: R) -> Result<ImportReport, tokio::io::Error> {
+    let mut report = ImportReport::default();
+    // the same bytes can be several media with their own metadata, so only the file itself has to match too
+    let mut stored_media: HashSet<(BlobHash, String, u64)> = all_media(&*cache.read().await)
+        .filter_map(|cached_media| Some((blob_hash(cached_media)?, cached_media.filename.clone(), cached_media.taken_datetime.to_bits())))
+        .collect();
+    let uploads = transactions.read().await.uploads();
+
+    // entries are checked against their CRC as they are read, so a corrupt one fails its read
+    let mut zip_reader = ZipArchiveReader::open(BufReader::new(archive).compat()).await.map_err(invalid_archive)?;
+    let metadata_index = entry_index(&zip_reader, "metadata.json")?;
+    let mut metadata_json = Vec::new();
+    zip_reader.file(metadata_index).await.map_err(invalid_archive)?.compat()
+        .read_to_end(&mut metadata_json).await?;
+    let exported_metadata: Vec<ExportedMetadata> = serde_json::from_slice(&metadata_json)?;
+
+    for exported in exported_metadata {
+        let index = entry_index(&zip_reader, &exported.path)?;
+        let entry_reader = zip_reader.file(index).await.map_err(invalid_archive)?.compat();
+        let upload = uploads.write(entry_reader).await.map_err(|err| match err.kind() {
+            tokio::io::ErrorKind::InvalidData => tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("{} in the archive is corrupt: {}", exported.path, err)),
+            _ => err,
+        })?;
+        if !stored_media.insert((upload.blob_reference().hash, exported.filename.clone(), exported.taken_datetime.to_bits())) {
+            upload.discard().await?;
+            report.duplicates += 1;
+            continue;
+        }
+        let exif = upload_exif_metadata(&uploads, &upload, exported.media_type).await?;
+        let video_info = upload_video_info(&uploads, &upload, exported.media_type).await?;
+
+        let mut cache = cache.write().await;
+        let mut transactions = transactions.write().await;
+        let mut tags_vec = Vec::new();
+        for tag_name in &exported.tags {
+            let tag_id = find_or_add_tag(&mut transactions, &mut cache, tag_name, &mut report).await?;
+            if !tags_vec.contains(&tag_id) {
+                tags_vec.push(tag_id);
+            }
+        }
+
+        let metadata = MediaMetadata {
+            title: exported.title,
+            description: exported.description,
+            tags_vec,
+            taken_datetime: exported.taken_datetime,
+            media_type: exported.media_type,
+            filename: exported.filename,
+        };
+        let blob_reference = transactions.add_media(&metadata, upload).await?;
+        let media_id = cache.add_media(CachedMedia::from_metadata(metadata, FileReference::Blob(blob_reference)));
+        if let Some(exif) = exif {
+            transactions.set_media_exif(media_id, &exif).await?;
+            cache.set_media_exif(media_id, exif);
+        }
+        if let Some(video_info) = video_info {
+            transactions.set_video_info(media_id, &video_info).await?;
+            cache.set_video_info(media_id, video_info);
+        }
+        info!("Imported {} from the archive", exported.path);
+        report.imported += 1;
+    }
+
+    Ok(report)
+}
+
+fn entry_index<R>(zip_reader: &ZipArchiveReader<R>, path: &str) -> Result<usize, tokio::io::Error> {
+    zip_reader.find(path.as_bytes()).map_err(invalid_archive)?.next()
+        .ok_or_else(|| tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("archive is missing {}", path)))
+}
+
+fn invalid_archive(err: async_zip::error::ZipError) -> tokio::io::Error {
+    tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("invalid zip archive: {}", err))
+}
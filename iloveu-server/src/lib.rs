@@ -1,3 +1,14 @@
+pub mod crypto;
 pub mod db;
+pub mod exif_metadata;
+pub mod export;
+pub mod import;
+pub mod media_file;
+pub mod migrations;
+pub mod mime;
+pub mod replay;
 pub mod session;
-pub mod types;
\ No newline at end of file
+pub mod types;
+pub mod variants;
+pub mod video_info;
+pub mod verify;
\ No newline at end of file
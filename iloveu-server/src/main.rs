@@ -1,17 +1,32 @@
-use std::{sync::Arc, pin::Pin, task::Poll};
+use std::{sync::Arc, pin::Pin, task::Poll, collections::HashMap, path::Path, time::{Duration, Instant}};
 
 use actix_cors::Cors;
 use actix_multipart::Multipart;
-use actix_web::{HttpServer, App, web::{self, Bytes}, get, post, HttpRequest, HttpResponse, http::header::{self, HeaderValue, ContentEncoding}};
+use actix_web::{HttpServer, App, web::{self, Bytes}, get, post, HttpRequest, HttpResponse, HttpMessage, http::{StatusCode, header::{self, HeaderValue, ContentEncoding, ContentType, Encoding, EntityTag, CacheDirective}}};
 use base64::Engine;
-use clap::Parser;
-use iloveu_server::{db::{IloveuTransactionsStore, IloveuCache}, session::{SessionManager, HashedSessionID}, types::{MediaType, CachedMedia}};
-use tokio::{sync::RwLock, io::{AsyncWriteExt, AsyncSeekExt, AsyncReadExt}, fs::File};
-use futures_util::{TryStreamExt};
-use tokio_util::io::ReaderStream;
+use clap::{Parser, Subcommand};
+use iloveu_lib::transaction::MediaMetadata;
+use iloveu_server::{db::{IloveuTransactionsStore, IloveuCache, StoreOptions, now_datetime}, crypto::{MediaReader, is_encrypted}, migrations::{pending_migrations_at, LATEST_VERSION}, replay::ReplayMode, session::{SessionManager, HashedSessionID, DownloadToken}, export::{plan_export, media_zip_length, write_media_zip}, media_file::{MAX_RANGES, ByteRangesBody, if_range_matches, satisfiable_ranges, served_filename, content_disposition}, mime::mime_type_of, exif_metadata::upload_exif_metadata, video_info::upload_video_info, import::{import_directory, import_archive}, verify::verify_store, variants::{needs_variants, generate_media_variants}, types::{MediaType, CachedMedia, MediaUpdate, MediaQuery, MediaFileQuery, SearchQuery, DownloadQuery, TrashedMedia, FileReference}};
+use tokio::{sync::{RwLock, mpsc}, io::AsyncWriteExt};
+use log::error;
+use futures_util::{StreamExt, TryStreamExt};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Whether the client already has the version of a resource tagged `etag`, so it can be sent a 304 instead.
+/// Every change to the cache is appended to the log while holding the cache's write lock, so listings must read
+/// the log's tag while holding its read lock for the tag to never be newer than what it is sent with.
+fn is_fresh(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(etags)) => etags.iter().any(|client_etag| client_etag.weak_eq(etag)),
+        None => false,
+    }
+}
 
 struct Config {
     password: Arc<String>,
+    /// How long deleted media can still be restored, in milliseconds.
+    trash_retention: f64,
 }
 
 #[derive(Debug, Clone)]
@@ -23,6 +38,24 @@ struct ActixCache(Arc<RwLock<IloveuCache>>);
 #[derive(Debug, Clone)]
 struct ActixSessionManager(Arc<RwLock<SessionManager>>);
 
+/// Ids of media to generate missing variants for in the background.
+#[derive(Debug, Clone)]
+struct ActixVariantQueue(mpsc::UnboundedSender<u64>);
+
+impl ActixVariantQueue {
+    /// Queues every live picture that is missing variants, newest first.
+    fn queue_missing(&self, cache: &IloveuCache) {
+        let mut media_ids: Vec<u64> = cache.get_media().iter()
+            .filter(|(_, cached_media)| needs_variants(cached_media))
+            .map(|(media_id, _)| *media_id)
+            .collect();
+        media_ids.sort_unstable_by(|a, b| b.cmp(a));
+        for media_id in media_ids {
+            let _ = self.0.send(media_id);
+        }
+    }
+}
+
 #[post("/login")]
 async fn login(config: web::Data<Config>, sessions: web::Data<ActixSessionManager>, password: String) -> Result<Vec<u8>, actix_web::Error> {
     if password == *config.password {
@@ -40,8 +73,13 @@ async fn add_tag(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cac
             actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
         })?))
     {
+        let mut cache = cache.0.write().await;
+        if cache.find_tag(&name).is_some() {
+            return Err(actix_web::error::ErrorConflict("tag already exists"));
+        }
+
         transactions.0.write().await.add_tag(&name).await?;
-        let id = cache.0.write().await.add_tag(name);
+        let id = cache.add_tag(name);
         Ok(id.to_be_bytes().to_vec())
     } else {
         Err(actix_web::error::ErrorUnauthorized("invalid session"))
@@ -49,21 +87,103 @@ async fn add_tag(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cac
 }
 
 #[get("/tags")]
-async fn tags(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>) -> Result<String, actix_web::Error> {
+async fn tags(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>) -> Result<HttpResponse, actix_web::Error> {
+    let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
+    if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
+        })?))
+    {
+        let cache = cache.0.read().await;
+        let etag = EntityTag::new_strong(transactions.0.read().await.log_tag().await?);
+        if is_fresh(&req, &etag) {
+            return Ok(HttpResponse::NotModified().insert_header(header::ETag(etag)).finish())
+        }
+        Ok(HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .insert_header(header::ETag(etag))
+            .insert_header(header::CacheControl(vec![CacheDirective::Private, CacheDirective::NoCache]))
+            .body(serde_json::to_string(cache.get_tags())?))
+    } else {
+        Err(actix_web::error::ErrorUnauthorized("invalid session"))
+    }
+}
+
+#[post("/rename_tag/{tag_id}")]
+async fn rename_tag(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, tag_id: web::Path<u64>, name: String) -> Result<Vec<u8>, actix_web::Error> {
     let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
     if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
         &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
             actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
         })?))
     {
-        Ok(serde_json::to_string(cache.0.read().await.get_tags())?)
+        let mut cache = cache.0.write().await;
+        if !cache.get_tags().contains_key(&tag_id) {
+            return Err(actix_web::error::ErrorNotFound("tag not found"));
+        }
+        if cache.find_tag(&name).is_some_and(|existing_tag_id| existing_tag_id != *tag_id) {
+            return Err(actix_web::error::ErrorConflict("tag already exists"));
+        }
+
+        transactions.0.write().await.rename_tag(*tag_id, &name).await?;
+        cache.rename_tag(*tag_id, name);
+
+        Ok(Vec::new())
+    } else {
+        Err(actix_web::error::ErrorUnauthorized("invalid session"))
+    }
+}
+
+#[post("/merge_tag/{tag_id}")]
+async fn merge_tag(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, tag_id: web::Path<u64>, into_tag_id: String) -> Result<Vec<u8>, actix_web::Error> {
+    let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
+    if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
+        })?))
+    {
+        let into_tag_id: u64 = into_tag_id.trim().parse().map_err(|e| actix_web::error::ErrorBadRequest(format!("invalid tag id to merge into: {}", e)))?;
+        let mut cache = cache.0.write().await;
+        if !cache.get_tags().contains_key(&tag_id) || !cache.get_tags().contains_key(&into_tag_id) {
+            return Err(actix_web::error::ErrorNotFound("tag not found"));
+        }
+        if *tag_id == into_tag_id {
+            return Err(actix_web::error::ErrorBadRequest("cannot merge a tag into itself"));
+        }
+
+        transactions.0.write().await.merge_tag(*tag_id, into_tag_id).await?;
+        cache.merge_tag(*tag_id, into_tag_id);
+
+        Ok(Vec::new())
+    } else {
+        Err(actix_web::error::ErrorUnauthorized("invalid session"))
+    }
+}
+
+#[post("/delete_tag/{tag_id}")]
+async fn delete_tag(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, tag_id: web::Path<u64>) -> Result<Vec<u8>, actix_web::Error> {
+    let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
+    if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
+        })?))
+    {
+        let mut cache = cache.0.write().await;
+        if !cache.get_tags().contains_key(&tag_id) {
+            return Err(actix_web::error::ErrorNotFound("tag not found"));
+        }
+
+        transactions.0.write().await.delete_tag(*tag_id).await?;
+        cache.delete_tag(*tag_id);
+
+        Ok(Vec::new())
     } else {
         Err(actix_web::error::ErrorUnauthorized("invalid session"))
     }
 }
 
 #[post("/add_media")]
-async fn add_media(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, mut multipart: Multipart) -> Result<Vec<u8>, actix_web::Error> {
+async fn add_media(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, variant_queue: web::Data<ActixVariantQueue>, mut multipart: Multipart) -> Result<Vec<u8>, actix_web::Error> {
     let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
     if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
         &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
@@ -99,7 +219,12 @@ async fn add_media(sessions: web::Data<ActixSessionManager>, req: HttpRequest, c
         while let Some(chunk) = taken_datetime_field.try_next().await? {
             taken_datetime_bytes.write_all(&chunk).await?;
         }
-        let taken_datetime: f64 = serde_json::from_slice(taken_datetime_bytes.as_slice())?;
+        // left empty when the file itself should say when it was taken
+        let taken_datetime: Option<f64> = if taken_datetime_bytes.trim_ascii().is_empty() {
+            None
+        } else {
+            Some(serde_json::from_slice(taken_datetime_bytes.as_slice())?)
+        };
         drop(taken_datetime_field);
 
         let mut media_type_field = multipart.try_next().await?.ok_or(actix_web::error::ErrorBadRequest("Missing taken_datetime"))?;
@@ -116,22 +241,47 @@ async fn add_media(sessions: web::Data<ActixSessionManager>, req: HttpRequest, c
         
         let mut file_field = multipart.try_next().await?.ok_or(actix_web::error::ErrorBadRequest("Missing file"))?;
         let filename = file_field.content_disposition().get_filename().ok_or(actix_web::error::ErrorBadRequest("Missing filename on file"))?.to_string();
-        let mut file_bytes = Vec::new();
-        while let Some(chunk) = file_field.try_next().await? {
-            file_bytes.write_all(&chunk).await?;
-        }
+        let uploads = transactions.0.read().await.uploads();
+        let upload = uploads.write(StreamReader::new((&mut file_field).map_err(|e| std::io::Error::other(e.to_string())))).await?;
         drop(file_field);
 
-        let file_reference = transactions.0.write().await.add_media(&title, &description, &tags_vec, taken_datetime, media_type, &filename, &file_bytes).await?;
-        let media_id = cache.0.write().await.add_media(CachedMedia {
+        // returning early anywhere from here on drops the upload, which deletes its file
+        let exif = upload_exif_metadata(&uploads, &upload, media_type).await?;
+        let video_info = upload_video_info(&uploads, &upload, media_type).await?;
+        let embedded_datetime = exif.as_ref().and_then(|exif| exif.taken_datetime)
+            .or(video_info.as_ref().and_then(|video_info| video_info.created_datetime));
+        let taken_datetime = match taken_datetime.or(embedded_datetime) {
+            Some(taken_datetime) => taken_datetime,
+            None => {
+                upload.discard().await?;
+                return Err(actix_web::error::ErrorBadRequest("Missing taken_datetime and the file doesn't say when it was taken"));
+            }
+        };
+
+        let metadata = MediaMetadata {
             title,
             description,
             tags_vec,
             taken_datetime,
             media_type,
             filename,
-            file_reference,
-        });
+        };
+        let mut cache = cache.0.write().await;
+        let mut transactions = transactions.0.write().await;
+        let blob_reference = transactions.add_media(&metadata, upload).await?;
+        let media_id = cache.add_media(CachedMedia::from_metadata(metadata, FileReference::Blob(blob_reference)));
+        if let Some(exif) = exif {
+            transactions.set_media_exif(media_id, &exif).await?;
+            cache.set_media_exif(media_id, exif);
+        }
+        if let Some(video_info) = video_info {
+            transactions.set_video_info(media_id, &video_info).await?;
+            cache.set_video_info(media_id, video_info);
+        }
+        drop(transactions);
+        if media_type == MediaType::Picture {
+            let _ = variant_queue.0.send(media_id);
+        }
 
         Ok(media_id.to_be_bytes().to_vec())
     } else {
@@ -139,22 +289,143 @@ async fn add_media(sessions: web::Data<ActixSessionManager>, req: HttpRequest, c
     }
 }
 
+/// Lists media as `[media_id, media]` pairs, filtered and ordered by the query parameters of [`MediaQuery`].
 #[get("/media")]
-async fn media(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>) -> Result<String, actix_web::Error> {
+async fn media(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, query: web::Query<MediaQuery>) -> Result<HttpResponse, actix_web::Error> {
     let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
     if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
         &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
             actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
         })?))
     {
-        Ok(serde_json::to_string(cache.0.read().await.get_media())?)
+        let cache = cache.0.read().await;
+        let etag = EntityTag::new_strong(transactions.0.read().await.log_tag().await?);
+        if is_fresh(&req, &etag) {
+            return Ok(HttpResponse::NotModified().insert_header(header::ETag(etag)).finish())
+        }
+        Ok(HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .insert_header(header::ETag(etag))
+            .insert_header(header::CacheControl(vec![CacheDirective::Private, CacheDirective::NoCache]))
+            .body(serde_json::to_string(&cache.query_media(&query))?))
     } else {
         Err(actix_web::error::ErrorUnauthorized("invalid session"))
     }
 }
 
+/// Lists the ids of media matching the words of `q`, best match first.
+#[get("/search")]
+async fn search(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, query: web::Query<SearchQuery>) -> Result<String, actix_web::Error> {
+    let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
+    if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
+        })?))
+    {
+        Ok(serde_json::to_string(&cache.0.read().await.search_media(&query.q))?)
+    } else {
+        Err(actix_web::error::ErrorUnauthorized("invalid session"))
+    }
+}
+
+#[post("/media/{media_id}")]
+async fn update_media(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, media_id: web::Path<u64>, update: web::Json<MediaUpdate>) -> Result<String, actix_web::Error> {
+    let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
+    if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
+        })?))
+    {
+        let update = update.into_inner();
+        let mut cache = cache.0.write().await;
+        let cached_media = cache.get_media().get(&media_id).ok_or(actix_web::error::ErrorNotFound("cached media not found"))?;
+
+        let title = update.title.unwrap_or_else(|| cached_media.title.clone());
+        let description = update.description.unwrap_or_else(|| cached_media.description.clone());
+        let tags_vec = update.tags_vec.unwrap_or_else(|| cached_media.tags_vec.clone());
+        let taken_datetime = update.taken_datetime.unwrap_or(cached_media.taken_datetime);
+
+        if let Some(tag_id) = tags_vec.iter().find(|tag_id| !cache.get_tags().contains_key(tag_id)) {
+            return Err(actix_web::error::ErrorBadRequest(format!("unknown tag {}", tag_id)));
+        }
+
+        transactions.0.write().await.update_media(*media_id, &title, &description, &tags_vec, taken_datetime).await?;
+        cache.update_media(*media_id, title, description, tags_vec, taken_datetime);
+
+        Ok(serde_json::to_string(&cache.get_media()[&media_id])?)
+    } else {
+        Err(actix_web::error::ErrorUnauthorized("invalid session"))
+    }
+}
+
+#[post("/delete_media/{media_id}")]
+async fn delete_media(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, media_id: web::Path<u64>) -> Result<Vec<u8>, actix_web::Error> {
+    let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
+    if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
+        })?))
+    {
+        let mut cache = cache.0.write().await;
+        if !cache.get_media().contains_key(&media_id) {
+            return Err(actix_web::error::ErrorNotFound("cached media not found"));
+        }
+
+        let deleted_datetime = now_datetime();
+        transactions.0.write().await.delete_media(*media_id, deleted_datetime).await?;
+        cache.delete_media(*media_id, deleted_datetime);
+
+        Ok(Vec::new())
+    } else {
+        Err(actix_web::error::ErrorUnauthorized("invalid session"))
+    }
+}
+
+#[post("/restore_media/{media_id}")]
+async fn restore_media(config: web::Data<Config>, sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, media_id: web::Path<u64>) -> Result<Vec<u8>, actix_web::Error> {
+    let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
+    if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
+        })?))
+    {
+        let mut cache = cache.0.write().await;
+        let trashed_media = cache.get_trash().get(&media_id).ok_or(actix_web::error::ErrorNotFound("trashed media not found"))?;
+        if trashed_media.deleted_datetime+config.trash_retention < now_datetime() {
+            return Err(actix_web::error::ErrorGone("trashed media is past the retention window"));
+        }
+
+        transactions.0.write().await.restore_media(*media_id).await?;
+        cache.restore_media(*media_id);
+
+        Ok(Vec::new())
+    } else {
+        Err(actix_web::error::ErrorUnauthorized("invalid session"))
+    }
+}
+
+#[get("/trash")]
+async fn trash(config: web::Data<Config>, sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>) -> Result<String, actix_web::Error> {
+    let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
+    if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
+        })?))
+    {
+        let oldest_restorable = now_datetime()-config.trash_retention;
+        let cache = cache.0.read().await;
+        let restorable: HashMap<&u64, &TrashedMedia> = cache.get_trash().iter()
+            .filter(|(_, trashed_media)| trashed_media.deleted_datetime >= oldest_restorable)
+            .collect();
+        Ok(serde_json::to_string(&restorable)?)
+    } else {
+        Err(actix_web::error::ErrorUnauthorized("invalid session"))
+    }
+}
+
+/// Sends a media file, or a smaller variant of it with `?size=thumb` or `?size=medium`.
 #[get("/media_file/{media_id}")]
-async fn media_file(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, media_id: web::Path<u64>) -> HttpResponse {
+async fn media_file(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, media_id: web::Path<u64>, query: web::Query<MediaFileQuery>) -> HttpResponse {
     let authorization_header_value = match req.headers().get("AUTHORIZATION") {
         Some(value) => value,
         None => return actix_web::error::ErrorBadRequest("missing AUTHORIZATION header").into()
@@ -172,10 +443,24 @@ async fn media_file(sessions: web::Data<ActixSessionManager>, req: HttpRequest,
             None => return actix_web::error::ErrorNotFound("cached media not found").into()
         };
 
+        let (file_reference, settled) = cached_media.file_for_size(query.size);
+        let size = file_reference.size();
+
+        /// Streams exactly `size` bytes of a media file, which may be followed by bytes that aren't part of it.
         struct FileStream {
             offset: usize,
             size: usize,
-            transactions: ReaderStream<File>,
+            transactions: ReaderStream<MediaReader>,
+        }
+
+        impl FileStream {
+            fn new(file: MediaReader, size: u64) -> Self {
+                FileStream {
+                    offset: 0,
+                    size: size as usize,
+                    transactions: ReaderStream::new(file),
+                }
+            }
         }
 
         impl futures_util::Stream for FileStream {
@@ -187,12 +472,17 @@ async fn media_file(sessions: web::Data<ActixSessionManager>, req: HttpRequest,
                 }
                 match Pin::new(&mut self.transactions).poll_next(cx) {
                     Poll::Pending => Poll::Pending,
-                    Poll::Ready(None) => Poll::Ready(None),
+                    Poll::Ready(None) => {
+                        // the response already promised `size` bytes, so it must fail rather than look complete
+                        self.offset = self.size;
+                        Poll::Ready(Some(Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "media file ended early"))))
+                    },
                     Poll::Ready(Some(bytes_result)) => match bytes_result {
                         Ok(bytes) => {
-                            if self.offset+bytes.len() > self.size {
+                            let remaining = self.size-self.offset;
+                            if bytes.len() > remaining {
                                 self.offset = self.size;
-                                Poll::Ready(Some(Ok(Bytes::copy_from_slice(&bytes[0..(self.offset+bytes.len()-self.size)]))))
+                                Poll::Ready(Some(Ok(bytes.slice(..remaining))))
                             } else {
                                 self.offset += bytes.len();
                                 Poll::Ready(Some(Ok(bytes)))
@@ -204,32 +494,114 @@ async fn media_file(sessions: web::Data<ActixSessionManager>, req: HttpRequest,
             }
 
             fn size_hint(&self) -> (usize, Option<usize>) {
-                (self.size-self.offset, Some(self.size-self.offset))
+                (0, Some(self.size-self.offset))
             }
         }
 
-        let stream = match transactions.0.read().await.get_transactions_raw().await {
-            Ok(mut transactions) => {
-                if let Err(err) = transactions.seek(std::io::SeekFrom::Start(cached_media.file_reference.offset)).await {
-                    return actix_web::error::ErrorInternalServerError(format!("failed to seek to media position: {}", err)).into()
-                }
-                FileStream {
-                    offset: 0,
-                    size: cached_media.file_reference.size as usize,
-                    transactions: ReaderStream::new(transactions),
-                }
-                // let mut bytes = (0..cached_media.file_reference.size).map(|u| 0u8).collect::<Vec<u8>>();
-                // transactions.read_exact(bytes.as_mut_slice()).await.unwrap();
-                // bytes
-            },
-            Err(err) => return actix_web::error::ErrorInternalServerError(format!("failed to read transactions: {}", err)).into()
+        let stored_file = match transactions.0.read().await.open_stored_file(&file_reference).await {
+            Ok(stored_file) => stored_file,
+            Err(err) => return actix_web::error::ErrorInternalServerError(format!("failed to open media file: {}", err)).into()
         };
-        HttpResponse::Ok()
-            .insert_header((header::CONTENT_DISPOSITION, format!("inline; filename=\"{}\"", cached_media.filename)))
-            .streaming(stream)
-            .into()
+        let file_etag = EntityTag::new_strong(transactions.0.read().await.file_tag(&file_reference));
+        let range = if if_range_matches(req.get_header::<header::IfRange>().as_ref(), &file_etag) {
+            req.get_header::<header::Range>()
+        } else {
+            None
+        };
+        // compressed files are passed on as they are to clients that can decompress them themselves,
+        // though ranges are always of the file itself
+        let send_compressed = stored_file.compressed && range.is_none() && req.get_header::<header::AcceptEncoding>()
+            .and_then(|accept_encoding| accept_encoding.negotiate([Encoding::zstd(), Encoding::identity()].iter())) == Some(Encoding::zstd());
+        let etag = if send_compressed {
+            EntityTag::new_strong(format!("{}-zstd", file_etag.tag()))
+        } else {
+            file_etag
+        };
+
+        let mut response = HttpResponse::Ok();
+        response
+            .insert_header((header::ACCEPT_RANGES, HeaderValue::from_static("bytes")))
+            .insert_header(header::ETag(etag.clone()))
+            // only the owner may see media, so it stays out of shared caches, and a stand in for a variant that
+            // hasn't been generated yet must be checked again
+            .insert_header(header::CacheControl(if settled {
+                vec![CacheDirective::Private, CacheDirective::MaxAge(365*24*60*60), CacheDirective::Extension("immutable".to_string(), None)]
+            } else {
+                vec![CacheDirective::Private, CacheDirective::NoCache]
+            }));
+        if stored_file.compressed {
+            response.insert_header((header::VARY, HeaderValue::from_static("accept-encoding")));
+        }
+        if is_fresh(&req, &etag) {
+            return response.status(StatusCode::NOT_MODIFIED).finish()
+        }
+
+        let content_type = match mime_type_of(&*transactions.0.read().await, &file_reference, &cached_media.filename).await {
+            Ok(content_type) => content_type,
+            Err(err) => return actix_web::error::ErrorInternalServerError(format!("failed to open media file: {}", err)).into()
+        };
+        let is_variant = file_reference != cached_media.file_reference;
+        response.insert_header(content_disposition(&served_filename(&cached_media.filename, is_variant, content_type)));
+        if send_compressed {
+            return response
+                .content_type(content_type)
+                .insert_header(ContentEncoding::Zstd)
+                .streaming(ReaderStream::new(stored_file.reader))
+        }
+
+        if let Some(header::Range::Bytes(range_specs)) = range {
+            let ranges = satisfiable_ranges(&range_specs, size);
+            if ranges.is_empty() {
+                return HttpResponse::RangeNotSatisfiable()
+                    .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                    .finish()
+            }
+
+            if let [(start, end)] = ranges[..] {
+                let file = match transactions.0.read().await.open_file_at(&file_reference, start).await {
+                    Ok(file) => file,
+                    Err(err) => return actix_web::error::ErrorInternalServerError(format!("failed to open media file: {}", err)).into()
+                };
+                return response
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .content_type(content_type)
+                    .insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size)))
+                    .no_chunking(end-start+1)
+                    .streaming(FileStream::new(file, end-start+1))
+            }
+            // with too many ranges the whole file is sent instead, as if none were asked for
+            if ranges.len() <= MAX_RANGES {
+                // each range becomes a part of a multipart/byteranges body, opening the file again where the part starts
+                let body = ByteRangesBody::new(&ranges, content_type, size, format!("{:016x}", rand::random::<u64>()));
+                let multipart_type = body.content_type();
+                let content_length = body.content_length();
+                let ByteRangesBody { parts, closing_boundary, .. } = body;
+
+                let transactions = transactions.0.clone();
+                let body = futures_util::stream::iter(parts)
+                    .then(move |(part_headers, start, length)| {
+                        let transactions = transactions.clone();
+                        async move {
+                            let file = transactions.read().await.open_file_at(&file_reference, start).await?;
+                            Ok::<_, std::io::Error>(futures_util::stream::once(async move { Ok(Bytes::from(part_headers)) }).chain(FileStream::new(file, length)))
+                        }
+                    })
+                    .try_flatten()
+                    .chain(futures_util::stream::once(async move { Ok(Bytes::from(closing_boundary)) }));
+                return response
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .content_type(multipart_type)
+                    .no_chunking(content_length)
+                    .streaming(Box::pin(body))
+            }
+        }
+
+        response
+            .content_type(content_type)
+            .no_chunking(size)
+            .streaming(FileStream::new(stored_file.decompressed(), size))
     } else {
-        return actix_web::error::ErrorUnauthorized("invalid session").into()
+        actix_web::error::ErrorUnauthorized("invalid session").into()
     }
 }
 
@@ -246,62 +618,315 @@ async fn get_transactions(sessions: web::Data<ActixSessionManager>, req: HttpReq
         }
     };
     if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(&hashed_session_id_slice)) {
-        return HttpResponse::Ok()
+        HttpResponse::Ok()
             .streaming(ReaderStream::new(match transactions.0.read().await.get_transactions_raw().await {
                 Ok(transactions) => transactions,
                 Err(err) => return actix_web::error::ErrorInternalServerError(format!("failed to get raw transacations: {}", err)).into()
             }))
-            .into()
     } else {
-        return actix_web::error::ErrorUnauthorized("invalid session").into()
-    }
-}
-
-// #[get("/media_files_zip")]
-// async fn media_files_zip(sessions: web::Data<ActixSessionManager>, req: HttpRequest, transactions: web::Data<ActixTransactions>) -> HttpResponse {
-//     let authorization_header_value = match req.headers().get("AUTHORIZATION") {
-//         Some(value) => value,
-//         None => return actix_web::error::ErrorBadRequest("missing AUTHORIZATION header").into()
-//     };
-//     let hashed_session_id_slice = match base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value) {
-//         Ok(hashed_session_id_slice) => hashed_session_id_slice,
-//         Err(err) => {
-//             return actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", err)).into()
-//         }
-//     };
-//     if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(&hashed_session_id_slice)) {
-//         let transactions = match transactions.0.read().await.get_transactions_raw().await {
-//             Ok(transactions) => transactions,
-//             Err(err) => return actix_web::error::ErrorInternalServerError(format!("failed to get raw transacations: {}", err)).into()
-//         };
-//         struct MediaFilesStream {
-//             transactions: 
-//         }
-
-//         impl futures_util::Stream for MediaFilesStream {
-//             fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
-                
-//             }
-//         }
-//         return HttpResponse::Ok()
-//             .streaming(ReaderStream::new())
-//             .into()
-//     } else {
-//         return actix_web::error::ErrorUnauthorized("invalid session").into()
-//     }
-// }
+        actix_web::error::ErrorUnauthorized("invalid session").into()
+    }
+}
+
+#[post("/compact")]
+async fn compact(config: web::Data<Config>, sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>) -> Result<String, actix_web::Error> {
+    let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
+    if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
+        })?))
+    {
+        let mut cache = cache.0.write().await;
+        let mut transactions = transactions.0.write().await;
+
+        let report = transactions.compact(&cache, now_datetime()-config.trash_retention).await?;
+
+        let mut compacted_cache = IloveuCache::new();
+        compacted_cache.run_raw_transactions(transactions.get_transactions_raw().await?, transactions.key(), ReplayMode::Strict).await.map_err(tokio::io::Error::from)?;
+        *cache = compacted_cache;
+
+        Ok(serde_json::to_string(&report)?)
+    } else {
+        Err(actix_web::error::ErrorUnauthorized("invalid session"))
+    }
+}
+
+#[post("/download_token")]
+async fn issue_download_token(sessions: web::Data<ActixSessionManager>, req: HttpRequest) -> Result<Vec<u8>, actix_web::Error> {
+    let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
+    let hashed_session_id = HashedSessionID::clone_from_slice(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
+        })?);
+    let mut sessions = sessions.0.write().await;
+    if sessions.validate_session(&hashed_session_id) {
+        Ok(sessions.new_download_token(hashed_session_id, Instant::now()).to_vec())
+    } else {
+        Err(actix_web::error::ErrorUnauthorized("invalid session"))
+    }
+}
+
+/// Authorized by a download token in the URL rather than the AUTHORIZATION header, so browsers can save the
+/// archive straight to disk by navigating to it.
+#[get("/media_files_zip")]
+async fn media_files_zip(sessions: web::Data<ActixSessionManager>, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, query: web::Query<DownloadQuery>) -> HttpResponse {
+    let download_token: DownloadToken = match base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(&query.token).ok().and_then(|download_token| download_token.try_into().ok()) {
+        Some(download_token) => download_token,
+        None => return actix_web::error::ErrorBadRequest("malformed download token").into()
+    };
+    if sessions.0.write().await.redeem_download_token(&download_token, Instant::now()) {
+        let exported_media = plan_export(&*cache.0.read().await);
+        let zip_length = match media_zip_length(&exported_media) {
+            Ok(zip_length) => zip_length,
+            Err(err) => return actix_web::error::ErrorInternalServerError(err.to_string()).into(),
+        };
+        let transactions = transactions.0.clone();
+
+        // the archive is written into one end of a pipe while the response streams out of the other
+        let (zip_reader, zip_writer) = tokio::io::duplex(64*1024);
+        let (result_sender, result_receiver) = tokio::sync::oneshot::channel();
+        actix_web::rt::spawn(async move {
+            let result = write_media_zip(zip_writer, &transactions, &exported_media).await;
+            if let Err(err) = &result {
+                error!("Failed to write media files zip: {}", err);
+            }
+            let _ = result_sender.send(result);
+        });
+
+        // once the response has started, failing the stream is the only way left to report an error
+        let failure = futures_util::stream::once(result_receiver).filter_map(|result| async move {
+            match result {
+                Ok(Err(err)) => Some(Err(err)),
+                _ => None,
+            }
+        });
+        HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"media-files.zip\""))
+            .no_chunking(zip_length)
+            .streaming(ReaderStream::new(zip_reader).chain(failure))
+    } else {
+        actix_web::error::ErrorUnauthorized("invalid download token").into()
+    }
+}
+
+#[post("/import_archive")]
+async fn import_archive_upload(sessions: web::Data<ActixSessionManager>, req: HttpRequest, cache: web::Data<ActixCache>, transactions: web::Data<ActixTransactions>, variant_queue: web::Data<ActixVariantQueue>, payload: web::Payload) -> Result<String, actix_web::Error> {
+    let authorization_header_value = req.headers().get("AUTHORIZATION").ok_or(actix_web::error::ErrorBadRequest("missing AUTHORIZATION header"))?;
+    if sessions.0.read().await.validate_session(&HashedSessionID::clone_from_slice(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(authorization_header_value).map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("failed to decode base64: {}", e))
+        })?))
+    {
+        let uploads = transactions.0.read().await.uploads();
+        let archive_upload = uploads.write_temporary(StreamReader::new(payload.map_err(|e| std::io::Error::other(e.to_string())))).await?;
+
+        let result = import_archive(&transactions.0, &cache.0, uploads.open_seekable(&archive_upload).await?).await;
+        archive_upload.discard().await.ok();
+        variant_queue.queue_missing(&*cache.0.read().await);
+
+        Ok(serde_json::to_string(&result?)?)
+    } else {
+        Err(actix_web::error::ErrorUnauthorized("invalid session"))
+    }
+}
 
 #[derive(Parser)]
-#[clap(author="GameSense Sports", version="v1.0.0", about="Rendering backend for Real Prep editor")]
+#[clap(author="GameSense Sports", version="v1.0.0", about="Rendering backend for Real Prep editor", subcommand_negates_reqs = true)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     #[clap(short, long, default_value = "127.0.0.1:5050")]
     address: String,
 
-    #[clap(short, long)]
-    password: String,
+    #[clap(short, long, required = true)]
+    password: Option<String>,
+
+    #[clap(long, required = true)]
+    transactions_dir: Option<String>,
 
+    /// How many days deleted media stays restorable from the trash
+    #[clap(long, default_value = "30")]
+    trash_retention_days: f64,
+
+    /// How often to snapshot the cache so startup only has to replay the transactions after it
+    #[clap(long, default_value = "10")]
+    snapshot_interval_minutes: u64,
+
+    /// Skip transactions that can't be replayed instead of refusing to start, reporting each one
     #[clap(long)]
-    transactions_dir: String,
+    lenient_replay: bool,
+
+    /// A file holding the passphrase of an encrypted transactions store. Starting a new store with one encrypts it.
+    #[clap(long, global = true)]
+    passphrase_file: Option<String>,
+
+    /// Compress new transactions, and media files that shrink enough, with zstd
+    #[clap(long, global = true)]
+    compress: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Rewrite the transactions log without edited, deleted or expired data. The server must not be running.
+    Compact {
+        #[clap(long)]
+        transactions_dir: String,
+
+        /// How many days deleted media stays restorable from the trash
+        #[clap(long, default_value = "30")]
+        trash_retention_days: f64,
+    },
+    /// Upgrade the transactions store to the latest version, backing it up first. The server must not be running.
+    Migrate {
+        #[clap(long)]
+        transactions_dir: String,
+
+        /// Only list the migrations that would run
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Check the transactions store for damaged records, unknown tags and missing or truncated files without
+    /// changing it, exiting with an error if anything is wrong.
+    Verify {
+        #[clap(long)]
+        transactions_dir: String,
+    },
+    /// Add every photo and video in a directory, skipping files already in the store. The server must not be running.
+    Import {
+        #[clap(long)]
+        transactions_dir: String,
+
+        /// The directory to import, including its subfolders
+        #[clap(long)]
+        source_dir: String,
+
+        /// Tag each file with the names of the subfolders it is in
+        #[clap(long)]
+        folder_tags: bool,
+    },
+    /// Add everything from a media files zip export, recreating its tags by name and skipping media already in
+    /// the store. The server must not be running.
+    ImportArchive {
+        #[clap(long)]
+        transactions_dir: String,
+
+        #[clap(long)]
+        archive: String,
+    },
+    /// Encrypt an existing transactions store with the passphrase from --passphrase-file, backing it up first.
+    /// The server must not be running.
+    Encrypt {
+        #[clap(long)]
+        transactions_dir: String,
+    },
+}
+
+fn days_to_millis(days: f64) -> f64 {
+    days*24.0*60.0*60.0*1000.0
+}
+
+/// Reads the passphrase from a file so it doesn't end up in the shell history or the process list.
+async fn read_passphrase(passphrase_file: Option<String>) -> std::io::Result<Option<String>> {
+    match passphrase_file {
+        Some(passphrase_file) => {
+            let passphrase = tokio::fs::read_to_string(passphrase_file).await?;
+            let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+            if passphrase.is_empty() {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "the passphrase file is empty"));
+            }
+            Ok(Some(passphrase.to_string()))
+        },
+        None => Ok(None),
+    }
+}
+
+
+async fn compact_offline(transactions_dir: String, trash_retention_days: f64, store_options: StoreOptions) -> std::io::Result<()> {
+    let mut transactions = IloveuTransactionsStore::open_with_options(transactions_dir, store_options).await?;
+
+    let mut cache = IloveuCache::new();
+    cache.run_raw_transactions(transactions.get_transactions_raw().await?, transactions.key(), ReplayMode::Strict).await?;
+
+    let report = transactions.compact(&cache, now_datetime()-days_to_millis(trash_retention_days)).await?;
+    println!("Compacted transactions from {} to {} bytes, dropped {} expired trashed media and {} unreferenced blobs", report.bytes_before, report.bytes_after, report.dropped_media, report.dropped_blobs);
+
+    Ok(())
+}
+
+async fn migrate_offline(transactions_dir: String, dry_run: bool, store_options: StoreOptions) -> std::io::Result<()> {
+    let pending = pending_migrations_at(Path::new(&transactions_dir)).await?;
+    if pending.is_empty() {
+        println!("Transactions store is already at the latest version {}", LATEST_VERSION);
+        return Ok(());
+    }
+
+    for migration in pending {
+        println!("{} -> {}: {}", migration.from_version(), migration.from_version()+1, migration.description());
+    }
+    if !dry_run {
+        IloveuTransactionsStore::open_with_options(transactions_dir, store_options).await?;
+        println!("Migrated transactions store to version {}", LATEST_VERSION);
+    }
+
+    Ok(())
+}
+
+async fn verify_offline(transactions_dir: String, passphrase: Option<String>) -> std::io::Result<()> {
+    let report = verify_store(Path::new(&transactions_dir), passphrase.as_deref()).await?;
+    println!("Transactions store version {}, {} bytes in {} records", report.version, report.transactions_length, report.records);
+    println!("{} tags, {} media and {} trashed media", report.tags, report.media, report.trashed_media);
+    if report.version < LATEST_VERSION {
+        println!("Store needs migrating to version {}", LATEST_VERSION);
+    }
+    if report.unknown_transactions > 0 {
+        println!("{} transactions of unknown types were skipped", report.unknown_transactions);
+    }
+    for problem in &report.problems {
+        println!("Problem: {}", problem);
+    }
+
+    if !report.problems.is_empty() {
+        println!("Found {} problems", report.problems.len());
+        std::process::exit(1);
+    }
+    println!("No problems found");
+    Ok(())
+}
+
+async fn import_offline(transactions_dir: String, source_dir: String, folder_tags: bool, store_options: StoreOptions) -> std::io::Result<()> {
+    let mut transactions = IloveuTransactionsStore::open_with_options(transactions_dir, store_options).await?;
+    let mut cache = transactions.load_cache().await?;
+
+    let report = import_directory(&mut transactions, &mut cache, Path::new(&source_dir), folder_tags).await?;
+    println!("Imported {} media files and added {} tags, skipped {} duplicates and {} unsupported files", report.imported, report.added_tags, report.duplicates, report.unsupported);
+
+    Ok(())
+}
+
+async fn import_archive_offline(transactions_dir: String, archive: String, store_options: StoreOptions) -> std::io::Result<()> {
+    let transactions = IloveuTransactionsStore::open_with_options(transactions_dir, store_options).await?;
+    let cache = transactions.load_cache().await?;
+
+    let report = import_archive(&RwLock::new(transactions), &RwLock::new(cache), tokio::fs::File::open(&archive).await?).await?;
+    println!("Imported {} media files and added {} tags, skipped {} duplicates", report.imported, report.added_tags, report.duplicates);
+
+    Ok(())
+}
+
+async fn encrypt_offline(transactions_dir: String, store_options: StoreOptions) -> std::io::Result<()> {
+    let passphrase = store_options.passphrase.clone().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "encrypting needs --passphrase-file"))?;
+    if is_encrypted(Path::new(&transactions_dir)).await? {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "the transactions store is already encrypted"));
+    }
+    let mut transactions = IloveuTransactionsStore::open_with_options(transactions_dir, StoreOptions { passphrase: None, ..store_options }).await?;
+    let cache = transactions.load_cache().await?;
+
+    let backup_path = transactions.encrypt(&cache, &passphrase).await?;
+    println!("Encrypted the transactions store, the unencrypted original was backed up to {}", backup_path.display());
+
+    Ok(())
 }
 
 #[actix_web::main]
@@ -309,14 +934,55 @@ async fn main() -> std::io::Result<()> {
     env_logger::init();
 
     let args = Args::parse();
+    let store_options = StoreOptions {
+        replay_mode: if args.lenient_replay { ReplayMode::Lenient } else { ReplayMode::Strict },
+        passphrase: read_passphrase(args.passphrase_file).await?,
+        compress: args.compress,
+    };
 
-    let transactions = IloveuTransactionsStore::open(args.transactions_dir).await?;
+    match args.command {
+        Some(Command::Compact { transactions_dir, trash_retention_days }) => return compact_offline(transactions_dir, trash_retention_days, store_options).await,
+        Some(Command::Migrate { transactions_dir, dry_run }) => return migrate_offline(transactions_dir, dry_run, store_options).await,
+        Some(Command::Verify { transactions_dir }) => return verify_offline(transactions_dir, store_options.passphrase).await,
+        Some(Command::Import { transactions_dir, source_dir, folder_tags }) => return import_offline(transactions_dir, source_dir, folder_tags, store_options).await,
+        Some(Command::ImportArchive { transactions_dir, archive }) => return import_archive_offline(transactions_dir, archive, store_options).await,
+        Some(Command::Encrypt { transactions_dir }) => return encrypt_offline(transactions_dir, store_options).await,
+        None => {}
+    }
 
-    let mut cache = IloveuCache::new();
-    cache.run_raw_transactions(transactions.get_transactions_raw().await?).await?;
+    let password = args.password.expect("password is required");
+    let transactions = IloveuTransactionsStore::open_with_options(args.transactions_dir.expect("transactions_dir is required"), store_options).await?;
+
+    let cache = transactions.load_cache().await?;
 
     let actix_transactions = ActixTransactions(Arc::new(RwLock::new(transactions)));
+
+    let snapshot_transactions = actix_transactions.0.clone();
+    let snapshot_interval = Duration::from_secs(args.snapshot_interval_minutes*60);
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval_at(actix_web::rt::time::Instant::now()+snapshot_interval, snapshot_interval);
+        loop {
+            interval.tick().await;
+            if let Err(err) = snapshot_transactions.read().await.update_snapshot().await {
+                error!("Failed to update the cache snapshot: {}", err);
+            }
+        }
+    });
     let actix_cache = ActixCache(Arc::new(RwLock::new(cache)));
+
+    // pictures are resized one at a time, since each is decoded whole in memory
+    let (variant_sender, mut variant_receiver) = mpsc::unbounded_channel();
+    let actix_variant_queue = ActixVariantQueue(variant_sender);
+    actix_variant_queue.queue_missing(&*actix_cache.0.read().await);
+    let variant_transactions = actix_transactions.0.clone();
+    let variant_cache = actix_cache.0.clone();
+    actix_web::rt::spawn(async move {
+        while let Some(media_id) = variant_receiver.recv().await {
+            if let Err(err) = generate_media_variants(&variant_transactions, &variant_cache, media_id).await {
+                error!("Failed to generate variants of media {}: {}", media_id, err);
+            }
+        }
+    });
     let actix_sessions = ActixSessionManager(Arc::new(RwLock::new(SessionManager::new())));
 
     HttpServer::new(move || {
@@ -324,23 +990,56 @@ async fn main() -> std::io::Result<()> {
             .wrap(Cors::default()
                 .allow_any_origin()
                 .allowed_methods(["GET", "POST"])
-                .allowed_headers(["AUTHORIZATION"])
+                .allowed_headers(["AUTHORIZATION", "CONTENT-TYPE", "RANGE", "IF-RANGE", "IF-NONE-MATCH"])
+                // scripts only get to read the headers they are allowed to, which are needed to resume and revalidate media
+                .expose_headers(["CONTENT-RANGE", "ACCEPT-RANGES", "ETAG"])
             )
             .app_data(web::Data::new(Config {
-                password: Arc::new(args.password.clone()),
+                password: Arc::new(password.clone()),
+                trash_retention: days_to_millis(args.trash_retention_days),
             }))
             .app_data(web::Data::new(actix_transactions.clone()))
             .app_data(web::Data::new(actix_cache.clone()))
             .app_data(web::Data::new(actix_sessions.clone()))
+            .app_data(web::Data::new(actix_variant_queue.clone()))
             .service(login)
             .service(add_tag)
             .service(tags)
+            .service(rename_tag)
+            .service(merge_tag)
+            .service(delete_tag)
             .service(add_media)
             .service(media)
+            .service(search)
+            .service(update_media)
+            .service(delete_media)
+            .service(restore_media)
+            .service(trash)
             .service(media_file)
             .service(get_transactions)
+            .service(compact)
+            .service(issue_download_token)
+            .service(media_files_zip)
+            .service(import_archive_upload)
     })
         .bind(args.address)?
         .run()
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn if_none_match_compares_etags_weakly() {
+        let etag = EntityTag::new_strong("abc".to_string());
+        assert!(!is_fresh(&TestRequest::default().to_http_request(), &etag));
+        assert!(is_fresh(&TestRequest::default().insert_header((header::IF_NONE_MATCH, "\"abc\"")).to_http_request(), &etag));
+        assert!(is_fresh(&TestRequest::default().insert_header((header::IF_NONE_MATCH, "W/\"abc\"")).to_http_request(), &etag));
+        assert!(is_fresh(&TestRequest::default().insert_header((header::IF_NONE_MATCH, "\"old\", \"abc\"")).to_http_request(), &etag));
+        assert!(is_fresh(&TestRequest::default().insert_header((header::IF_NONE_MATCH, "*")).to_http_request(), &etag));
+        assert!(!is_fresh(&TestRequest::default().insert_header((header::IF_NONE_MATCH, "\"abc-zstd\"")).to_http_request(), &etag));
+    }
+}
@@ -0,0 +1,179 @@
+use std::path::Path;
+
+use actix_web::http::header::{ByteRangeSpec, Charset, ContentDisposition, DispositionParam, DispositionType, EntityTag, ExtendedValue, IfRange};
+
+/// Requests for more ranges than this get the whole file instead, since each range means opening and maybe
+/// decompressing the file again.
+pub const MAX_RANGES: usize = 16;
+
+/// Whether the ranges of a request can be sent for the file tagged `etag`. With an `If-Range` they only make sense
+/// if the client has the rest of that same file, which only a matching strong tag can tell.
+pub fn if_range_matches(if_range: Option<&IfRange>, etag: &EntityTag) -> bool {
+    match if_range {
+        None => true,
+        Some(IfRange::EntityTag(if_range_etag)) => if_range_etag.strong_eq(etag),
+        Some(IfRange::Date(_)) => false,
+    }
+}
+
+/// The inclusive byte ranges of a file of `size` bytes that `range_specs` ask for, in order and with overlapping or
+/// adjacent ones merged so no byte is sent twice. Empty if none of them can be satisfied.
+pub fn satisfiable_ranges(range_specs: &[ByteRangeSpec], size: u64) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = range_specs.iter()
+        .filter_map(|range_spec| range_spec.to_satisfiable_range(size))
+        .collect();
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, merged_end)) if start <= *merged_end+1 => *merged_end = (*merged_end).max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// A multipart/byteranges body, laid out up front so its length is known before any of the file is read.
+pub struct ByteRangesBody {
+    pub boundary: String,
+    /// The headers of each part, followed by where its bytes start in the file and how many there are.
+    pub parts: Vec<(String, u64, u64)>,
+    pub closing_boundary: String,
+}
+
+impl ByteRangesBody {
+    pub fn new(ranges: &[(u64, u64)], content_type: &str, size: u64, boundary: String) -> Self {
+        let parts = ranges.iter().map(|(start, end)| {
+            let part_headers = format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n", boundary, content_type, start, end, size);
+            (part_headers, *start, end-start+1)
+        }).collect();
+        let closing_boundary = format!("\r\n--{}--\r\n", boundary);
+        ByteRangesBody {
+            boundary,
+            parts,
+            closing_boundary,
+        }
+    }
+
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    pub fn content_length(&self) -> u64 {
+        self.parts.iter().map(|(part_headers, _, length)| part_headers.len() as u64+length).sum::<u64>()+self.closing_boundary.len() as u64
+    }
+}
+
+/// What a file sent for media called `filename` is called. Variants are encoded anew, so they keep the name of
+/// the original but get the extension of the format they were encoded in.
+pub fn served_filename(filename: &str, is_variant: bool, content_type: &str) -> String {
+    let extension = match content_type {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        _ => return filename.to_string(),
+    };
+    if !is_variant {
+        return filename.to_string();
+    }
+    let stem = Path::new(filename).file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    format!("{}.{}", stem, extension)
+}
+
+/// Shows a file inline under `filename`. Old clients get a plain ASCII stand in, everything else reads the exact
+/// name from `filename*` as RFC 6266 says.
+pub fn content_disposition(filename: &str) -> ContentDisposition {
+    let ascii_filename = filename.chars()
+        .map(|character| if character.is_ascii() && !character.is_ascii_control() { character } else { '_' })
+        .collect();
+    ContentDisposition {
+        disposition: DispositionType::Inline,
+        parameters: vec![
+            DispositionParam::Filename(ascii_filename),
+            DispositionParam::FilenameExt(ExtendedValue {
+                charset: Charset::Ext("UTF-8".to_string()),
+                language_tag: None,
+                value: filename.as_bytes().to_vec(),
+            }),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HttpDate, Range};
+
+    fn ranges(range: &str, size: u64) -> Vec<(u64, u64)> {
+        match range.parse::<Range>().unwrap() {
+            Range::Bytes(range_specs) => satisfiable_ranges(&range_specs, size),
+            Range::Unregistered(..) => panic!("not a byte range"),
+        }
+    }
+
+    #[test]
+    fn suffix_and_open_ended_ranges() {
+        assert_eq!(ranges("bytes=-10", 100), [(90, 99)]);
+        assert_eq!(ranges("bytes=-500", 100), [(0, 99)]);
+        assert_eq!(ranges("bytes=40-", 100), [(40, 99)]);
+        assert_eq!(ranges("bytes=40-1000", 100), [(40, 99)]);
+        assert_eq!(ranges("bytes=0-0", 100), [(0, 0)]);
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_are_merged() {
+        assert_eq!(ranges("bytes=50-59,0-9,5-19", 100), [(0, 19), (50, 59)]);
+        assert_eq!(ranges("bytes=0-9,10-19", 100), [(0, 19)]);
+        assert_eq!(ranges("bytes=0-9,-95", 100), [(0, 99)]);
+        assert_eq!(ranges("bytes=0-9,20-29", 100), [(0, 9), (20, 29)]);
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert!(ranges("bytes=100-", 100).is_empty());
+        assert!(ranges("bytes=200-300", 100).is_empty());
+        assert!(ranges("bytes=-0", 100).is_empty());
+        assert!(ranges("bytes=0-10", 0).is_empty());
+        assert_eq!(ranges("bytes=200-300,10-19", 100), [(10, 19)]);
+    }
+
+    #[test]
+    fn if_range_needs_the_same_strong_tag() {
+        let etag = EntityTag::new_strong("abc".to_string());
+        assert!(if_range_matches(None, &etag));
+        assert!(if_range_matches(Some(&IfRange::EntityTag(EntityTag::new_strong("abc".to_string()))), &etag));
+        assert!(!if_range_matches(Some(&IfRange::EntityTag(EntityTag::new_strong("abd".to_string()))), &etag));
+        assert!(!if_range_matches(Some(&IfRange::EntityTag(EntityTag::new_weak("abc".to_string()))), &etag));
+        assert!(!if_range_matches(Some(&IfRange::Date(HttpDate::from(std::time::SystemTime::now()))), &etag));
+    }
+
+    #[test]
+    fn multipart_parts_are_framed_by_the_boundary() {
+        let body = ByteRangesBody::new(&[(0, 9), (90, 99)], "image/jpeg", 100, "b0undary".to_string());
+        assert_eq!(body.content_type(), "multipart/byteranges; boundary=b0undary");
+        assert_eq!(body.parts[0], ("\r\n--b0undary\r\nContent-Type: image/jpeg\r\nContent-Range: bytes 0-9/100\r\n\r\n".to_string(), 0, 10));
+        assert_eq!(body.parts[1], ("\r\n--b0undary\r\nContent-Type: image/jpeg\r\nContent-Range: bytes 90-99/100\r\n\r\n".to_string(), 90, 10));
+        assert_eq!(body.closing_boundary, "\r\n--b0undary--\r\n");
+
+        let written: String = body.parts.iter()
+            .map(|(part_headers, _, length)| format!("{}{}", part_headers, "x".repeat(*length as usize)))
+            .chain([body.closing_boundary.clone()])
+            .collect();
+        assert_eq!(body.content_length(), written.len() as u64);
+    }
+
+    #[test]
+    fn variants_are_named_after_their_format() {
+        assert_eq!(served_filename("beach.heic", true, "image/jpeg"), "beach.jpg");
+        assert_eq!(served_filename("logo.webp", true, "image/png"), "logo.png");
+        assert_eq!(served_filename("beach.heic", false, "image/heic"), "beach.heic");
+        assert_eq!(served_filename("small.jpeg", false, "image/jpeg"), "small.jpeg");
+    }
+
+    #[test]
+    fn filenames_are_escaped_for_content_disposition() {
+        assert_eq!(content_disposition("beach.jpg").to_string(), "inline; filename=\"beach.jpg\"; filename*=UTF-8''beach.jpg");
+        assert_eq!(content_disposition("plage d'été.jpg").to_string(), "inline; filename=\"plage d'_t_.jpg\"; filename*=UTF-8''plage%20d%27%C3%A9t%C3%A9.jpg");
+        assert_eq!(content_disposition("a\"b\\c.jpg").to_string(), "inline; filename=\"a\\\"b\\\\c.jpg\"; filename*=UTF-8''a%22b%5Cc.jpg");
+    }
+}
@@ -0,0 +1,108 @@
+use std::{path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use tokio::io::AsyncReadExt;
+
+use crate::db::{IloveuTransactionsStore, IloveuCache, RecordFormat};
+
+/// Every step needed to bring a store from version 0 up to the latest, in order. The step at index `n`
+/// upgrades version `n` to `n+1`.
+///
+/// Only changes to the framing need a new version. New transaction types can be added without one,
+/// since older binaries skip over records with types they don't know.
+pub const MIGRATIONS: [Migration; 3] = [
+    Migration::CreateTransactions,
+    Migration::MoveInlineFilesToBlobs,
+    Migration::FrameTransactions,
+];
+
+pub const LATEST_VERSION: u64 = MIGRATIONS.len() as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Migration {
+    /// 0 → 1
+    CreateTransactions,
+    /// 1 → 2
+    MoveInlineFilesToBlobs,
+    /// 2 → 3
+    FrameTransactions,
+}
+
+impl Migration {
+    pub fn from_version(&self) -> u64 {
+        MIGRATIONS.iter().position(|migration| migration == self).unwrap() as u64
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Migration::CreateTransactions => "create an empty transactions log and blob directory",
+            Migration::MoveInlineFilesToBlobs => "move file bytes stored inline in the log out into blobs",
+            Migration::FrameTransactions => "frame every transaction with its length and a checksum",
+        }
+    }
+
+    pub(crate) async fn run(&self, store: &mut IloveuTransactionsStore) -> Result<(), tokio::io::Error> {
+        match self {
+            Migration::CreateTransactions => {
+                tokio::fs::File::create(store.path().join("transactions")).await?;
+                tokio::fs::create_dir_all(store.path().join("blobs")).await?;
+            },
+            Migration::MoveInlineFilesToBlobs => {
+                // compaction writes every file into a blob, but the log stays unframed until the next step
+                tokio::fs::create_dir_all(store.path().join("blobs")).await?;
+                let mut cache = IloveuCache::new();
+                cache.run_legacy_transactions(store.get_transactions_raw().await?).await?;
+                store.compact_as(&cache, f64::NEG_INFINITY, RecordFormat::Unframed).await?;
+            },
+            Migration::FrameTransactions => {
+                store.reframe_legacy_transactions().await?;
+            },
+        }
+        Ok(())
+    }
+}
+
+pub async fn read_version(path: &Path) -> Result<u64, tokio::io::Error> {
+    tokio::fs::File::open(path.join("version")).await?.read_u64().await
+}
+
+/// The migrations a store at `version` still needs, refusing stores written by a newer binary.
+pub fn pending_migrations(version: u64) -> Result<&'static [Migration], tokio::io::Error> {
+    if version > LATEST_VERSION {
+        return Err(tokio::io::Error::new(tokio::io::ErrorKind::Unsupported, format!("transactions store version {} is newer than the latest version {} this binary supports", version, LATEST_VERSION)));
+    }
+    Ok(&MIGRATIONS[version as usize..])
+}
+
+/// The migrations opening the store at `path` would run, without touching it. A missing store needs all of them.
+pub async fn pending_migrations_at(path: &Path) -> Result<&'static [Migration], tokio::io::Error> {
+    if !(tokio::fs::try_exists(path).await?) {
+        return Ok(&MIGRATIONS);
+    }
+    pending_migrations(read_version(path).await?)
+}
+
+/// Copies the store into a sibling directory before migrating it. Blobs are never modified in place,
+/// so they are hard linked instead of copied.
+pub(crate) async fn backup_store(path: &Path, version: u64) -> Result<PathBuf, tokio::io::Error> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut backup_name = path.file_name().unwrap_or_default().to_os_string();
+    backup_name.push(format!(".backup-v{}-{}", version, timestamp));
+    let backup_path = path.with_file_name(backup_name);
+    tokio::fs::create_dir(&backup_path).await?;
+
+    let mut entries = tokio::fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let entry_type = entry.file_type().await?;
+        if entry_type.is_dir() && entry.file_name() == "blobs" {
+            tokio::fs::create_dir(backup_path.join("blobs")).await?;
+            let mut blobs = tokio::fs::read_dir(entry.path()).await?;
+            while let Some(blob) = blobs.next_entry().await? {
+                tokio::fs::hard_link(blob.path(), backup_path.join("blobs").join(blob.file_name())).await?;
+            }
+        } else if entry_type.is_file() {
+            tokio::fs::copy(entry.path(), backup_path.join(entry.file_name())).await?;
+        }
+    }
+
+    Ok(backup_path)
+}
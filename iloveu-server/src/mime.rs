@@ -0,0 +1,93 @@
+use std::path::Path;
+
+use tokio::io::AsyncReadExt;
+
+use crate::db::IloveuTransactionsStore;
+use crate::types::FileReference;
+
+/// How much of the start of a file is needed to recognise it. MPEG transport streams repeat their sync byte
+/// every 188 bytes, so this has to reach well past the second one.
+pub const SNIFF_LENGTH: u64 = 256;
+
+const FALLBACK_MIME_TYPE: &str = "application/octet-stream";
+
+/// Recognises the format of a photo or video from its first bytes.
+pub fn sniff_mime_type(first_bytes: &[u8]) -> Option<&'static str> {
+    let starts_with = |magic: &[u8]| first_bytes.starts_with(magic);
+    let at = |offset: usize, magic: &[u8]| first_bytes.get(offset..offset+magic.len()) == Some(magic);
+
+    if starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if starts_with(b"GIF87a") || starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if starts_with(b"RIFF") && at(8, b"WEBP") {
+        Some("image/webp")
+    } else if starts_with(b"RIFF") && at(8, b"AVI ") {
+        Some("video/x-msvideo")
+    } else if starts_with(b"II*\0") || starts_with(b"MM\0*") {
+        Some("image/tiff")
+    } else if starts_with(b"BM") {
+        Some("image/bmp")
+    } else if at(4, b"ftyp") {
+        // ISO media files all look alike apart from the brand that follows
+        Some(match first_bytes.get(8..12)? {
+            b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" | b"hevm" | b"hevs" => "image/heic",
+            b"mif1" | b"msf1" => "image/heif",
+            b"avif" | b"avis" => "image/avif",
+            b"qt  " => "video/quicktime",
+            b"M4V " | b"M4VH" | b"M4VP" => "video/x-m4v",
+            brand if brand.starts_with(b"3g2") => "video/3gpp2",
+            brand if brand.starts_with(b"3gp") => "video/3gpp",
+            _ => "video/mp4",
+        })
+    } else if starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+        // webm is matroska restricted to web codecs, told apart by the doctype near the start
+        if first_bytes.windows(4).any(|window| window == b"webm") {
+            Some("video/webm")
+        } else {
+            Some("video/x-matroska")
+        }
+    } else if at(0, &[0x47]) && at(188, &[0x47]) || at(4, &[0x47]) && at(196, &[0x47]) {
+        Some("video/mp2t")
+    } else {
+        None
+    }
+}
+
+/// Guesses the MIME type of a file from its extension.
+pub fn mime_type_for_filename(filename: &str) -> Option<&'static str> {
+    let extension = Path::new(filename).extension()?.to_str()?.to_lowercase();
+    Some(match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
+        "tif" | "tiff" => "image/tiff",
+        "mp4" => "video/mp4",
+        "m4v" => "video/x-m4v",
+        "mov" => "video/quicktime",
+        "webm" => "video/webm",
+        "mkv" => "video/x-matroska",
+        "avi" => "video/x-msvideo",
+        "3gp" => "video/3gpp",
+        "mts" | "m2ts" => "video/mp2t",
+        _ => return None,
+    })
+}
+
+/// The MIME type of a stored media file, going by its contents first since filenames can be wrong.
+pub async fn mime_type_of(transactions: &IloveuTransactionsStore, file_reference: &FileReference, filename: &str) -> Result<&'static str, tokio::io::Error> {
+    let mut first_bytes = Vec::new();
+    transactions.open_file(file_reference).await?
+        .take(SNIFF_LENGTH.min(file_reference.size()))
+        .read_to_end(&mut first_bytes).await?;
+    Ok(sniff_mime_type(&first_bytes)
+        .or_else(|| mime_type_for_filename(filename))
+        .unwrap_or(FALLBACK_MIME_TYPE))
+}
@@ -0,0 +1,56 @@
+use std::fmt;
+
+use iloveu_lib::transaction::DecodeError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplayMode {
+    /// Stop at the first record that can't be replayed.
+    #[default]
+    Strict,
+    /// Skip records that can't be replayed and report them, for getting a damaged store back up.
+    Lenient,
+}
+
+/// A record of the transactions log that couldn't be replayed.
+#[derive(Debug)]
+pub struct ReplayError {
+    /// How many records come before this one in the log.
+    pub record_index: u64,
+    /// Where the record starts in the log.
+    pub offset: u64,
+    pub kind: ReplayErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ReplayErrorKind {
+    Io(std::io::Error),
+    /// The framing of the record is damaged, so its payload can't be trusted.
+    Corrupt(String),
+    Decode(DecodeError),
+    /// The transaction refers to tags or media that don't exist.
+    Invalid(String),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "record {} at offset {} of the transactions log: ", self.record_index, self.offset)?;
+        match &self.kind {
+            ReplayErrorKind::Io(err) => write!(f, "{}", err),
+            ReplayErrorKind::Corrupt(reason) => write!(f, "{}", reason),
+            ReplayErrorKind::Decode(err) => write!(f, "{}", err),
+            ReplayErrorKind::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<ReplayError> for std::io::Error {
+    fn from(err: ReplayError) -> Self {
+        let kind = match &err.kind {
+            ReplayErrorKind::Io(io_err) => io_err.kind(),
+            _ => std::io::ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
@@ -1,17 +1,30 @@
 use rand::Rng;
 use sha2::Sha256;
 use sha2::Digest;
+use std::time::{Duration, Instant};
 
 const SALT_LEN: usize = 16;
 
+/// How long after being issued a download token can still be redeemed.
+pub const DOWNLOAD_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+
 pub type SessionID = u64;
 pub type HashedSessionID = sha2::digest::Output<Sha256>;
+pub type DownloadToken = [u8; 32];
 
 #[derive(Debug)]
 pub struct SessionManager {
     sessions_salt: [u8; SALT_LEN],
     next_session_id: SessionID,
     valid_hashed_session_ids: Vec<HashedSessionID>,
+    /// Unredeemed download tokens with the session they were issued to and when they expire.
+    download_tokens: Vec<(DownloadToken, HashedSessionID, Instant)>,
+}
+
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SessionManager {
@@ -23,6 +36,7 @@ impl SessionManager {
             sessions_salt: salt,
             next_session_id: 0,
             valid_hashed_session_ids: Vec::new(),
+            download_tokens: Vec::new(),
         }
     }
 
@@ -41,17 +55,78 @@ impl SessionManager {
 
         self.next_session_id += 1;
 
-        return hashed_session_id
-
+        hashed_session_id
     }
 
     pub fn invalidate_session(&mut self, hashed_session_id: HashedSessionID) -> bool {
         for (i, valid_hashed_session_id) in self.valid_hashed_session_ids.iter().enumerate() {
             if hashed_session_id == *valid_hashed_session_id {
                 self.valid_hashed_session_ids.remove(i);
+                self.download_tokens.retain(|(_, issued_to, _)| *issued_to != hashed_session_id);
                 return true;
             }
         }
         false
     }
-}
\ No newline at end of file
+
+    /// Issues a token for a valid session that lets it start one download by navigating to a URL, since
+    /// navigations can't send the AUTHORIZATION header and fetching the download instead would buffer all of it.
+    pub fn new_download_token(&mut self, hashed_session_id: HashedSessionID, now: Instant) -> DownloadToken {
+        self.download_tokens.retain(|(_, _, expires)| *expires > now);
+
+        let mut download_token = [0; 32];
+        rand::thread_rng().fill(&mut download_token);
+        self.download_tokens.push((download_token, hashed_session_id, now+DOWNLOAD_TOKEN_LIFETIME));
+
+        download_token
+    }
+
+    /// Whether `download_token` was issued and hasn't expired, using it up either way.
+    pub fn redeem_download_token(&mut self, download_token: &DownloadToken, now: Instant) -> bool {
+        match self.download_tokens.iter().position(|(token, _, _)| token == download_token) {
+            Some(i) => self.download_tokens.swap_remove(i).2 > now,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_tokens_are_single_use() {
+        let mut sessions = SessionManager::new();
+        let hashed_session_id = sessions.new_session();
+        let now = Instant::now();
+
+        let download_token = sessions.new_download_token(hashed_session_id, now);
+        assert!(!sessions.redeem_download_token(&[0; 32], now));
+        assert!(sessions.redeem_download_token(&download_token, now));
+        assert!(!sessions.redeem_download_token(&download_token, now));
+    }
+
+    #[test]
+    fn download_tokens_expire() {
+        let mut sessions = SessionManager::new();
+        let hashed_session_id = sessions.new_session();
+        let now = Instant::now();
+
+        let download_token = sessions.new_download_token(hashed_session_id, now);
+        assert!(!sessions.redeem_download_token(&download_token, now+DOWNLOAD_TOKEN_LIFETIME));
+    }
+
+    #[test]
+    fn invalidating_a_session_revokes_its_download_tokens() {
+        let mut sessions = SessionManager::new();
+        let hashed_session_id = sessions.new_session();
+        let other_hashed_session_id = sessions.new_session();
+        let now = Instant::now();
+
+        let download_token = sessions.new_download_token(hashed_session_id, now);
+        let other_download_token = sessions.new_download_token(other_hashed_session_id, now);
+        assert!(sessions.invalidate_session(hashed_session_id));
+        assert!(!sessions.redeem_download_token(&download_token, now));
+        assert!(sessions.redeem_download_token(&other_download_token, now));
+    }
+}
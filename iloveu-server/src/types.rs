@@ -1,22 +1,61 @@
-use serde::Serialize;
+use std::collections::HashMap;
 
-#[derive(Debug, Clone, Copy, Serialize)]
-pub enum MediaType {
-    Picture,
-    Video,
-}
+use serde::{Serialize, Deserialize};
 
-impl MediaType {
-    
-}
+use iloveu_lib::transaction::MediaMetadata;
+pub use iloveu_lib::transaction::{MediaType, BlobHash, VariantSize, ExifMetadata, VideoInfo};
 
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SizedReference {
     pub offset: u64,
     pub size: u64,
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// A file stored in the blob directory, named by the SHA-256 of its bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobReference {
+    #[serde(serialize_with = "serialize_hash", deserialize_with = "deserialize_hash")]
+    pub hash: BlobHash,
+    pub size: u64,
+}
+
+pub fn hash_to_hex(hash: &BlobHash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn serialize_hash<S: serde::Serializer>(hash: &BlobHash, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hash_to_hex(hash))
+}
+
+fn deserialize_hash<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<BlobHash, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    let mut hash = [0u8; 32];
+    if hex.len() != hash.len()*2 || !hex.is_ascii() {
+        return Err(serde::de::Error::custom(format!("invalid blob hash {:?}", hex)));
+    }
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i*2..i*2+2], 16).map_err(serde::de::Error::custom)?;
+    }
+    Ok(hash)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileReference {
+    /// File bytes stored inside the transactions log itself, only written by version 1 stores.
+    Inline(SizedReference),
+    Blob(BlobReference),
+}
+
+impl FileReference {
+    pub fn size(&self) -> u64 {
+        match self {
+            FileReference::Inline(sized_reference) => sized_reference.size,
+            FileReference::Blob(blob_reference) => blob_reference.size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CachedMedia {
     pub title: String,
     pub description: String,
@@ -24,5 +63,155 @@ pub struct CachedMedia {
     pub taken_datetime: f64,
     pub media_type: MediaType,
     pub filename: String,
-    pub file_reference: SizedReference,
-}
\ No newline at end of file
+    pub file_reference: FileReference,
+    /// Smaller copies of a picture, missing until they have been generated. Sizes the original is already small
+    /// enough for refer to the original.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub variants: HashMap<VariantSize, BlobReference>,
+    /// What the photo's own metadata says about it, if it says anything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exif: Option<ExifMetadata>,
+    /// How long and how big a video is, and when it was recorded, if its container says.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_info: Option<VideoInfo>,
+}
+
+impl CachedMedia {
+    pub fn from_metadata(metadata: MediaMetadata, file_reference: FileReference) -> CachedMedia {
+        CachedMedia {
+            title: metadata.title,
+            description: metadata.description,
+            tags_vec: metadata.tags_vec,
+            taken_datetime: metadata.taken_datetime,
+            media_type: metadata.media_type,
+            filename: metadata.filename,
+            file_reference,
+            variants: HashMap::new(),
+            exif: None,
+            video_info: None,
+        }
+    }
+
+    /// The file to send for `size`, falling back to a bigger variant or the original while the one asked for
+    /// doesn't exist yet. Also returns whether it is the file that will always be sent for `size`.
+    pub fn file_for_size(&self, size: MediaFileSize) -> (FileReference, bool) {
+        let variant_sizes: &[VariantSize] = match size {
+            MediaFileSize::Thumb => &[VariantSize::Thumb, VariantSize::Medium],
+            MediaFileSize::Medium => &[VariantSize::Medium],
+            MediaFileSize::Original => return (self.file_reference, true),
+        };
+        // only pictures get variants
+        let settled = self.media_type != MediaType::Picture || self.variants.contains_key(&variant_sizes[0]);
+        match variant_sizes.iter().find_map(|variant_size| self.variants.get(variant_size)) {
+            Some(blob_reference) => (FileReference::Blob(*blob_reference), settled),
+            None => (self.file_reference, settled),
+        }
+    }
+
+    /// Every blob the media needs, its file and then its variants.
+    pub fn blob_references(&self) -> impl Iterator<Item = BlobReference> + '_ {
+        let file_blob_reference = match self.file_reference {
+            FileReference::Blob(blob_reference) => Some(blob_reference),
+            FileReference::Inline(_) => None,
+        };
+        file_blob_reference.into_iter().chain(self.variants.values().copied())
+    }
+
+    pub fn metadata(&self) -> MediaMetadata {
+        MediaMetadata {
+            title: self.title.clone(),
+            description: self.description.clone(),
+            tags_vec: self.tags_vec.clone(),
+            taken_datetime: self.taken_datetime,
+            media_type: self.media_type,
+            filename: self.filename.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashedMedia {
+    pub media: CachedMedia,
+    pub deleted_datetime: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CompactionReport {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// Trashed media that was past the retention window and is now gone for good.
+    pub dropped_media: u64,
+    /// Blobs no longer referenced by any media.
+    pub dropped_blobs: u64,
+}
+
+/// Changes to the metadata of existing media, fields left as `None` keep their current value.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MediaUpdate {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub tags_vec: Option<Vec<u64>>,
+    pub taken_datetime: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// Most recently taken first
+    #[default]
+    Newest,
+    Oldest,
+}
+
+/// Filters for [`crate::db::IloveuCache::query_media`], as given in the query string of `/media`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MediaQuery {
+    /// Comma separated ids of tags the media must all have
+    #[serde(default, deserialize_with = "deserialize_ids")]
+    pub tags: Vec<u64>,
+    /// Comma separated ids of tags the media must not have
+    #[serde(default, deserialize_with = "deserialize_ids")]
+    pub exclude_tags: Vec<u64>,
+    /// Earliest `taken_datetime` to include
+    pub from: Option<f64>,
+    /// Latest `taken_datetime` to include
+    pub to: Option<f64>,
+    pub media_type: Option<MediaType>,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+/// Which copy of a media file to send, as given in the query string of `/media_file`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaFileSize {
+    Thumb,
+    Medium,
+    #[default]
+    Original,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MediaFileQuery {
+    #[serde(default)]
+    pub size: MediaFileSize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DownloadQuery {
+    /// Base64 download token from `/download_token`
+    pub token: String,
+}
+
+fn deserialize_ids<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<u64>, D::Error> {
+    String::deserialize(deserializer)?
+        .split(',')
+        .filter(|id| !id.is_empty())
+        .map(|id| id.trim().parse().map_err(serde::de::Error::custom))
+        .collect()
+}
@@ -0,0 +1,130 @@
+use std::io::Cursor;
+
+use image::{ImageReader, ImageDecoder, DynamicImage, ImageEncoder, codecs::{jpeg::JpegEncoder, png::PngEncoder}};
+use log::info;
+use tokio::{io::AsyncReadExt, sync::RwLock};
+
+use crate::db::{IloveuCache, IloveuTransactionsStore, Upload};
+use crate::mime::SNIFF_LENGTH;
+use crate::types::{CachedMedia, FileReference, MediaType, VariantSize};
+
+/// Every variant a picture gets, smallest first.
+pub const VARIANT_SIZES: [VariantSize; 2] = [VariantSize::Thumb, VariantSize::Medium];
+
+/// Whole pictures are decoded in memory to be resized, so bigger originals don't get variants.
+const MAX_ORIGINAL_LENGTH: u64 = 128*1024*1024;
+const JPEG_QUALITY: u8 = 85;
+
+/// The encoded picture of a variant size, or `None` if the original is already small enough to be that variant.
+pub type ResizedVariant = (VariantSize, Option<Vec<u8>>);
+
+/// How many pixels the longer side of a variant has at most.
+pub fn max_dimension(variant_size: VariantSize) -> u32 {
+    match variant_size {
+        VariantSize::Thumb => 640,
+        VariantSize::Medium => 1920,
+    }
+}
+
+/// Whether media is a picture that is still missing some of its variants.
+pub fn needs_variants(cached_media: &CachedMedia) -> bool {
+    cached_media.media_type == MediaType::Picture
+        && !VARIANT_SIZES.iter().all(|variant_size| cached_media.variants.contains_key(variant_size))
+}
+
+/// Whether a picture starting with `first_bytes` is in a format that can be decoded to be resized.
+pub fn is_resizable(first_bytes: &[u8]) -> bool {
+    image::guess_format(first_bytes).is_ok_and(|format| format.reading_enabled())
+}
+
+/// Decodes a picture, turning it upright as its orientation says, and encodes a smaller copy of it for every
+/// variant size it is bigger than. Pictures with transparency become PNGs and everything else JPEGs.
+pub fn resize_picture(original: &[u8]) -> Result<Vec<ResizedVariant>, image::ImageError> {
+    let mut decoder = ImageReader::new(Cursor::new(original)).with_guessed_format()?.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut picture = DynamicImage::from_decoder(decoder)?;
+    picture.apply_orientation(orientation);
+
+    let mut variants = Vec::new();
+    for variant_size in VARIANT_SIZES {
+        let dimension = max_dimension(variant_size);
+        if picture.width().max(picture.height()) <= dimension {
+            variants.push((variant_size, None));
+            continue;
+        }
+
+        let resized = picture.thumbnail(dimension, dimension);
+        let mut encoded = Vec::new();
+        if resized.color().has_alpha() {
+            let resized = resized.to_rgba8();
+            PngEncoder::new(&mut encoded).write_image(&resized, resized.width(), resized.height(), image::ExtendedColorType::Rgba8)?;
+        } else {
+            let resized = resized.to_rgb8();
+            JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY).write_image(&resized, resized.width(), resized.height(), image::ExtendedColorType::Rgb8)?;
+        }
+        variants.push((variant_size, Some(encoded)));
+    }
+    Ok(variants)
+}
+
+/// Generates whichever variants media `media_id` is missing and records them, returning whether it got any.
+/// Only the reading and writing of files borrows the store, never the resizing.
+pub async fn generate_media_variants(transactions: &RwLock<IloveuTransactionsStore>, cache: &RwLock<IloveuCache>, media_id: u64) -> Result<bool, tokio::io::Error> {
+    let original = match cache.read().await.get_media().get(&media_id) {
+        Some(cached_media) if needs_variants(cached_media) => match cached_media.file_reference {
+            FileReference::Blob(blob_reference) => blob_reference,
+            FileReference::Inline(_) => return Ok(false),
+        },
+        _ => return Ok(false),
+    };
+    if original.size > MAX_ORIGINAL_LENGTH {
+        return Ok(false);
+    }
+
+    let (file, uploads) = {
+        let transactions = transactions.read().await;
+        (transactions.open_file(&FileReference::Blob(original)).await?, transactions.uploads())
+    };
+    let mut file = file.take(original.size);
+    let mut original_bytes = Vec::with_capacity(original.size as usize);
+    (&mut file).take(SNIFF_LENGTH).read_to_end(&mut original_bytes).await?;
+    // formats that can't be decoded, like HEIC, are told apart without reading the whole file
+    if !is_resizable(&original_bytes) {
+        return Ok(false);
+    }
+    file.read_to_end(&mut original_bytes).await?;
+
+    let resized = tokio::task::spawn_blocking(move || resize_picture(&original_bytes)).await
+        .map_err(tokio::io::Error::other)?
+        .map_err(|err| tokio::io::Error::new(tokio::io::ErrorKind::InvalidData, format!("picture can't be resized: {}", err)))?;
+    let mut variants: Vec<(VariantSize, Option<Upload>)> = Vec::new();
+    for (variant_size, encoded) in resized {
+        let upload = match encoded {
+            Some(encoded) => Some(uploads.write(encoded.as_slice()).await?),
+            None => None,
+        };
+        variants.push((variant_size, upload));
+    }
+
+    let mut cache = cache.write().await;
+    // the media may have been dropped from the trash by compaction in the meantime
+    if !cache.get_media().contains_key(&media_id) && !cache.get_trash().contains_key(&media_id) {
+        for upload in variants.into_iter().filter_map(|(_, upload)| upload) {
+            upload.discard().await?;
+        }
+        return Ok(false);
+    }
+    let mut transactions = transactions.write().await;
+    for (variant_size, upload) in variants {
+        let blob_reference = match upload {
+            Some(upload) => transactions.add_media_variant(media_id, variant_size, upload).await?,
+            None => {
+                transactions.add_original_as_variant(media_id, variant_size, original).await?;
+                original
+            },
+        };
+        cache.add_media_variant(media_id, variant_size, blob_reference);
+    }
+    info!("Generated variants of media {}", media_id);
+    Ok(true)
+}
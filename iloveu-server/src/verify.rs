@@ -0,0 +1,152 @@
+use std::{path::Path, sync::Arc};
+
+use sha2::{Sha256, Digest};
+use tokio::io::AsyncReadExt;
+
+use crate::crypto::{StoreKey, load_store_key, sealed_blob_length};
+use crate::db::{IloveuCache, open_blob, BLOB_MAGIC, BLOB_HEADER_LENGTH, BLOB_FLAG_ENCRYPTED, BLOB_FLAG_COMPRESSED};
+use crate::migrations::{read_version, LATEST_VERSION};
+use crate::replay::ReplayMode;
+use crate::types::{BlobHash, BlobReference, CachedMedia, FileReference, hash_to_hex};
+
+/// What checking a transactions store found, without changing anything in it.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub version: u64,
+    pub transactions_length: u64,
+    pub records: u64,
+    /// Transactions of types this binary doesn't know, which aren't problems on their own.
+    pub unknown_transactions: u64,
+    pub tags: u64,
+    pub media: u64,
+    pub trashed_media: u64,
+    pub problems: Vec<String>,
+}
+
+/// Replays the whole log of the store at `path`, ignoring any snapshot, and checks that every record is intact,
+/// every tag a media refers to exists and every file fits within the blob or log holding it. Encrypted stores
+/// need their passphrase.
+pub async fn verify_store(path: &Path, passphrase: Option<&str>) -> Result<VerifyReport, tokio::io::Error> {
+    let mut report = VerifyReport {
+        version: read_version(path).await?,
+        ..Default::default()
+    };
+    if report.version > LATEST_VERSION {
+        report.problems.push(format!("store version {} is newer than the latest version {} this binary supports", report.version, LATEST_VERSION));
+        return Ok(report);
+    }
+    if report.version == 0 {
+        return Ok(report);
+    }
+
+    let key = load_store_key(path, passphrase).await?.map(Arc::new);
+    let transactions_path = path.join("transactions");
+    let transactions_file = tokio::fs::File::open(&transactions_path).await?;
+    report.transactions_length = transactions_file.metadata().await?.len();
+
+    let mut cache = IloveuCache::new();
+    if report.version == LATEST_VERSION {
+        match cache.run_raw_transactions(transactions_file, key.as_deref(), ReplayMode::Lenient).await {
+            Ok(skipped) => report.problems.extend(skipped.iter().map(|err| err.to_string())),
+            Err(err) => report.problems.push(err.to_string()),
+        }
+        report.records = cache.replayed_records();
+    } else if let Err(err) = cache.run_legacy_transactions(transactions_file).await {
+        // unframed logs can't be resumed past a bad transaction
+        report.problems.push(format!("legacy transactions log: {}", err));
+    }
+    report.unknown_transactions = cache.skipped_transactions();
+
+    let tags = cache.get_tags();
+    report.tags = tags.len() as u64;
+    report.media = cache.get_media().len() as u64;
+    report.trashed_media = cache.get_trash().len() as u64;
+
+    let mut all_media: Vec<_> = cache.get_media().iter()
+        .chain(cache.get_trash().iter().map(|(media_id, trashed_media)| (media_id, &trashed_media.media)))
+        .collect();
+    all_media.sort_unstable_by_key(|(media_id, _)| **media_id);
+    for (media_id, cached_media) in all_media {
+        for tag_id in &cached_media.tags_vec {
+            if !tags.contains_key(tag_id) {
+                report.problems.push(format!("media {} refers to unknown tag {}", media_id, tag_id));
+            }
+        }
+        if let Err(problem) = verify_file(path, key.as_ref(), report.transactions_length, cached_media).await {
+            report.problems.push(format!("media {}: {}", media_id, problem));
+        }
+        let mut variants: Vec<_> = cached_media.variants.iter().collect();
+        variants.sort_unstable_by_key(|(variant_size, _)| **variant_size);
+        for (variant_size, blob_reference) in variants {
+            if let Err(problem) = verify_blob(path, key.as_ref(), blob_reference).await {
+                report.problems.push(format!("media {} {:?} variant: {}", media_id, variant_size, problem));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+async fn verify_file(path: &Path, key: Option<&Arc<StoreKey>>, transactions_length: u64, cached_media: &CachedMedia) -> Result<(), String> {
+    match &cached_media.file_reference {
+        FileReference::Inline(sized_reference) => {
+            if sized_reference.offset+sized_reference.size > transactions_length {
+                return Err(format!("inline file of {} bytes at offset {} runs past the end of the {} byte transactions log", sized_reference.size, sized_reference.offset, transactions_length));
+            }
+        },
+        FileReference::Blob(blob_reference) => verify_blob(path, key, blob_reference).await?,
+    }
+    Ok(())
+}
+
+async fn verify_blob(path: &Path, key: Option<&Arc<StoreKey>>, blob_reference: &BlobReference) -> Result<(), String> {
+    let blob_hex = hash_to_hex(&blob_reference.hash);
+    let blob_name = key.map(|key| key.blob_name(&blob_reference.hash)).unwrap_or_else(|| blob_hex.clone());
+    let blob_path = path.join("blobs").join(&blob_name);
+    let mut blob_file = tokio::fs::File::open(&blob_path).await
+        .map_err(|err| format!("blob {}: {}", blob_hex, err))?;
+    let blob_length = blob_file.metadata().await.map_err(|err| format!("blob {}: {}", blob_hex, err))?.len();
+    let mut magic = [0u8; 8];
+    if blob_file.read_exact(&mut magic).await.is_err() || &magic != BLOB_MAGIC {
+        return Err(format!("blob {} has an invalid header", blob_hex));
+    }
+    let flags = blob_file.read_u64().await.map_err(|err| format!("blob {}: {}", blob_hex, err))?;
+    let expected_length = match flags & !BLOB_FLAG_COMPRESSED {
+        0 => blob_reference.size,
+        BLOB_FLAG_ENCRYPTED => sealed_blob_length(blob_reference.size),
+        _ => return Err(format!("blob {} has unsupported flags", blob_hex)),
+    };
+    if flags & BLOB_FLAG_COMPRESSED != 0 {
+        return verify_compressed_blob(&blob_path, blob_reference, key).await
+            .map_err(|problem| format!("blob {} {}", blob_hex, problem));
+    }
+    if blob_length != BLOB_HEADER_LENGTH+expected_length {
+        return Err(format!("blob {} holds {} bytes but {} were expected", blob_hex, blob_length.saturating_sub(BLOB_HEADER_LENGTH), expected_length));
+    }
+    Ok(())
+}
+
+/// How long a compressed blob is depends on how well it compressed, so the whole file is read back and hashed instead.
+async fn verify_compressed_blob(blob_path: &Path, blob_reference: &BlobReference, key: Option<&Arc<StoreKey>>) -> Result<(), String> {
+    let mut file = open_blob(blob_path, blob_reference, key).await.map_err(|err| format!("can't be opened: {}", err))?.decompressed();
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut buffer = vec![0u8; 64*1024];
+    loop {
+        let read = file.read(&mut buffer).await.map_err(|err| format!("can't be decompressed: {}", err))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+
+    if size != blob_reference.size {
+        return Err(format!("holds {} bytes but {} were expected", size, blob_reference.size));
+    }
+    let hash: BlobHash = hasher.finalize().into();
+    if hash != blob_reference.hash {
+        return Err("doesn't match its hash".to_string());
+    }
+    Ok(())
+}
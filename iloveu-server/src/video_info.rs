@@ -0,0 +1,560 @@
+use tokio::io::AsyncReadExt;
+
+use crate::crypto::skip;
+use crate::db::{Upload, Uploads};
+use crate::types::{MediaType, VideoInfo};
+
+/// The most of a container's metadata that is read into memory. Even the sample tables of hours long MP4s stay
+/// well under this.
+const MAX_METADATA_LENGTH: u64 = 64*1024*1024;
+/// Seconds from 1904-01-01, where MP4 and MOV times start, to the unix epoch.
+const ISO_EPOCH_OFFSET: i64 = 2_082_844_800;
+/// Seconds from 2001-01-01, where Matroska times start, to the unix epoch.
+const MATROSKA_EPOCH_OFFSET: i64 = 978_307_200;
+
+const EBML_MAGIC: [u8; 4] = [0x1a, 0x45, 0xdf, 0xa3];
+const MATROSKA_SEGMENT: u64 = 0x18538067;
+const MATROSKA_CLUSTER: u64 = 0x1f43b675;
+const MATROSKA_INFO: u64 = 0x1549a966;
+const MATROSKA_TIMESTAMP_SCALE: u64 = 0x2ad7b1;
+const MATROSKA_DURATION: u64 = 0x4489;
+const MATROSKA_DATE_UTC: u64 = 0x4461;
+const MATROSKA_TRACKS: u64 = 0x1654ae6b;
+const MATROSKA_TRACK_ENTRY: u64 = 0xae;
+const MATROSKA_TRACK_TYPE: u64 = 0x83;
+const MATROSKA_VIDEO: u64 = 0xe0;
+const MATROSKA_PIXEL_WIDTH: u64 = 0xb0;
+const MATROSKA_PIXEL_HEIGHT: u64 = 0xba;
+const MATROSKA_DISPLAY_WIDTH: u64 = 0x54b0;
+const MATROSKA_DISPLAY_HEIGHT: u64 = 0x54ba;
+const MATROSKA_DISPLAY_UNIT: u64 = 0x54b2;
+
+fn be_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(offset..offset+4)?.try_into().unwrap()))
+}
+
+fn be_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(offset..offset+8)?.try_into().unwrap()))
+}
+
+/// The type, header length and total length of the ISO media box starting `bytes`, which is the start of the
+/// `remaining` bytes left in its parent. `None` if the box doesn't fit.
+fn iso_box_header(bytes: &[u8], remaining: u64) -> Option<([u8; 4], u64, u64)> {
+    let box_type = bytes.get(4..8)?.try_into().unwrap();
+    let (header_length, box_length) = match be_u32(bytes, 0)? {
+        // a box of length 0 runs to the end of its parent
+        0 => (8, remaining),
+        1 => (16, be_u64(bytes, 8)?),
+        box_length => (8, box_length.into()),
+    };
+    (header_length <= box_length && box_length <= remaining).then_some((box_type, header_length, box_length))
+}
+
+/// The types and payloads of the ISO media boxes in `bytes`, up to the first one that doesn't fit.
+fn iso_boxes(mut bytes: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let (box_type, header_length, box_length) = iso_box_header(bytes, bytes.len() as u64)?;
+        let payload = &bytes[header_length as usize..box_length as usize];
+        bytes = &bytes[box_length as usize..];
+        Some((box_type, payload))
+    })
+}
+
+fn iso_child<'a>(bytes: &'a [u8], child_type: &[u8; 4]) -> Option<&'a [u8]> {
+    iso_boxes(bytes).find_map(|(box_type, payload)| (&box_type == child_type).then_some(payload))
+}
+
+/// The duration in seconds and creation time in milliseconds since the unix epoch from the payload of an `mvhd` box.
+fn iso_movie_header(mvhd: &[u8]) -> (Option<f64>, Option<f64>) {
+    let (created, timescale, duration) = match mvhd.first() {
+        Some(0) => (be_u32(mvhd, 4).map(u64::from), be_u32(mvhd, 12), be_u32(mvhd, 16).filter(|duration| *duration != u32::MAX).map(u64::from)),
+        Some(1) => (be_u64(mvhd, 4), be_u32(mvhd, 20), be_u64(mvhd, 24).filter(|duration| *duration != u64::MAX)),
+        _ => return (None, None),
+    };
+    let duration = match (duration, timescale) {
+        (Some(duration), Some(timescale)) if timescale > 0 => Some(duration as f64/timescale as f64),
+        _ => None,
+    };
+    // recorders that don't know the time leave it at 0, or sometimes close enough to it to land before 1970
+    let created_datetime = created
+        .and_then(|created| i64::try_from(created).ok())
+        .map(|created| created-ISO_EPOCH_OFFSET)
+        .filter(|created| *created > 0)
+        .map(|created| created as f64*1000.0);
+    (duration, created_datetime)
+}
+
+/// The size a video track is shown at from the payload of its `tkhd` box, turned if its matrix rotates it.
+fn iso_track_dimensions(tkhd: &[u8]) -> Option<(u64, u64)> {
+    let matrix_offset = match tkhd.first()? {
+        0 => 40,
+        1 => 52,
+        _ => return None,
+    };
+    // both are 16.16 fixed point, as are the scale and shear parts of the matrix
+    let width = u64::from(be_u32(tkhd, matrix_offset+36)?) >> 16;
+    let height = u64::from(be_u32(tkhd, matrix_offset+40)?) >> 16;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let turned = be_u32(tkhd, matrix_offset)? == 0 && be_u32(tkhd, matrix_offset+16)? == 0;
+    Some(if turned { (height, width) } else { (width, height) })
+}
+
+/// Reads the payload of an MP4 or MOV `moov` box.
+fn read_iso_movie(moov: &[u8]) -> VideoInfo {
+    let (duration, created_datetime) = iso_child(moov, b"mvhd").map(iso_movie_header).unwrap_or_default();
+    let dimensions = iso_boxes(moov)
+        .filter(|(box_type, _)| box_type == b"trak")
+        .filter(|(_, trak)| {
+            let handler = iso_child(trak, b"mdia").and_then(|mdia| iso_child(mdia, b"hdlr"));
+            handler.and_then(|hdlr| hdlr.get(8..12)) == Some(b"vide")
+        })
+        .find_map(|(_, trak)| iso_track_dimensions(iso_child(trak, b"tkhd")?));
+    VideoInfo {
+        duration,
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        created_datetime,
+    }
+}
+
+/// Finds the `moov` box among the top level boxes of an MP4 or MOV, reopening the upload at each box so the media
+/// data in between is never read.
+async fn iso_video_info(uploads: &Uploads, upload: &Upload) -> Result<Option<VideoInfo>, tokio::io::Error> {
+    let size = upload.blob_reference().size;
+    let mut position = 0;
+    while size-position >= 8 {
+        let mut header = vec![0u8; (size-position).min(16) as usize];
+        uploads.open_at(upload, position).await?.read_exact(&mut header).await?;
+        let (box_type, header_length, box_length) = match iso_box_header(&header, size-position) {
+            Some(box_header) => box_header,
+            None => return Ok(None),
+        };
+        if &box_type == b"moov" {
+            let moov_length = box_length-header_length;
+            if moov_length > MAX_METADATA_LENGTH {
+                return Ok(None);
+            }
+            let mut moov = vec![0u8; moov_length as usize];
+            uploads.open_at(upload, position+header_length).await?.read_exact(&mut moov).await?;
+            return Ok(Some(read_iso_movie(&moov)));
+        }
+        position += box_length;
+    }
+    Ok(None)
+}
+
+/// The length of the EBML variable length integer starting with `first_byte`, or `None` if it's longer than 8 bytes.
+fn ebml_vint_length(first_byte: u8) -> Option<usize> {
+    let length = first_byte.leading_zeros() as usize+1;
+    (length <= 8).then_some(length)
+}
+
+/// An EBML element id or size from its bytes. Ids keep their length marker, sizes don't and are `None` when unknown.
+fn ebml_vint(bytes: &[u8]) -> (u64, Option<u64>) {
+    let raw = bytes.iter().fold(0u64, |value, byte| value << 8 | u64::from(*byte));
+    let value_bits = 7*bytes.len() as u32;
+    let value = raw & ((1 << value_bits)-1);
+    (raw, (value != (1 << value_bits)-1).then_some(value))
+}
+
+/// Reads the id, payload size and header length of the EBML element at the start of `bytes`.
+fn ebml_header(bytes: &[u8]) -> Option<(u64, Option<u64>, usize)> {
+    let id_length = ebml_vint_length(*bytes.first()?)?;
+    let (id, _) = ebml_vint(bytes.get(..id_length)?);
+    let size_length = ebml_vint_length(*bytes.get(id_length)?)?;
+    let (_, size) = ebml_vint(bytes.get(id_length..id_length+size_length)?);
+    Some((id, size, id_length+size_length))
+}
+
+/// The ids and payloads of the EBML elements in `bytes`, up to the first one that doesn't fit.
+fn ebml_elements(mut bytes: &[u8]) -> impl Iterator<Item = (u64, &[u8])> {
+    std::iter::from_fn(move || {
+        let (id, size, header_length) = ebml_header(bytes)?;
+        let end = header_length.checked_add(usize::try_from(size?).ok()?)?;
+        let payload = bytes.get(header_length..end)?;
+        bytes = &bytes[end..];
+        Some((id, payload))
+    })
+}
+
+fn ebml_child(bytes: &[u8], child_id: u64) -> Option<&[u8]> {
+    ebml_elements(bytes).find_map(|(id, payload)| (id == child_id).then_some(payload))
+}
+
+fn ebml_uint(payload: &[u8]) -> Option<u64> {
+    (payload.len() <= 8).then(|| payload.iter().fold(0u64, |value, byte| value << 8 | u64::from(*byte)))
+}
+
+fn ebml_int(payload: &[u8]) -> Option<i64> {
+    let unsigned = ebml_uint(payload)?;
+    if payload.is_empty() {
+        return Some(0);
+    }
+    let unused_bits = 64-8*payload.len() as u32;
+    // shifting back down copies the sign bit into the bytes that weren't there
+    Some((unsigned << unused_bits) as i64 >> unused_bits)
+}
+
+fn ebml_float(payload: &[u8]) -> Option<f64> {
+    match payload.len() {
+        4 => Some(f32::from_be_bytes(payload.try_into().unwrap()).into()),
+        8 => Some(f64::from_be_bytes(payload.try_into().unwrap())),
+        _ => None,
+    }
+}
+
+/// Reads the duration and recording date from the payload of a Matroska `Info` element.
+fn read_matroska_info(info: &[u8], video_info: &mut VideoInfo) {
+    let timestamp_scale = ebml_child(info, MATROSKA_TIMESTAMP_SCALE).and_then(ebml_uint).unwrap_or(1_000_000);
+    video_info.duration = ebml_child(info, MATROSKA_DURATION).and_then(ebml_float)
+        .map(|duration| duration*timestamp_scale as f64/1e9)
+        .filter(|duration| duration.is_finite() && *duration >= 0.0);
+    video_info.created_datetime = ebml_child(info, MATROSKA_DATE_UTC).and_then(ebml_int)
+        .map(|nanoseconds| nanoseconds as f64/1e6+(MATROSKA_EPOCH_OFFSET*1000) as f64);
+}
+
+/// Reads the size the first video track is shown at from the payload of a Matroska `Tracks` element.
+fn read_matroska_tracks(tracks: &[u8], video_info: &mut VideoInfo) {
+    let video = ebml_elements(tracks)
+        .filter(|(id, _)| *id == MATROSKA_TRACK_ENTRY)
+        .filter(|(_, track_entry)| ebml_child(track_entry, MATROSKA_TRACK_TYPE).and_then(ebml_uint) == Some(1))
+        .find_map(|(_, track_entry)| ebml_child(track_entry, MATROSKA_VIDEO));
+    let Some(video) = video else {
+        return;
+    };
+    let dimension = |id| ebml_child(video, id).and_then(ebml_uint).filter(|dimension| *dimension > 0);
+    // display sizes in anything but pixels only give the aspect ratio
+    let display_in_pixels = ebml_child(video, MATROSKA_DISPLAY_UNIT).and_then(ebml_uint).unwrap_or(0) == 0;
+    let (width, height) = match (dimension(MATROSKA_DISPLAY_WIDTH), dimension(MATROSKA_DISPLAY_HEIGHT)) {
+        (Some(width), Some(height)) if display_in_pixels => (Some(width), Some(height)),
+        _ => (dimension(MATROSKA_PIXEL_WIDTH), dimension(MATROSKA_PIXEL_HEIGHT)),
+    };
+    video_info.width = width;
+    video_info.height = height;
+}
+
+/// Reads the `Info` and `Tracks` elements of a Matroska or WebM, which come before any of its clusters.
+async fn matroska_video_info(uploads: &Uploads, upload: &Upload) -> Result<Option<VideoInfo>, tokio::io::Error> {
+    let mut file = uploads.open(upload).await?;
+    let mut video_info = VideoInfo::default();
+    let (mut read_info, mut read_tracks) = (false, false);
+    let mut position = 0;
+    let mut end = upload.blob_reference().size;
+    while !(read_info && read_tracks) {
+        let mut header = [0u8; 16];
+        let mut header_length = 0;
+        let (id, size) = loop {
+            if let Some((id, size, _)) = ebml_header(&header[..header_length]) {
+                break (id, size);
+            }
+            if header_length == header.len() || end-position <= header_length as u64 {
+                return Ok((video_info != VideoInfo::default()).then_some(video_info));
+            }
+            file.read_exact(&mut header[header_length..header_length+1]).await?;
+            header_length += 1;
+        };
+        position += header_length as u64;
+
+        match (id, size) {
+            // the segment holds everything else, so it is walked into rather than over
+            (MATROSKA_SEGMENT, size) => {
+                if let Some(size) = size {
+                    end = end.min(position.saturating_add(size));
+                }
+            },
+            (MATROSKA_CLUSTER, _) | (_, None) => break,
+            (_, Some(size)) if size > end-position => break,
+            (MATROSKA_INFO | MATROSKA_TRACKS, Some(size)) if size <= MAX_METADATA_LENGTH => {
+                let mut payload = vec![0u8; size as usize];
+                file.read_exact(&mut payload).await?;
+                if id == MATROSKA_INFO {
+                    read_matroska_info(&payload, &mut video_info);
+                    read_info = true;
+                } else {
+                    read_matroska_tracks(&payload, &mut video_info);
+                    read_tracks = true;
+                }
+                position += size;
+            },
+            (_, Some(size)) => {
+                skip(&mut file, size).await?;
+                position += size;
+            },
+        }
+    }
+    Ok((video_info != VideoInfo::default()).then_some(video_info))
+}
+
+/// Reads the duration, size and recording time of an uploaded MP4, MOV, Matroska or WebM video, or `None` for
+/// pictures and other containers.
+pub async fn upload_video_info(uploads: &Uploads, upload: &Upload, media_type: MediaType) -> Result<Option<VideoInfo>, tokio::io::Error> {
+    if media_type != MediaType::Video {
+        return Ok(None);
+    }
+    let mut first_bytes = vec![0u8; upload.blob_reference().size.min(8) as usize];
+    uploads.open(upload).await?.read_exact(&mut first_bytes).await?;
+    let video_info = if first_bytes.starts_with(&EBML_MAGIC) {
+        matroska_video_info(uploads, upload).await?
+    } else if first_bytes.get(4..8).is_some_and(|box_type| [b"ftyp", b"moov", b"mdat", b"wide", b"free", b"skip"].iter().any(|known| box_type == *known)) {
+        // old MOVs start straight away with their media instead of a file type box
+        iso_video_info(uploads, upload).await?
+    } else {
+        None
+    };
+    Ok(video_info.filter(|video_info| *video_info != VideoInfo::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::TestStore;
+    use crate::replay::ReplayMode;
+
+    const IDENTITY: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+    const TURNED: [u32; 9] = [0, 0x10000, 0, 0xffff0000, 0, 0, 0, 0, 0x40000000];
+
+    fn iso_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        [&(8+payload.len() as u32).to_be_bytes(), box_type.as_slice(), payload].concat()
+    }
+
+    fn mvhd_v0(created: u32, timescale: u32, duration: u32) -> Vec<u8> {
+        let mut mvhd = [[0u8; 4], created.to_be_bytes(), created.to_be_bytes(), timescale.to_be_bytes(), duration.to_be_bytes()].concat();
+        mvhd.resize(100, 0);
+        mvhd
+    }
+
+    fn mvhd_v1(created: u64, timescale: u32, duration: u64) -> Vec<u8> {
+        let mut mvhd = [[1, 0, 0, 0].as_slice(), &created.to_be_bytes(), &created.to_be_bytes(), &timescale.to_be_bytes(), &duration.to_be_bytes()].concat();
+        mvhd.resize(112, 0);
+        mvhd
+    }
+
+    /// A `tkhd` payload of `version` with everything but the matrix and size zeroed.
+    fn tkhd(version: u8, matrix: [u32; 9], width: u32, height: u32) -> Vec<u8> {
+        let mut tkhd = vec![version, 0, 0, 0];
+        tkhd.resize(if version == 0 { 40 } else { 52 }, 0);
+        tkhd.extend(matrix.iter().flat_map(|value| value.to_be_bytes()));
+        tkhd.extend((width << 16).to_be_bytes());
+        tkhd.extend((height << 16).to_be_bytes());
+        tkhd
+    }
+
+    fn trak(handler: &[u8; 4], tkhd: &[u8]) -> Vec<u8> {
+        let hdlr = [[0u8; 8].as_slice(), handler, &[0u8; 13]].concat();
+        iso_box(b"trak", &[iso_box(b"tkhd", tkhd), iso_box(b"mdia", &iso_box(b"hdlr", &hdlr))].concat())
+    }
+
+    /// An EBML element, always with an 8 byte size.
+    fn ebml(id: u64, payload: &[u8]) -> Vec<u8> {
+        let id_bytes = id.to_be_bytes();
+        let id_start = id_bytes.iter().position(|byte| *byte != 0).unwrap();
+        [&id_bytes[id_start..], &(1 << 56 | payload.len() as u64).to_be_bytes(), payload].concat()
+    }
+
+    fn ebml_video_track(dimensions: &[(u64, u64)]) -> Vec<u8> {
+        let video: Vec<u8> = dimensions.iter().flat_map(|(id, value)| ebml(*id, &value.to_be_bytes()[6..])).collect();
+        ebml(MATROSKA_TRACK_ENTRY, &[ebml(MATROSKA_TRACK_TYPE, &[1]), ebml(MATROSKA_VIDEO, &video)].concat())
+    }
+
+    #[test]
+    fn iso_box_headers() {
+        let mut bytes = iso_box(b"free", &[0; 8]);
+        assert_eq!(iso_box_header(&bytes, 16), Some((*b"free", 8, 16)));
+        assert_eq!(iso_box_header(&bytes, 15), None);
+
+        bytes[..4].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(iso_box_header(&bytes, 100), Some((*b"free", 8, 100)));
+
+        let large = [1u32.to_be_bytes().as_slice(), b"mdat", &(1u64 << 33).to_be_bytes()].concat();
+        assert_eq!(iso_box_header(&large, 1 << 34), Some((*b"mdat", 16, 1 << 33)));
+        assert_eq!(iso_box_header(&large[..12], 1 << 34), None);
+
+        // too short to hold even its own header
+        assert_eq!(iso_box_header(&[0, 0, 0, 4, b'f', b'r', b'e', b'e'], 8), None);
+        assert_eq!(iso_box_header(&[0, 0, 0, 8, b'f', b'r'], 8), None);
+    }
+
+    #[test]
+    fn iso_boxes_stop_at_the_first_that_doesnt_fit() {
+        let bytes = [iso_box(b"ftyp", b"isom"), iso_box(b"free", &[]), iso_box(b"moov", &[1, 2]), iso_box(b"mdat", &[3])].concat();
+        let boxes: Vec<_> = iso_boxes(&bytes[..bytes.len()-1]).collect();
+        assert_eq!(boxes, [(*b"ftyp", b"isom".as_slice()), (*b"free", [].as_slice()), (*b"moov", [1, 2].as_slice())]);
+        assert_eq!(iso_child(&bytes, b"moov"), Some([1, 2].as_slice()));
+        assert_eq!(iso_child(&bytes, b"trak"), None);
+    }
+
+    #[test]
+    fn iso_movie_headers() {
+        let created = (ISO_EPOCH_OFFSET+1_600_000_000) as u64;
+        assert_eq!(iso_movie_header(&mvhd_v0(created as u32, 600, 6000)), (Some(10.0), Some(1_600_000_000_000.0)));
+        assert_eq!(iso_movie_header(&mvhd_v1(created, 1000, 90_500)), (Some(90.5), Some(1_600_000_000_000.0)));
+
+        // unknown durations and times
+        assert_eq!(iso_movie_header(&mvhd_v0(0, 600, u32::MAX)), (None, None));
+        assert_eq!(iso_movie_header(&mvhd_v1(60, 1000, u64::MAX)), (None, None));
+        assert_eq!(iso_movie_header(&mvhd_v0(created as u32, 0, 6000)), (None, Some(1_600_000_000_000.0)));
+        assert_eq!(iso_movie_header(&mvhd_v0(created as u32, 600, 6000)[..16]), (None, Some(1_600_000_000_000.0)));
+        assert_eq!(iso_movie_header(&[2, 0, 0, 0]), (None, None));
+    }
+
+    #[test]
+    fn iso_track_dimensions_follow_the_matrix() {
+        assert_eq!(iso_track_dimensions(&tkhd(0, IDENTITY, 1920, 1080)), Some((1920, 1080)));
+        assert_eq!(iso_track_dimensions(&tkhd(1, IDENTITY, 1920, 1080)), Some((1920, 1080)));
+        assert_eq!(iso_track_dimensions(&tkhd(0, TURNED, 1920, 1080)), Some((1080, 1920)));
+        assert_eq!(iso_track_dimensions(&tkhd(1, TURNED, 1920, 1080)), Some((1080, 1920)));
+        assert_eq!(iso_track_dimensions(&tkhd(0, IDENTITY, 0, 0)), None);
+        assert_eq!(iso_track_dimensions(&tkhd(0, IDENTITY, 1920, 1080)[..80]), None);
+    }
+
+    #[test]
+    fn iso_movies_take_the_size_of_their_video_track() {
+        let created = (ISO_EPOCH_OFFSET+1_600_000_000) as u32;
+        let moov = [
+            iso_box(b"mvhd", &mvhd_v0(created, 600, 6000)),
+            trak(b"soun", &tkhd(0, IDENTITY, 0, 0)),
+            trak(b"vide", &tkhd(0, TURNED, 1920, 1080)),
+            trak(b"vide", &tkhd(0, IDENTITY, 640, 480)),
+        ].concat();
+        assert_eq!(read_iso_movie(&moov), VideoInfo {
+            duration: Some(10.0),
+            width: Some(1080),
+            height: Some(1920),
+            created_datetime: Some(1_600_000_000_000.0),
+        });
+        assert_eq!(read_iso_movie(&trak(b"soun", &tkhd(0, IDENTITY, 1920, 1080))), VideoInfo::default());
+    }
+
+    #[test]
+    fn ebml_varints() {
+        assert_eq!(ebml_vint_length(0x81), Some(1));
+        assert_eq!(ebml_vint_length(0x40), Some(2));
+        assert_eq!(ebml_vint_length(0x1a), Some(4));
+        assert_eq!(ebml_vint_length(0x01), Some(8));
+        assert_eq!(ebml_vint_length(0x00), None);
+
+        assert_eq!(ebml_vint(&[0x81]), (0x81, Some(1)));
+        assert_eq!(ebml_vint(&[0x40, 0x02]), (0x4002, Some(2)));
+        assert_eq!(ebml_vint(&[0x1a, 0x45, 0xdf, 0xa3]), (0x1a45dfa3, Some(0x0a45dfa3)));
+        assert_eq!(ebml_vint(&[0x01, 0, 0, 0, 0, 0, 1, 0]), (0x0100000000000100, Some(0x100)));
+        // all ones means the size is unknown
+        assert_eq!(ebml_vint(&[0xff]), (0xff, None));
+        assert_eq!(ebml_vint(&[0x7f, 0xff]).1, None);
+        assert_eq!(ebml_vint(&[0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).1, None);
+        assert_eq!(ebml_vint(&[0x40, 0x7f]).1, Some(0x7f));
+    }
+
+    #[test]
+    fn ebml_headers_and_elements() {
+        assert_eq!(ebml_header(&[0x44, 0x89, 0x88]), Some((MATROSKA_DURATION, Some(8), 3)));
+        assert_eq!(ebml_header(&[0x18, 0x53, 0x80, 0x67, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]), Some((MATROSKA_SEGMENT, None, 12)));
+        assert_eq!(ebml_header(&[0x18, 0x53, 0x80]), None);
+        assert_eq!(ebml_header(&[0x00, 0x81]), None);
+
+        let bytes = [ebml(0x83, &[1]), ebml(0xb0, &[0x07, 0x80]), ebml(0xba, &[0x04, 0x38])].concat();
+        let elements: Vec<_> = ebml_elements(&bytes[..bytes.len()-1]).collect();
+        assert_eq!(elements, [(0x83, [1].as_slice()), (0xb0, [0x07, 0x80].as_slice())]);
+        assert_eq!(ebml_child(&bytes, 0xba).and_then(ebml_uint), Some(1080));
+    }
+
+    #[test]
+    fn ebml_numbers() {
+        assert_eq!(ebml_uint(&[]), Some(0));
+        assert_eq!(ebml_uint(&[0x0f, 0x42, 0x40]), Some(1_000_000));
+        assert_eq!(ebml_uint(&[0; 9]), None);
+        assert_eq!(ebml_int(&[]), Some(0));
+        assert_eq!(ebml_int(&[0x7f]), Some(127));
+        assert_eq!(ebml_int(&[0xff, 0xfe]), Some(-2));
+        assert_eq!(ebml_int(&(-1_000_000_000i64).to_be_bytes()), Some(-1_000_000_000));
+        assert_eq!(ebml_float(&1.5f32.to_be_bytes()), Some(1.5));
+        assert_eq!(ebml_float(&12345.0f64.to_be_bytes()), Some(12345.0));
+        assert_eq!(ebml_float(&[0; 3]), None);
+    }
+
+    #[test]
+    fn matroska_info() {
+        let mut video_info = VideoInfo::default();
+        read_matroska_info(&[
+            ebml(MATROSKA_TIMESTAMP_SCALE, &[0x0f, 0x42, 0x40]),
+            ebml(MATROSKA_DURATION, &12345.0f64.to_be_bytes()),
+            ebml(MATROSKA_DATE_UTC, &(-1_000_000_000i64).to_be_bytes()),
+        ].concat(), &mut video_info);
+        assert_eq!(video_info.duration, Some(12.345));
+        assert_eq!(video_info.created_datetime, Some((MATROSKA_EPOCH_OFFSET*1000-1000) as f64));
+
+        // the timestamp scale defaults to milliseconds
+        read_matroska_info(&ebml(MATROSKA_DURATION, &2500.0f32.to_be_bytes()), &mut video_info);
+        assert_eq!(video_info.duration, Some(2.5));
+        assert_eq!(video_info.created_datetime, None);
+
+        read_matroska_info(&ebml(MATROSKA_DURATION, &(-1.0f64).to_be_bytes()), &mut video_info);
+        assert_eq!(video_info.duration, None);
+    }
+
+    #[test]
+    fn matroska_tracks_prefer_pixel_display_sizes() {
+        let audio = ebml(MATROSKA_TRACK_ENTRY, &[ebml(MATROSKA_TRACK_TYPE, &[2]), ebml(MATROSKA_VIDEO, &ebml(MATROSKA_PIXEL_WIDTH, &[1]))].concat());
+        let pixels = [(MATROSKA_PIXEL_WIDTH, 1440), (MATROSKA_PIXEL_HEIGHT, 1080)];
+
+        let mut video_info = VideoInfo::default();
+        read_matroska_tracks(&[audio.clone(), ebml_video_track(&pixels)].concat(), &mut video_info);
+        assert_eq!((video_info.width, video_info.height), (Some(1440), Some(1080)));
+
+        read_matroska_tracks(&ebml_video_track(&[pixels[0], pixels[1], (MATROSKA_DISPLAY_WIDTH, 1920), (MATROSKA_DISPLAY_HEIGHT, 1080)]), &mut video_info);
+        assert_eq!((video_info.width, video_info.height), (Some(1920), Some(1080)));
+
+        // a display size in another unit is only an aspect ratio
+        read_matroska_tracks(&ebml_video_track(&[pixels[0], pixels[1], (MATROSKA_DISPLAY_WIDTH, 16), (MATROSKA_DISPLAY_HEIGHT, 9), (MATROSKA_DISPLAY_UNIT, 3)]), &mut video_info);
+        assert_eq!((video_info.width, video_info.height), (Some(1440), Some(1080)));
+
+        let mut video_info = VideoInfo::default();
+        read_matroska_tracks(&audio, &mut video_info);
+        assert_eq!(video_info, VideoInfo::default());
+    }
+
+    #[tokio::test]
+    async fn uploaded_videos_are_walked_to_their_metadata() {
+        let test_store = TestStore::new();
+        let uploads = test_store.open(ReplayMode::Strict).await.unwrap().uploads();
+        let created = (ISO_EPOCH_OFFSET+1_600_000_000) as u32;
+
+        let mp4 = [
+            iso_box(b"ftyp", b"isom\0\0\0\0"),
+            iso_box(b"mdat", &[0xaa; 100_000]),
+            iso_box(b"moov", &[iso_box(b"mvhd", &mvhd_v0(created, 600, 6000)), trak(b"vide", &tkhd(0, IDENTITY, 1920, 1080))].concat()),
+        ].concat();
+        let webm = [
+            ebml(0x1a45dfa3, &ebml(0x4282, b"webm")),
+            vec![0x18, 0x53, 0x80, 0x67, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ebml(0xec, &[0; 64]),
+            ebml(MATROSKA_INFO, &ebml(MATROSKA_DURATION, &2500.0f64.to_be_bytes())),
+            ebml(MATROSKA_TRACKS, &ebml_video_track(&[(MATROSKA_PIXEL_WIDTH, 1280), (MATROSKA_PIXEL_HEIGHT, 720)])),
+            ebml(MATROSKA_CLUSTER, &[0xbb; 1000]),
+        ].concat();
+
+        let upload = uploads.write(mp4.as_slice()).await.unwrap();
+        assert_eq!(upload_video_info(&uploads, &upload, MediaType::Video).await.unwrap(), Some(VideoInfo {
+            duration: Some(10.0),
+            width: Some(1920),
+            height: Some(1080),
+            created_datetime: Some(1_600_000_000_000.0),
+        }));
+        assert_eq!(upload_video_info(&uploads, &upload, MediaType::Picture).await.unwrap(), None);
+        upload.discard().await.unwrap();
+
+        let upload = uploads.write(webm.as_slice()).await.unwrap();
+        assert_eq!(upload_video_info(&uploads, &upload, MediaType::Video).await.unwrap(), Some(VideoInfo {
+            duration: Some(2.5),
+            width: Some(1280),
+            height: Some(720),
+            created_datetime: None,
+        }));
+        upload.discard().await.unwrap();
+
+        let upload = uploads.write(&mp4[..mp4.len()-10]).await.unwrap();
+        assert_eq!(upload_video_info(&uploads, &upload, MediaType::Video).await.unwrap(), None);
+        upload.discard().await.unwrap();
+    }
+}
@@ -94,7 +94,7 @@ pub fn AddMedia() -> Html {
                 }).collect::<Html>()}
             </select></label><br/>
 
-            <label>{"Taken date & time: "}<input type="datetime-local" onchange={
+            <label>{"Taken date & time (read from the file if left empty): "}<input type="datetime-local" onchange={
                 let taken_datetime_handle = taken_datetime_handle.clone();
                 Callback::from(move |e: yew::Event| {
                     taken_datetime_handle.set(e.target_dyn_into::<HtmlInputElement>().unwrap().value());
@@ -153,10 +153,14 @@ pub fn AddMedia() -> Html {
                         body.append_with_str("title", &*title_handle).unwrap();
                         body.append_with_str("description", &*description_handle).unwrap();
                         body.append_with_str("tags", &serde_json::to_string(&*chosen_tags).unwrap()).unwrap();
-                        let taken_datetime_local = Date::parse(&*taken_datetime_handle);
-                        let timezone_offset = Date::new_0().get_timezone_offset();
-                        let taken_datetime = taken_datetime_local+timezone_offset;
-                        body.append_with_str("taken_datetime", &serde_json::to_string(&taken_datetime).unwrap()).unwrap();
+                        if (*taken_datetime_handle).len() == 0 {
+                            body.append_with_str("taken_datetime", "").unwrap();
+                        } else {
+                            let taken_datetime_local = Date::parse(&*taken_datetime_handle);
+                            let timezone_offset = Date::new_0().get_timezone_offset();
+                            let taken_datetime = taken_datetime_local+timezone_offset;
+                            body.append_with_str("taken_datetime", &serde_json::to_string(&taken_datetime).unwrap()).unwrap();
+                        }
                         body.append_with_str("media_type", &*type_handle).unwrap();
                         let file = (*file_handle).as_ref().unwrap();
                         body.append_with_blob_and_filename("file", &file, &file.name()).unwrap();
@@ -180,7 +184,7 @@ pub fn AddMedia() -> Html {
                         adding_handle.set(false);
                     })
                 })
-            } disabled={(*file_handle).is_none() || (*taken_datetime_handle).len() == 0 || (*adding_handle)}>{if *adding_handle {"Adding"} else {"Add"}}</button>
+            } disabled={(*file_handle).is_none() || (*adding_handle)}>{if *adding_handle {"Adding"} else {"Add"}}</button>
         </>
     }
 }
\ No newline at end of file
@@ -1,13 +1,13 @@
 use std::{collections::HashMap, str::FromStr};
 
 use gloo_net::http::Request;
-use js_sys::{Map, JsString, Date};
+use js_sys::{Map, JsString, Date, encode_uri_component};
 use log::error;
 use serde::Deserialize;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
-use web_sys::{window, RequestInit, Blob, Url};
-use yew::{Html, function_component, html, use_state, use_effect_with_deps, platform::spawn_local, use_context};
+use web_sys::{window, RequestInit, Blob, Url, HtmlInputElement};
+use yew::{Html, function_component, html, use_state, use_effect_with_deps, platform::spawn_local, use_context, Callback, TargetCast};
 
 use crate::{API_ROOT, HashedSessionIDBase64};
 
@@ -15,7 +15,7 @@ use crate::{API_ROOT, HashedSessionIDBase64};
 pub fn Home() -> Html {
     let hashed_session_id_base64 = use_context::<HashedSessionIDBase64>().unwrap();
 
-    let media_handle = use_state(HashMap::<u64, MediaInfo>::default);
+    let media_handle = use_state(Vec::<(u64, MediaInfo)>::default);
     let media_files_handle = use_state(HashMap::<u64, String>::default);
     
     let media_handle_effect = media_handle.clone();
@@ -28,7 +28,7 @@ pub fn Home() -> Html {
                 .send()
                 .await {
                 Ok(response) => if response.ok() {
-                    match response.json::<HashMap<u64, MediaInfo>>().await {
+                    match response.json::<Vec<(u64, MediaInfo)>>().await {
                         Ok(media) => {
                             media_handle_effect.set(media);
                         },
@@ -42,14 +42,6 @@ pub fn Home() -> Html {
         });
     }, ());
 
-    let sorted_media_handle = use_state(Vec::<(u64, MediaInfo)>::default);
-    let sorted_media_handle_effect = sorted_media_handle.clone();
-    use_effect_with_deps(move |media_handle| {
-        let mut media: Vec<(u64, MediaInfo)> = (**media_handle).clone().into_iter().collect();
-        media.sort_by(|a, b| b.1.taken_datetime.total_cmp(&a.1.taken_datetime));
-        sorted_media_handle_effect.set(media);
-    }, media_handle.clone());
-
     let media_files_handle_effect = media_files_handle.clone();
     let hashed_session_id_base64_effect = hashed_session_id_base64.clone();
     use_effect_with_deps(move |media_handle| {
@@ -58,9 +50,14 @@ pub fn Home() -> Html {
         let hashed_session_id_base64 = hashed_session_id_base64_effect.clone();
         spawn_local(async move {
             let mut media_files = HashMap::new();
-            for (media_id, _) in media_handle.iter() {
+            for (media_id, media) in media_handle.iter() {
+                // grid tiles only need a thumbnail of pictures
+                let size = match media.media_type {
+                    MediaType::Picture => "thumb",
+                    MediaType::Video => "original",
+                };
                 match JsFuture::from(window().unwrap().fetch_with_str_and_init(
-                    &format!("{}/media_file/{}", API_ROOT, media_id),
+                    &format!("{}/media_file/{}?size={}", API_ROOT, media_id, size),
                     &RequestInit::new()
                         .method("get")
                         .headers(&Map::new().set(&JsString::from_str("AUTHORIZATION").unwrap(), &JsString::from_str(&(*hashed_session_id_base64.0)).unwrap()))
@@ -120,9 +117,50 @@ pub fn Home() -> Html {
         })
     }, ());
 
+    let search_results_handle = use_state(Option::<Vec<u64>>::default);
+    let search = {
+        let search_results_handle = search_results_handle.clone();
+        let hashed_session_id_base64 = hashed_session_id_base64.clone();
+        Callback::from(move |e: yew::Event| {
+            let q = e.target_dyn_into::<HtmlInputElement>().unwrap().value();
+            if q.trim().is_empty() {
+                search_results_handle.set(None);
+                return;
+            }
+            let search_results_handle = search_results_handle.clone();
+            let hashed_session_id_base64 = hashed_session_id_base64.clone();
+            spawn_local(async move {
+                match Request::get(&format!("{}/search?q={}", API_ROOT, encode_uri_component(&q)))
+                    .header("AUTHORIZATION", &hashed_session_id_base64.0)
+                    .send()
+                    .await {
+                    Ok(response) => if response.ok() {
+                        match response.json::<Vec<u64>>().await {
+                            Ok(media_ids) => {
+                                search_results_handle.set(Some(media_ids));
+                            },
+                            Err(err) => error!("Failed to parse JSON from search response: {}", err)
+                        }
+                    } else {
+                        error!("Bad response when searching: {:#?}", response.text().await);
+                    },
+                    Err(err) => error!("Failed to send search request: {}", err)
+                }
+            });
+        })
+    };
+
+    // search results are ranked, so show them in that order instead of by date
+    let shown_media: Vec<&(u64, MediaInfo)> = match &*search_results_handle {
+        Some(media_ids) => media_ids.iter().filter_map(|media_id| media_handle.iter().find(|(id, _)| id == media_id)).collect(),
+        None => media_handle.iter().collect(),
+    };
+
     html! {
+        <>
+        <label>{"Search: "}<input type="search" onchange={search}/></label>
         <div class="media-grid">
-            {(*sorted_media_handle).iter().map(|(media_id, media)| html! {
+            {shown_media.into_iter().map(|(media_id, media)| html! {
                 <div key={*media_id} class="media">
                     <h2>{&media.title}</h2>
                     {match media_files_handle.get(media_id) {
@@ -131,7 +169,12 @@ pub fn Home() -> Html {
                                 <img class="media-img" src={src.clone()}/>
                             },
                             MediaType::Video => html! {
-                                <video class="media-video" src={src.clone()} controls=true/>
+                                <div class="media-video-frame">
+                                    <video class="media-video" src={src.clone()} controls=true/>
+                                    if let Some(duration) = media.video_info.as_ref().and_then(|video_info| video_info.duration) {
+                                        <span class="duration-badge">{format_duration(duration)}</span>
+                                    }
+                                </div>
                             }
                         },
                         None => html! {}
@@ -152,6 +195,7 @@ pub fn Home() -> Html {
                 </div>
             }).collect::<Html>()}
         </div>
+        </>
     }
 }
 
@@ -163,6 +207,23 @@ struct MediaInfo {
     pub taken_datetime: f64,
     pub media_type: MediaType,
     pub filename: String,
+    #[serde(default)]
+    pub video_info: Option<VideoInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+struct VideoInfo {
+    pub duration: Option<f64>,
+}
+
+/// Formats seconds like a video player does, as `m:ss` or `h:mm:ss`.
+fn format_duration(duration: f64) -> String {
+    let seconds = duration.round() as u64;
+    if seconds >= 60*60 {
+        format!("{}:{:02}:{:02}", seconds/(60*60), seconds/60%60, seconds%60)
+    } else {
+        format!("{}:{:02}", seconds/60, seconds%60)
+    }
 }
 
 #[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
@@ -2,6 +2,7 @@
 pub mod home;
 pub mod add_media;
 pub mod add_tag;
+pub mod manage_tags;
 
 pub const API_ROOT: &'static str = std::env!("API_ROOT");
 
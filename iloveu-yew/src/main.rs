@@ -6,7 +6,7 @@ use wasm_bindgen_futures::JsFuture;
 use web_sys::{HtmlInputElement, Blob, Url, window, HtmlElement};
 use yew::{function_component, Html, html, use_state, MouseEvent, Callback, TargetCast, platform::spawn_local, Properties, ContextProvider, classes};
 use yew_router::{Routable, BrowserRouter, Switch, prelude::Link};
-use iloveu_yew::{API_ROOT, HashedSessionIDBase64, home::Home, add_media::AddMedia, add_tag::AddTag};
+use iloveu_yew::{API_ROOT, HashedSessionIDBase64, home::Home, add_media::AddMedia, add_tag::AddTag, manage_tags::ManageTags};
 
 use log::{info, error, warn};
 
@@ -16,6 +16,8 @@ enum Route {
     Home,
     #[at("/add_tag")]
     AddTag,
+    #[at("/manage_tags")]
+    ManageTags,
     #[at("/add_media")]
     AddMedia,
     #[not_found]
@@ -86,40 +88,39 @@ fn App() -> Html {
                     });
                 })
             }>{"Download Transactions"}</button>
-            // <button onclick={
-            //     Callback::from(move |_e: MouseEvent| {
-            //         let hashed_session_id_handle = hashed_session_id_base64_handle.clone();
-            //         spawn_local(async move {
-            //             match Request::get(&format!("{}/media_files_zip", API_ROOT))
-            //                 .header("AUTHORIZATION", hashed_session_id_handle.as_ref().unwrap())
-            //                 .send()
-            //                 .await {
-            //                 Ok(response) => if response.ok() {
-            //                     match response.as_raw().blob() {
-            //                         Ok(blob_promise) => match JsFuture::from(blob_promise).await {
-            //                             Ok(blob) => {
-            //                                 let blob = blob.dyn_into::<Blob>().unwrap();
-            //                                 let url = Url::create_object_url_with_blob(&blob).unwrap();
-            //                                 let document = window().unwrap().document().unwrap();
-            //                                 let body = document.body().unwrap();
-            //                                 let a = document.create_element("a").unwrap();
-            //                                 a.set_attribute("href", &url).unwrap();
-            //                                 a.set_attribute("download", "media-files.zip").unwrap();
-            //                                 body.append_child(&a).unwrap();
-            //                                 a.dyn_into::<HtmlElement>().unwrap().click();
-            //                             },
-            //                             Err(err) => error!("Failed to get transactions blob from blob promise: {:#?}", err)
-            //                         },
-            //                         Err(err) => error!("Failed to get transactions blob promise: {:#?}", err)
-            //                     }
-            //                 } else {
-            //                     error!("Bad response when downloading transactions: {:#?}", response.text().await);
-            //                 },
-            //                 Err(err) => error!("Failed to send download transaction request: {}", err)
-            //             }
-            //         });
-            //     })
-            // }>{"Download Media Files"}</button>
+            <button onclick={
+                let hashed_session_id_handle = hashed_session_id_base64_handle.clone();
+                Callback::from(move |_e: MouseEvent| {
+                    let hashed_session_id_handle = hashed_session_id_handle.clone();
+                    spawn_local(async move {
+                        // the archive can be far larger than memory, so the browser is pointed at it to save it as it
+                        // streams in, using a download token since navigations can't carry the AUTHORIZATION header
+                        match Request::post(&format!("{}/download_token", API_ROOT))
+                            .header("AUTHORIZATION", hashed_session_id_handle.as_ref().unwrap())
+                            .send()
+                            .await {
+                            Ok(response) => if response.ok() {
+                                match response.binary().await {
+                                    Ok(download_token) => {
+                                        let download_token_base64 = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(download_token);
+                                        let document = window().unwrap().document().unwrap();
+                                        let body = document.body().unwrap();
+                                        let a = document.create_element("a").unwrap();
+                                        a.set_attribute("href", &format!("{}/media_files_zip?token={}", API_ROOT, download_token_base64)).unwrap();
+                                        a.set_attribute("download", "media-files.zip").unwrap();
+                                        body.append_child(&a).unwrap();
+                                        a.dyn_into::<HtmlElement>().unwrap().click();
+                                    },
+                                    Err(err) => error!("Failed to get binary of download token response: {}", err)
+                                }
+                            } else {
+                                error!("Bad response when requesting a download token: {:#?}", response.text().await);
+                            },
+                            Err(err) => error!("Failed to send download token request: {}", err)
+                        }
+                    });
+                })
+            }>{"Download Media Files"}</button>
         } else {
             <Login on_set_hashed_session_id={
                 Callback::from(move |e: [u8; 32]| {
@@ -146,6 +147,7 @@ fn NavBar(props: &NavBarProps) -> Html {
         <nav>
         <div class={classes!(if props.route == Route::Home {vec!["nav-item-selected"]} else {vec![]})}><Link<Route> to={Route::Home}>{"Home"}</Link<Route>></div>
         <div class={classes!(if props.route == Route::AddTag {vec!["nav-item-selected"]} else {vec![]})}><Link<Route> to={Route::AddTag}>{"Add Tag"}</Link<Route>></div>
+        <div class={classes!(if props.route == Route::ManageTags {vec!["nav-item-selected"]} else {vec![]})}><Link<Route> to={Route::ManageTags}>{"Manage Tags"}</Link<Route>></div>
         <div class={classes!(if props.route == Route::AddMedia {vec!["nav-item-selected"]} else {vec![]})}><Link<Route> to={Route::AddMedia}>{"Add Media"}</Link<Route>></div>
         </nav>
     }
@@ -159,6 +161,7 @@ fn switch(route: Route) -> Html {
                 {match route {
                     Route::Home => html! {<Home/>},
                     Route::AddTag => html! {<AddTag/>},
+                    Route::ManageTags => html! {<ManageTags/>},
                     Route::AddMedia => html! {<AddMedia/>},
                     Route::NotFound => html! { <Link<Route> to={Route::Home}>{"Page not found, return home"}</Link<Route>> }
                 }}
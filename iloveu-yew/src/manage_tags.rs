@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use gloo_net::http::Request;
+use web_sys::{HtmlInputElement, HtmlSelectElement, MouseEvent};
+use yew::{Html, function_component, html, Callback, TargetCast, use_state, use_effect_with_deps, platform::spawn_local, use_context, Properties, UseStateHandle};
+use log::error;
+
+use crate::{API_ROOT, HashedSessionIDBase64};
+
+#[function_component]
+pub fn ManageTags() -> Html {
+    let hashed_session_id_base64 = use_context::<HashedSessionIDBase64>().unwrap();
+    let tags_handle = use_state(HashMap::<u64, String>::default);
+    let refresh_handle = use_state(|| 0u64);
+
+    let tags_handle_effect = tags_handle.clone();
+    let hashed_session_id_base64_for_tags = hashed_session_id_base64.clone();
+    use_effect_with_deps(move |_| {
+        let tags_handle = tags_handle_effect;
+        spawn_local(async move {
+            match Request::get(&format!("{}/tags", API_ROOT))
+                .header("AUTHORIZATION", &hashed_session_id_base64_for_tags.0)
+                .send()
+                .await {
+                Ok(response) => if response.ok() {
+                    match response.json::<HashMap<u64, String>>().await {
+                        Ok(tags) => {
+                            tags_handle.set(tags);
+                        },
+                        Err(err) => error!("Failed to parse JSON from tags response: {}", err)
+                    }
+                } else {
+                    error!("Bad response when getting tags: {:#?}", response.text().await);
+                },
+                Err(err) => error!("Failed to send response for tags: {}", err)
+            }
+        })
+    }, *refresh_handle);
+
+    let mut sorted_tags: Vec<(u64, String)> = (*tags_handle).clone().into_iter().collect();
+    sorted_tags.sort_by_key(|tag| tag.1.to_lowercase());
+
+    html! {
+        <>
+            <h1>{"Manage Tags"}</h1>
+            <table class="tags-table">
+                {sorted_tags.iter().map(|(tag_id, name)| html! {
+                    <TagRow key={*tag_id} tag_id={*tag_id} name={name.clone()} tags={sorted_tags.clone()} refresh_handle={refresh_handle.clone()}/>
+                }).collect::<Html>()}
+            </table>
+        </>
+    }
+}
+
+#[derive(Properties, PartialEq)]
+struct TagRowProps {
+    pub tag_id: u64,
+    pub name: String,
+    pub tags: Vec<(u64, String)>,
+    pub refresh_handle: UseStateHandle<u64>,
+}
+
+#[function_component]
+fn TagRow(props: &TagRowProps) -> Html {
+    let hashed_session_id_base64 = use_context::<HashedSessionIDBase64>().unwrap();
+    let name_handle = use_state(|| props.name.clone());
+    let merge_into_handle = use_state(Option::<u64>::default);
+    let working_handle = use_state(|| false);
+
+    let send = {
+        let hashed_session_id_base64 = hashed_session_id_base64.clone();
+        let working_handle = working_handle.clone();
+        let refresh_handle = props.refresh_handle.clone();
+        move |path: String, body: String| {
+            if *working_handle {
+                return;
+            }
+            working_handle.set(true);
+            let hashed_session_id_base64 = hashed_session_id_base64.0.clone();
+            let working_handle = working_handle.clone();
+            let refresh_handle = refresh_handle.clone();
+            spawn_local(async move {
+                match Request::post(&format!("{}{}", API_ROOT, path))
+                    .header("AUTHORIZATION", &hashed_session_id_base64)
+                    .body(body)
+                    .send()
+                    .await {
+                    Ok(response) => if response.ok() {
+                        refresh_handle.set(*refresh_handle+1);
+                    } else {
+                        error!("Bad response when managing tag: {:#?}", response.text().await);
+                    },
+                    Err(err) => error!("Failed to send manage tag request: {}", err)
+                }
+                working_handle.set(false);
+            })
+        }
+    };
+
+    html! {
+        <tr>
+            <td><input type="text" onchange={
+                let name_handle = name_handle.clone();
+                Callback::from(move |e: yew::Event| {
+                    name_handle.set(e.target_dyn_into::<HtmlInputElement>().unwrap().value());
+                })
+            } value={(*name_handle).clone()}/></td>
+            <td><button onclick={
+                let send = send.clone();
+                let tag_id = props.tag_id;
+                let name_handle = name_handle.clone();
+                Callback::from(move |_e: MouseEvent| {
+                    send(format!("/rename_tag/{}", tag_id), (*name_handle).clone());
+                })
+            } disabled={*name_handle == props.name || (*name_handle).is_empty() || *working_handle}>{"Rename"}</button></td>
+            <td><select onchange={
+                let merge_into_handle = merge_into_handle.clone();
+                Callback::from(move |e: yew::Event| {
+                    merge_into_handle.set(e.target_dyn_into::<HtmlSelectElement>().unwrap().value().parse::<u64>().ok());
+                })
+            }>
+                <option value="" selected={(*merge_into_handle).is_none()}>{"Merge into..."}</option>
+                {props.tags.iter().filter(|(tag_id, _)| *tag_id != props.tag_id).map(|(tag_id, name)| html! {
+                    <option key={*tag_id} value={format!("{}", tag_id)}>{name}</option>
+                }).collect::<Html>()}
+            </select></td>
+            <td><button onclick={
+                let send = send.clone();
+                let tag_id = props.tag_id;
+                let merge_into_handle = merge_into_handle.clone();
+                Callback::from(move |_e: MouseEvent| {
+                    if let Some(into_tag_id) = *merge_into_handle {
+                        send(format!("/merge_tag/{}", tag_id), format!("{}", into_tag_id));
+                    }
+                })
+            } disabled={(*merge_into_handle).is_none() || *working_handle}>{"Merge"}</button></td>
+            <td><button onclick={
+                let tag_id = props.tag_id;
+                Callback::from(move |_e: MouseEvent| {
+                    send(format!("/delete_tag/{}", tag_id), String::new());
+                })
+            } disabled={*working_handle}>{"Delete"}</button></td>
+        </tr>
+    }
+}